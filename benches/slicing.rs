@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use pipa::ir::gen_ir;
+use pipa::syntax::ast;
+use pipa::vm::Vm;
+use pipa::analysis::FULL_OPT;
+
+const RUNS: u32 = 200;
+
+// vars in a loop are read as whole slices in every iteration and a var is sliced many times,
+// each of them re-segmented the var before the offsets were cached
+fn main() {
+    let code = "{{ ROWS[:] | \"<tr>$(title)|$(body)</tr>\" }}".to_string() + &"{{ body[100:140] }}{{ title[5:] }}".repeat(100);
+    let ir = gen_ir(&code, &ast(&code).unwrap(), FULL_OPT).unwrap();
+    let vars = BTreeMap::from([
+        ("title".to_string(), "заголовок \u{1f44d}\u{1f3fd} ".repeat(10)),
+        ("body".to_string(), "тіло e\u{301} ".repeat(500)),
+    ]);
+    let arrays = BTreeMap::from([("ROWS".to_string(), (0..1000).map(|i| i.to_string()).collect())]);
+
+    let mut out = Vec::new();
+    let mut best = Duration::MAX;
+    let mut total = Duration::ZERO;
+
+    for _ in 0..RUNS {
+        out.clear();
+        let start = Instant::now();
+        Vm::new(&vars, &arrays).run(&mut out, &ir).unwrap();
+        let elapsed = start.elapsed();
+
+        black_box(&out);
+        best = best.min(elapsed);
+        total += elapsed;
+    }
+
+    println!("slicing: {} runs, best {:?}, mean {:?}", RUNS, best, total / RUNS);
+}
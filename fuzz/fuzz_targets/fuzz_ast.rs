@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pipa::syntax::ast;
+
+fuzz_target!(|data: &[u8]| {
+    // any result is fine as long as ast doesn't panic
+    let _ = ast(std::str::from_utf8(data).unwrap_or(""));
+});
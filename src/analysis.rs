@@ -1,34 +1,62 @@
 use std::mem;
 use std::collections::HashSet;
 use crate::syntax::{InnerNode, Node};
+use crate::visit::{Visitor, walk, walk_node};
 
-pub const NO_OPT: OptOptions = OptOptions{ string_evaluation: false, constant_evaluation: false };
-pub const FULL_OPT: OptOptions = OptOptions{ string_evaluation: true, constant_evaluation: true };
+pub const NO_OPT: OptOptions = OptOptions{ string_evaluation: false, constant_evaluation: false, debug_comments: false };
+pub const FULL_OPT: OptOptions = OptOptions{ string_evaluation: true, constant_evaluation: true, debug_comments: false };
 
 #[derive(Clone, Copy, Default, Debug)]
 pub struct OptOptions {
     pub string_evaluation: bool,
     pub constant_evaluation: bool,
+    /// emit Comment ops describing the source of each section
+    pub debug_comments: bool,
 }
 
 pub fn unique_constants_expr<'a>(parent: &Node, code: &'a str) -> HashSet<&'a str> {
     let mut constants = HashSet::new();
+    let mut tail = Some(parent);
 
-    if let InnerNode::String { ref children } = *parent.inner {
-
-        for child in children {
-            if let InnerNode::Name { .. } = *child.inner {
-                constants.insert(child.as_str(code));
-            }
+    // exprs with filters and fan-outs are not evaluated, so the whole pipe is checked
+    while let Some(node) = tail {
+        match *node.inner {
+            InnerNode::String { ref children } => {
+                for child in children {
+                    if let InnerNode::Name { .. } = *child.inner {
+                        constants.insert(child.as_str(code));
+                    }
+                }
+            },
+            InnerNode::Filter { .. } => {},
+            InnerNode::FanOut { ref branches } => {
+                for branch in branches {
+                    constants.extend(unique_constants_expr(branch, code));
+                }
+            },
+            _ => panic!("This functions must be used only with evaluated strings. Got: {:#?}", parent),
         }
 
-    } else {
-        panic!("This functions must be used only with evaluated strings. Got: {:#?}", parent);
+        tail = node.children.first();
     }
 
     constants
 }
 
+fn has_runtime_stage(parent: &Node) -> bool {
+    let mut tail = Some(parent);
+
+    while let Some(node) = tail {
+        if let InnerNode::Filter { .. } | InnerNode::FanOut { .. } = *node.inner {
+            return true;
+        }
+
+        tail = node.children.first();
+    }
+
+    false
+}
+
 pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
     if parent.children.is_empty() {
         if let InnerNode::String { ref children } = *parent.inner {
@@ -40,6 +68,11 @@ pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
         return Some(parent);
     }
 
+    // filters and fan-outs are applied at runtime
+    if has_runtime_stage(&parent) {
+        return Some(parent);
+    }
+
     let mut tail = parent;
     let mut parent_expr = Vec::new();
     let mut child_expr = Vec::new();
@@ -54,8 +87,8 @@ pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
             parent_expr.push(tail);
             tail = children;
         },
-        InnerNode::Array { .. } | InnerNode::Literal { .. } => {
-            unreachable!("This function should not be used with arrays and literals");
+        InnerNode::Array { .. } | InnerNode::ArrayConcat { .. } | InnerNode::Zip { .. } | InnerNode::Literal { .. } | InnerNode::Assert { .. } | InnerNode::Stop | InnerNode::Condition { .. } | InnerNode::Indent { .. } | InnerNode::Filter { .. } | InnerNode::Chars { .. } | InnerNode::FanOut { .. } => {
+            unreachable!("This function should not be used with loops, literals, asserts and conditions");
         },
     }
     // expand its children
@@ -78,7 +111,7 @@ pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
                                 child_expr.push(child);
                             }
                         },
-                        InnerNode::String { .. } | InnerNode::Int { .. } | InnerNode::Array { .. } => {
+                        InnerNode::String { .. } | InnerNode::Int { .. } | InnerNode::Array { .. } | InnerNode::ArrayConcat { .. } | InnerNode::Zip { .. } | InnerNode::Assert { .. } | InnerNode::Stop | InnerNode::Condition { .. } | InnerNode::Indent { .. } | InnerNode::Filter { .. } | InnerNode::Chars { .. } | InnerNode::FanOut { .. } => {
                             unreachable!("Should be handled during ast building");
                         },
                     }
@@ -96,7 +129,7 @@ pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
                     }
                 }
             },
-            InnerNode::Array { .. } | InnerNode::Literal { .. } | InnerNode::Int { .. } | InnerNode::Name { .. } => {
+            InnerNode::Array { .. } | InnerNode::ArrayConcat { .. } | InnerNode::Zip { .. } | InnerNode::Literal { .. } | InnerNode::Int { .. } | InnerNode::Name { .. } | InnerNode::Assert { .. } | InnerNode::Stop | InnerNode::Condition { .. } | InnerNode::Indent { .. } | InnerNode::Filter { .. } | InnerNode::Chars { .. } | InnerNode::FanOut { .. } => {
                 unreachable!("Should be handled during ast building");
             },
         }
@@ -114,13 +147,67 @@ pub fn evaluate_expr(parent: Node, code: &str) -> Option<Node> {
         )
 }
 
+/// Size metrics of an AST, lets tools reject templates that are too complex
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct AstSize {
+    pub node_count: usize,
+    /// Nesting of String children, a template without strings has depth 1
+    pub max_depth: usize,
+    pub string_child_count: usize,
+}
+
+pub fn ast_size(nodes: &[Node]) -> AstSize {
+    struct SizeVisitor {
+        size: AstSize,
+        depth: usize,
+    }
+
+    impl Visitor for SizeVisitor {
+        fn visit_node(&mut self, node: &Node) {
+            self.size.node_count += 1;
+            self.size.max_depth = std::cmp::max(self.size.max_depth, self.depth);
+
+            walk_node(self, node);
+        }
+
+        fn visit_string(&mut self, _node: &Node, children: &[Node]) {
+            self.size.string_child_count += children.len();
+
+            self.depth += 1;
+            walk(children, self);
+            self.depth -= 1;
+        }
+    }
+
+    let mut visitor = SizeVisitor { size: AstSize::default(), depth: 1 };
+    walk(nodes, &mut visitor);
+
+    visitor.size
+}
+
+#[cfg(test)]
 mod test {
     use std::collections::HashSet;
-    use crate::analysis::{Node, unique_constants_expr, InnerNode, evaluate_expr};
+    use crate::analysis::{Node, unique_constants_expr, InnerNode, evaluate_expr, ast_size, AstSize};
     use crate::syntax::ast;
     
     
 
+    #[test]
+    fn size() {
+        let size = |code: &str| ast_size(&ast(code).unwrap());
+
+        assert_eq!(size(""), AstSize { node_count: 0, max_depth: 0, string_child_count: 0 });
+        assert_eq!(size("text{{ first }}"), AstSize { node_count: 2, max_depth: 1, string_child_count: 0 });
+        // literal and name inside the string
+        assert_eq!(size("{{ \"a $(first)\" }}"), AstSize { node_count: 3, max_depth: 2, string_child_count: 2 });
+        // pipes and condition bodies are counted, but don't nest strings
+        assert_eq!(
+            size("{{ if on\nfirst | \"$(_)!\"\nend }}"),
+            AstSize { node_count: 5, max_depth: 2, string_child_count: 2 }
+        );
+    }
+
     #[test]
     fn empty_string_evaluation() {
         let code = "{{ \"\" }}";
@@ -1,5 +1,8 @@
 use std::env::Args;
+use std::fmt;
 use crate::analysis::{OptOptions, NO_OPT, FULL_OPT};
+use crate::ir::is_name_array;
+use crate::vm::{StringVars, ArrayVars};
 
 #[derive(Debug)]
 pub struct ArgOptions {
@@ -7,10 +10,18 @@ pub struct ArgOptions {
     pub opt: OptOptions,    
     pub separator: String,
     pub file: String,
+    pub check: bool,
+    pub files: Vec<String>,
+    pub env_file: Option<String>,
+    pub vars_file: Option<String>,
 }
 
 pub const USAGE: &'static str = 
 "Usage: pipa [options] script.pipa
+       pipa check [options] script.pipa...
+
+Commands:
+    check                       compile the scripts without running them, exit with 1 if any of them fails
 
 Options:
     --help                      print this message
@@ -18,6 +29,8 @@ Options:
     --fstring_evaluation        enable string evaluation optimization
     --fconstant_evaluation      enable constant evaluation optimization
     --sep                       specify separator for array constants(default: '\\n')
+    --env-file                  load constants from a file with KEY=value lines, values of arrays are separated by ','
+    --vars-file                 load constants from a .toml or .yaml file, nested tables are mapped to dotted names
 ";
 
 pub fn parse(args: Args) -> ArgOptions {
@@ -26,6 +39,10 @@ pub fn parse(args: Args) -> ArgOptions {
         opt: FULL_OPT,
         separator: "\n".into(),
         file: "".into(),
+        check: false,
+        files: Vec::new(),
+        env_file: None,
+        vars_file: None,
     };
 
     let mut iter = args.peekable();
@@ -45,6 +62,20 @@ pub fn parse(args: Args) -> ArgOptions {
                     None => opts.help = true,
                 }
             },
+            "--env-file" => {
+                match iter.next() {
+                    Some(path) => opts.env_file = Some(path),
+                    None => opts.help = true,
+                }
+            },
+            "--vars-file" => {
+                match iter.next() {
+                    Some(path) => opts.vars_file = Some(path),
+                    None => opts.help = true,
+                }
+            },
+            "check" if !opts.check && opts.file.is_empty() => opts.check = true,
+            _ if opts.check => opts.files.push(arg),
             _ => opts.file = arg,
         }
     }
@@ -52,3 +83,185 @@ pub fn parse(args: Args) -> ArgOptions {
     opts
 }
 
+
+#[derive(Debug, PartialEq)]
+pub struct EnvFileError {
+    pub line: usize,
+}
+
+impl fmt::Display for EnvFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: expected KEY=value", self.line)
+    }
+}
+
+/// Parses `KEY=value` lines into constants. Values of array names are separated by ','.
+/// Blank lines and lines starting with '#' are ignored.
+pub fn parse_env_file(content: &str, constants: &mut StringVars, arrays: &mut ArrayVars) -> Result<(), EnvFileError> {
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_start();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value),
+            _ => return Err(EnvFileError { line: i + 1 }),
+        };
+
+        if is_name_array(key) {
+            let values = match value.is_empty() {
+                true => Vec::new(),
+                false => value.split(',').map(|v| v.into()).collect(),
+            };
+
+            arrays.insert(key.into(), values);
+            continue;
+        }
+
+        constants.insert(key.into(), value.into());
+    }
+
+    Ok(())
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum VarsFileError {
+    UnsupportedFormat { path: String },
+    Parse { message: String },
+    ExpectedList { name: String },
+    ExpectedArrayName { name: String },
+    NestedList { name: String },
+}
+
+impl fmt::Display for VarsFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarsFileError::UnsupportedFormat { path } => write!(f, "unsupported format of {}, supported: {}", path, SUPPORTED_VARS_FORMATS.join(", ")),
+            VarsFileError::Parse { message } => write!(f, "{}", message.trim_end()),
+            VarsFileError::ExpectedList { name } => write!(f, "{}: values of array names must be lists", name),
+            VarsFileError::ExpectedArrayName { name } => write!(f, "{}: lists can only be assigned to array names", name),
+            VarsFileError::NestedList { name } => write!(f, "{}: lists can only contain strings, numbers and booleans", name),
+        }
+    }
+}
+
+const SUPPORTED_VARS_FORMATS: &[&str] = &[
+    #[cfg(feature = "toml")]
+    ".toml",
+    #[cfg(feature = "yaml")]
+    ".yaml",
+    #[cfg(feature = "yaml")]
+    ".yml",
+];
+
+/// Format-independent value of a vars file
+#[allow(dead_code)]
+enum FileValue {
+    Scalar(String),
+    List(Vec<FileValue>),
+    Table(Vec<(String, FileValue)>),
+}
+
+#[cfg(feature = "toml")]
+impl From<toml::Value> for FileValue {
+    fn from(value: toml::Value) -> Self {
+        match value {
+            toml::Value::String(s) => FileValue::Scalar(s),
+            toml::Value::Array(a) => FileValue::List(a.into_iter().map(|v| v.into()).collect()),
+            toml::Value::Table(t) => FileValue::Table(t.into_iter().map(|(k, v)| (k, v.into())).collect()),
+            v => FileValue::Scalar(v.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "yaml")]
+impl From<serde_yaml::Value> for FileValue {
+    fn from(value: serde_yaml::Value) -> Self {
+        use serde_yaml::Value;
+
+        match value {
+            Value::Null => FileValue::Scalar("".into()),
+            Value::Bool(b) => FileValue::Scalar(b.to_string()),
+            Value::Number(n) => FileValue::Scalar(n.to_string()),
+            Value::String(s) => FileValue::Scalar(s),
+            Value::Sequence(s) => FileValue::List(s.into_iter().map(|v| v.into()).collect()),
+            Value::Mapping(m) => FileValue::Table(m.into_iter().map(|(k, v)| {
+                let key = match k {
+                    Value::String(s) => s,
+                    k => serde_yaml::to_string(&k).unwrap_or_default().trim_end().into(),
+                };
+
+                (key, v.into())
+            }).collect()),
+            Value::Tagged(t) => t.value.into(),
+        }
+    }
+}
+
+/// Loads constants from a toml or yaml file depending on the extension of `path`.
+/// Keys of nested tables are joined with '.', lists can only be assigned to array names.
+#[cfg_attr(not(any(feature = "toml", feature = "yaml")), allow(unused_variables))]
+pub fn parse_vars_file(path: &str, content: &str, constants: &mut StringVars, arrays: &mut ArrayVars) -> Result<(), VarsFileError> {
+    let ext = path.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+
+    let root: Option<FileValue> = match ext {
+        #[cfg(feature = "toml")]
+        "toml" => content.parse::<toml::Table>()
+            .map(|t| Some(toml::Value::Table(t).into()))
+            .map_err(|e| VarsFileError::Parse { message: e.to_string() })?,
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => serde_yaml::from_str::<serde_yaml::Value>(content)
+            .map(|v| Some(v.into()))
+            .map_err(|e| VarsFileError::Parse { message: e.to_string() })?,
+        _ => None,
+    };
+
+    let Some(root) = root else {
+        return Err(VarsFileError::UnsupportedFormat { path: path.into() });
+    };
+
+    match root {
+        FileValue::Table(t) => {
+            for (name, value) in t {
+                insert_file_value(name, value, constants, arrays)?;
+            }
+
+            Ok(())
+        },
+        _ => Err(VarsFileError::Parse { message: "expected a table at the top level".into() }),
+    }
+}
+
+fn insert_file_value(name: String, value: FileValue, constants: &mut StringVars, arrays: &mut ArrayVars) -> Result<(), VarsFileError> {
+    match value {
+        FileValue::Scalar(_) if is_name_array(&name) => Err(VarsFileError::ExpectedList { name }),
+        FileValue::Scalar(s) => {
+            constants.insert(name, s);
+            Ok(())
+        },
+        FileValue::List(_) if !is_name_array(&name) => Err(VarsFileError::ExpectedArrayName { name }),
+        FileValue::List(list) => {
+            let mut values = Vec::with_capacity(list.len());
+
+            for v in list {
+                match v {
+                    FileValue::Scalar(s) => values.push(s),
+                    _ => return Err(VarsFileError::NestedList { name }),
+                }
+            }
+
+            arrays.insert(name, values);
+            Ok(())
+        },
+        FileValue::Table(t) => {
+            for (key, value) in t {
+                insert_file_value(format!("{}.{}", name, key), value, constants, arrays)?;
+            }
+
+            Ok(())
+        },
+    }
+}
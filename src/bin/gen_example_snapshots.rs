@@ -36,7 +36,7 @@ fn main() {
             }
         };
 
-        let ir = match gen_ir(&code, nodes, NO_OPT) {
+        let ir = match gen_ir(&code, &nodes, NO_OPT) {
             Ok(ir) => ir,
             Err(e) => {
                 e.write_message(&mut f, path, &code).unwrap();
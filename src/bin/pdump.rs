@@ -1,6 +1,6 @@
 use pipa::syntax::{ast};
 use pipa::ir::{gen_ir};
-use pipa::analysis::NO_OPT;
+use pipa::analysis::{NO_OPT, OptOptions};
 use std::fs::{read_to_string};
 use std::io::stdout;
 use std::env;
@@ -20,7 +20,9 @@ fn main() {
         }
     };
 
-    let ir = match gen_ir(&code, nodes.clone(), NO_OPT) {
+    let opt = OptOptions { debug_comments: true, ..NO_OPT };
+
+    let ir = match gen_ir(&code, &nodes, opt) {
         Ok(ir) => ir,
         Err(e) => {
             e.write_message(&mut f, path, &code).unwrap();
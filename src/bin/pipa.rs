@@ -1,4 +1,4 @@
-use pipa::args::{parse, USAGE};
+use pipa::args::{parse, parse_env_file, parse_vars_file, USAGE};
 use pipa::vm::{Vm, StringVars, ArrayVars};
 use pipa::ir::{is_name_array, gen_ir};
 use pipa::syntax::ast;
@@ -6,6 +6,8 @@ use std::collections::BTreeMap;
 use std::env;
 use std::fs::read_to_string;
 use std::io::{stdout, Write};
+use std::process;
+use pipa::analysis::OptOptions;
 
 
 fn main() {
@@ -13,10 +15,27 @@ fn main() {
     // parse args
     let mut opt = parse(env::args());
 
-    if opt.help || opt.file == "" {
+    if opt.help || (opt.file.is_empty() && opt.files.is_empty()) {
         write!(f, "{}", USAGE).unwrap();
         return;
     }
+
+    if opt.check {
+        let mut failed = false;
+
+        for file in &opt.files {
+            if !check(&mut f, file, opt.opt) {
+                failed = true;
+            }
+        }
+
+        if failed {
+            drop(f);
+            process::exit(1);
+        }
+
+        return;
+    }
     // gen ir
     let code = match read_to_string(&opt.file) {
         Ok(c) => c,
@@ -35,7 +54,7 @@ fn main() {
         }
     };
 
-    let ir = match gen_ir(&code, nodes, opt.opt) {
+    let ir = match gen_ir(&code, &nodes, opt.opt) {
         Ok(ir) => ir,
         Err(e) => {
             e.write_message(&mut f, &opt.file, &code).unwrap();
@@ -58,6 +77,41 @@ fn main() {
         constants.insert(key.into(), value.into());
     }
 
+    // constants from the file take precedence over environment
+    if let Some(path) = &opt.env_file {
+        let content = match read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                writeln!(f, "{}: {}", path, e).unwrap();
+                drop(f);
+                process::exit(1);
+            },
+        };
+
+        if let Err(e) = parse_env_file(&content, &mut constants, &mut arrays) {
+            writeln!(f, "{}: {}", path, e).unwrap();
+            drop(f);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &opt.vars_file {
+        let content = match read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                writeln!(f, "{}: {}", path, e).unwrap();
+                drop(f);
+                process::exit(1);
+            },
+        };
+
+        if let Err(e) = parse_vars_file(path, &content, &mut constants, &mut arrays) {
+            writeln!(f, "{}: {}", path, e).unwrap();
+            drop(f);
+            process::exit(1);
+        }
+    }
+
     // run
     let mut vm = Vm::new(&constants, &arrays);
 
@@ -68,3 +122,22 @@ fn main() {
         },
     }
 }
+
+fn check(f: &mut impl Write, file: &str, opt: OptOptions) -> bool {
+    let code = match read_to_string(file) {
+        Ok(c) => c,
+        Err(e) => {
+            writeln!(f, "{}: {}", file, e).unwrap();
+            return false;
+        },
+    };
+
+    let result = ast(&code).and_then(|nodes| gen_ir(&code, &nodes, opt));
+
+    if let Err(e) = result {
+        e.write_message(f, file, &code).unwrap();
+        return false;
+    }
+
+    true
+}
@@ -1,17 +1,20 @@
 use std::io::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::{fmt, mem};
 use crate::ir::Type;
-use crate::syntax::{TokenType, EscapeIter};
+use crate::syntax::TokenType;
 use unicode_segmentation::UnicodeSegmentation;
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ErrorReason {
     SyntaxError {
         expected: Vec<TokenType>,
     },
     NameError,
     MacroRedefinition {
-        name: String
+        name: String,
+        first_defined: usize,
     },
     UndefinedMacro {
         name: String
@@ -21,7 +24,10 @@ pub enum ErrorReason {
     },
     NestedMacro,
     EmptyMacro,
-    PipeNoParent,
+    PipeNoParent {
+        /// the pipe starts a code block right after template text
+        after_literal: bool,
+    },
     PipeNoChildren, 
     ArrayNoNewLine,
     ArrayNotPiped,
@@ -29,18 +35,71 @@ pub enum ErrorReason {
         expected: Type,
         got: Type,
     },
+    IntOverflow,
+    UnclosedBlock {
+        expected: &'static str,
+    },
+    UnmatchedEnd {
+        keyword: &'static str,
+    },
+    UnknownFilter {
+        name: String,
+    },
+    FilterArgs {
+        name: String,
+        expected: &'static str,
+    },
+    VersionTooHigh {
+        required: String,
+        current: String,
+    },
+    InvalidVersion {
+        version: String,
+    },
+    UnknownAnnotation {
+        name: String,
+    },
+    ImportError {
+        path: String,
+    },
+    UnquotedPipeTarget {
+        /// the target wrapped into a string, e.g. "$(name)" for a name
+        suggestion: String,
+    },
+    FeatureDisabled {
+        /// "loops" or "macros"
+        feature: String,
+    },
+    WidthTooLarge {
+        max: u64,
+    },
+    InvalidDelimiters {
+        open: char,
+        close: char,
+    },
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CompileError {
     pub first_char: usize,
+    /// Span of the `?name` token if the error is in the body of an expanded macro
+    pub expanded_at: Option<(usize, usize)>,
     pub reason: ErrorReason,
 }
 
+// only the position and the kind are hashed, equal errors still have equal hashes
+impl Hash for CompileError {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.first_char.hash(state);
+        mem::discriminant(&self.reason).hash(state);
+    }
+}
+
 impl CompileError {
     pub fn new_syntax(first_char: usize, expected: &[TokenType]) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::SyntaxError {
                 expected: expected.to_vec(),
             },
@@ -50,6 +109,7 @@ impl CompileError {
     pub fn new_name(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::NameError,
         }
     }
@@ -57,6 +117,7 @@ impl CompileError {
     pub fn new_undefined_var(first_char: usize, name: String) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::UndefinedVar { name },
         }
     }
@@ -64,20 +125,25 @@ impl CompileError {
     pub fn new_array_pipe(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::ArrayNotPiped,
         }
     }
 
-    pub fn new_pipe_no_parent(first_char: usize) -> Self {
+    pub fn new_pipe_no_parent(first_char: usize, after_literal: bool) -> Self {
         Self {
             first_char,
-            reason: ErrorReason::PipeNoParent,
+            expanded_at: None,
+            reason: ErrorReason::PipeNoParent {
+                after_literal,
+            },
         }
     }
 
     pub fn new_pipe_no_children(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::PipeNoChildren,
         }
     }
@@ -85,15 +151,18 @@ impl CompileError {
     pub fn new_invalid_array(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::ArrayNoNewLine,
         }
     }
 
-    pub fn new_macro_redefinition(first_char: usize, name: String) -> Self {
+    pub fn new_macro_redefinition(first_char: usize, name: String, first_defined: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::MacroRedefinition {
                 name,
+                first_defined,
             },
         }
     }
@@ -101,6 +170,7 @@ impl CompileError {
     pub fn new_undefined_macro(first_char: usize, name: String) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::UndefinedMacro {
                 name,
             },
@@ -110,6 +180,7 @@ impl CompileError {
     pub fn new_nested_macro(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::NestedMacro,
         }
     }
@@ -117,13 +188,121 @@ impl CompileError {
     pub fn new_empty_macro(first_char: usize) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::EmptyMacro,
         }
     }
 
+    pub fn new_int_overflow(first_char: usize) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::IntOverflow,
+        }
+    }
+
+    pub fn new_unclosed_block(first_char: usize, expected: &'static str) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::UnclosedBlock { expected },
+        }
+    }
+
+    pub fn new_unmatched_end(first_char: usize, keyword: &'static str) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::UnmatchedEnd { keyword },
+        }
+    }
+
+    pub fn new_version_too_high(first_char: usize, required: String, current: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::VersionTooHigh {
+                required,
+                current,
+            },
+        }
+    }
+
+    pub fn new_invalid_version(first_char: usize, version: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::InvalidVersion {
+                version,
+            },
+        }
+    }
+
+    pub fn new_unknown_annotation(first_char: usize, name: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::UnknownAnnotation {
+                name,
+            },
+        }
+    }
+
+    pub fn new_import_error(first_char: usize, path: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::ImportError {
+                path,
+            },
+        }
+    }
+
+    pub fn new_unquoted_pipe_target(first_char: usize, suggestion: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::UnquotedPipeTarget {
+                suggestion,
+            },
+        }
+    }
+
+    pub fn new_feature_disabled(first_char: usize, feature: String) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::FeatureDisabled {
+                feature,
+            },
+        }
+    }
+
+    pub fn new_width_too_large(first_char: usize, max: u64) -> Self {
+        Self {
+            first_char,
+            expanded_at: None,
+            reason: ErrorReason::WidthTooLarge {
+                max,
+            },
+        }
+    }
+
+    pub fn new_invalid_delimiters(open: char, close: char) -> Self {
+        Self {
+            first_char: 0,
+            expanded_at: None,
+            reason: ErrorReason::InvalidDelimiters {
+                open,
+                close,
+            },
+        }
+    }
+
     pub fn new_type_error(first_char: usize, expected: Type, got: Type) -> Self {
         Self {
             first_char,
+            expanded_at: None,
             reason: ErrorReason::TypeError {
                 expected,
                 got,
@@ -131,126 +310,421 @@ impl CompileError {
         }
     }
 
+    pub fn reason(&self) -> &ErrorReason {
+        &self.reason
+    }
+
+    /// Byte offset of the error in the source
+    pub fn offset(&self) -> usize {
+        self.first_char
+    }
+
+    /// Returns 1-based line and byte offset in the line of the error,
+    /// the same position as the one in the header of `write_message`
+    pub fn position_in_line(&self, code: &str) -> (usize, usize) {
+        let (line, col, _) = find_line(code, self.first_char);
+
+        (line, col)
+    }
+
+    /// Returns the source line containing the error without the line break
+    pub fn source_line<'a>(&self, code: &'a str) -> &'a str {
+        find_line(code, self.first_char).2
+    }
+
+    /// Stable code of the error reason, e.g. `P0002` for a name error
+    pub fn code(&self) -> &'static str {
+        match self.reason {
+            ErrorReason::SyntaxError { .. } => "P0001",
+            ErrorReason::NameError => "P0002",
+            ErrorReason::MacroRedefinition { .. } => "P0003",
+            ErrorReason::UndefinedMacro { .. } => "P0004",
+            ErrorReason::UndefinedVar { .. } => "P0005",
+            ErrorReason::NestedMacro => "P0006",
+            ErrorReason::EmptyMacro => "P0007",
+            ErrorReason::PipeNoParent { .. } => "P0008",
+            ErrorReason::PipeNoChildren => "P0009",
+            ErrorReason::ArrayNoNewLine => "P0010",
+            ErrorReason::ArrayNotPiped => "P0011",
+            ErrorReason::TypeError { .. } => "P0012",
+            ErrorReason::IntOverflow => "P0013",
+            ErrorReason::UnclosedBlock { .. } => "P0014",
+            ErrorReason::UnmatchedEnd { .. } => "P0015",
+            ErrorReason::UnknownFilter { .. } => "P0016",
+            ErrorReason::FilterArgs { .. } => "P0017",
+            ErrorReason::VersionTooHigh { .. } => "P0018",
+            ErrorReason::InvalidVersion { .. } => "P0019",
+            ErrorReason::UnknownAnnotation { .. } => "P0020",
+            ErrorReason::ImportError { .. } => "P0021",
+            ErrorReason::UnquotedPipeTarget { .. } => "P0022",
+            ErrorReason::FeatureDisabled { .. } => "P0023",
+            ErrorReason::WidthTooLarge { .. } => "P0024",
+            ErrorReason::InvalidDelimiters { .. } => "P0025",
+        }
+    }
+
     pub fn write_message(&self, f: &mut impl Write, filename: &str, code: &str) -> io::Result<()> {
+        let message = format!("[{}] {}", self.code(), self.message(Some(code)));
+
+        error_message(f, filename, code, self.first_char, &message)?;
+
+        if let Some((start, end)) = self.expanded_at {
+            let name = code.get(start + 1..end).unwrap_or_default();
+
+            error_message(f, filename, code, start, &format!("in macro '{}' expanded here", name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the error for editors and CI, e.g. for `{{ first`
+    /// `{"line":1,"column":7,"code":"P0001","message":"Expected: '}}'"}`.
+    /// Line and column are the ones of `position_in_line`.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self, code: &str) -> String {
+        use crate::vm::Escape;
+
+        let (line, column) = self.position_in_line(code);
+
+        format!(
+            "{{\"line\":{},\"column\":{},\"code\":\"{}\",\"message\":\"{}\"}}",
+            line,
+            column,
+            self.code(),
+            Escape::Json.apply(&self.message(Some(code)))
+        )
+    }
+
+    /// Marks the error as found in the body of a macro expanded at `expanded_at`
+    pub fn in_expansion(mut self, expanded_at: Option<(usize, usize)>) -> Self {
+        self.expanded_at = self.expanded_at.or(expanded_at);
+        self
+    }
+
+    // positions are shown as lines when the source is known
+    fn message(&self, code: Option<&str>) -> String {
         match &self.reason {
             ErrorReason::SyntaxError { expected } => {
                 if expected.len() > 0 {
                     let expected = expected.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ");
-                    let msg = format!("Expected: {}", expected);
-                    error_message(f, filename, code, self.first_char, &msg)
+                    format!("Expected: {}", expected)
                 } else {
-                    error_message(f, filename, code, self.first_char, "Unexpected token")
+                    "Unexpected token".into()
                 }
             },
             ErrorReason::NameError => {
-                error_message(f, filename, code, self.first_char, "Only alphabetic ascii-chars can be used for names")
+                "Only alphabetic ascii-chars can be used for names".into()
             },
-            ErrorReason::MacroRedefinition { name } => {
-                let msg = format!("Redefinition of '{}'. Macros cannot be redefined", name);
-                error_message(f, filename, code, self.first_char, &msg)
+            ErrorReason::MacroRedefinition { name, first_defined } => {
+                match code {
+                    Some(code) => format!("Redefinition of '{}'. Macros cannot be redefined, first defined at line {}", name, find_line(code, *first_defined).0),
+                    None => format!("Redefinition of '{}'. Macros cannot be redefined, first defined at {}", name, first_defined),
+                }
             },
             ErrorReason::UndefinedMacro { name } => {
-                let msg = format!("Usage of undefined macro '{}'", name);
-                error_message(f, filename, code, self.first_char, &msg)
+                format!("Usage of undefined macro '{}'", name)
             },
             ErrorReason::NestedMacro => {
-                error_message(f, filename, code, self.first_char, "Macros cannot be nested")
+                "Macros cannot be nested".into()
             },
             ErrorReason::EmptyMacro => {
-                error_message(f, filename, code, self.first_char, "Macros cannot be empty")
+                "Macros cannot be empty".into()
             },
             ErrorReason::TypeError { expected, got } => {
-                let msg = format!("Expected type {} but got {}", expected, got);
-                error_message(f, filename, code, self.first_char, &msg)
+                format!("Expected type {} but got {}", expected, got)
             },
             ErrorReason::ArrayNotPiped => {
-                error_message(f, filename, code, self.first_char, "Arrays must be piped")
+                "Arrays must be piped".into()
             },
             ErrorReason::ArrayNoNewLine => {
-                error_message(f, filename, code, self.first_char, "Array definitions must start with a newline")
+                "Array definitions must start with a newline".into()
+            },
+            ErrorReason::PipeNoParent { after_literal: false } => {
+                "Pipe has no parent".into()
             },
-            ErrorReason::PipeNoParent => {
-                error_message(f, filename, code, self.first_char, "Pipe has no parent")
+            ErrorReason::PipeNoParent { after_literal: true } => {
+                "Pipe has no parent, did you mean to put this after a variable name?".into()
             },
             ErrorReason::PipeNoChildren => {
-                error_message(f, filename, code, self.first_char, "Pipe has no children")
+                "Pipe has no children".into()
             },
             ErrorReason::UndefinedVar { name } => {
-                let msg = format!("Usage of undefined scope variable '{}'", name);
-                error_message(f, filename, code, self.first_char, &msg)
-            }
+                format!("Usage of undefined scope variable '{}'", name)
+            },
+            ErrorReason::IntOverflow => {
+                format!("Integer is too large, max value is {}", u64::MAX)
+            },
+            ErrorReason::UnclosedBlock { expected } => {
+                format!("Block is not closed with '{}'", expected)
+            },
+            ErrorReason::UnmatchedEnd { keyword: "endindent" } => {
+                "'endindent' without 'indent'".into()
+            },
+            ErrorReason::UnmatchedEnd { keyword } => {
+                format!("'{}' without 'if' or 'unless'", keyword)
+            },
+            ErrorReason::UnknownFilter { name } => {
+                format!("Unknown filter '{}'", name)
+            },
+            ErrorReason::FilterArgs { name, expected } => {
+                format!("Invalid arguments of '{}', expected: {}", name, expected)
+            },
+            ErrorReason::VersionTooHigh { required, current } => {
+                format!("Template requires pipa {}, but the current version is {}", required, current)
+            },
+            ErrorReason::InvalidVersion { version } => {
+                format!("Invalid version '{}', expected: major.minor.patch", version)
+            },
+            ErrorReason::UnknownAnnotation { name } => {
+                format!("Unknown loop annotation '#{}', expected: #padN", name)
+            },
+            ErrorReason::ImportError { path } => {
+                format!("Cannot import '{}'", path)
+            },
+            ErrorReason::UnquotedPipeTarget { suggestion } => {
+                format!("Pipe target must be a quoted string, did you mean {}?", suggestion)
+            },
+            ErrorReason::FeatureDisabled { feature } => {
+                format!("The use of {} is disabled for this template", feature)
+            },
+            ErrorReason::WidthTooLarge { max } => {
+                format!("Width is too large, max value is {}", max)
+            },
+            ErrorReason::InvalidDelimiters { open, close } => {
+                format!("Delimiters '{}' and '{}' must differ from each other and from the symbols used in code", open, close)
+            },
         }
     }
 }
 
+/// Shows the message without source context, use `write_message` for diagnostics
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.first_char, self.message(None))?;
+
+        match self.expanded_at {
+            Some((start, _)) => write!(f, ", in macro expanded at {}", start),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Error of lexing raw bytes, see `lex_bytes`
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexError {
+    Utf8Error(std::str::Utf8Error),
+    CompileError(CompileError),
+}
+
+impl From<std::str::Utf8Error> for LexError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        LexError::Utf8Error(e)
+    }
+}
+
+impl From<CompileError> for LexError {
+    fn from(e: CompileError) -> Self {
+        LexError::CompileError(e)
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::Utf8Error(e) => write!(f, "{}", e),
+            LexError::CompileError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+
+// returns 1-based line, byte offset in the line and the line itself
+fn find_line(code: &str, first_char: usize) -> (usize, usize, &str) {
+    let mut line_start = 0;
+    let mut last = (1, 0, "");
+
+    for (i, line) in code.split('\n').enumerate() {
+        last = (i + 1, first_char.saturating_sub(line_start), line.trim_end_matches('\r'));
+
+        if first_char <= line_start + line.len() {
+            break;
+        }
+
+        line_start += line.len() + 1;
+    }
+
+    last
+}
+
 fn error_message(f: &mut impl Write, filename: &str, code: &str, first_char: usize, message: &str) -> io::Result<()> 
 {
-    let mut line = 1;
-    let mut line_start = 0;
-    let mut output = String::new();
-    let mut iter = EscapeIter::new(code, 0, &[TokenType::CodeBegin, TokenType::CodeEnd]);
+    let (line, col, source) = find_line(code, first_char);
+    // the error can point past the end of the line, e.g. at a missing token
+    let before = source.get(..col).unwrap_or(source);
+
+    // align the message to the line keeping its tabs
+    let offset_str: String = UnicodeSegmentation::graphemes(before, true)
+        .map(|g| match g {
+            " " | "\t" => g,
+            _ => " ",
+        })
+        .collect();
+
+    // one line before and after the error are shown as context
+    let lines: Vec<&str> = code.split('\n').map(|l| l.trim_end_matches('\r')).collect();
+    let before_line = line.checked_sub(2).and_then(|i| lines.get(i));
+    let after_line = lines.get(line);
+    let width = (line + after_line.map_or(0, |_| 1)).to_string().len();
+    let gutter = " ".repeat(width);
+
+    writeln!(f, "{}:{}:{}", filename, line, col)?;
+    if let Some(before_line) = before_line {
+        context_line(f, width, line - 1, before_line)?;
+    }
+    context_line(f, width, line, source)?;
+    writeln!(f, "{} | {}^", gutter, offset_str)?;
+    writeln!(f, "{} | {}{}", gutter, offset_str, message)?;
+    if let Some(after_line) = after_line {
+        context_line(f, width, line + 1, after_line)?;
+    }
 
-    while let Some((_, i, g)) = iter.next() {
-        output.push_str(g);
+    Ok(())
+}
 
-        if i == first_char {
-            // go to the end of the line
-            if g != "\n" && "g" != "\r\n" {
-                while let Some((_, _, g)) = iter.next() {
+fn context_line(f: &mut impl Write, width: usize, number: usize, source: &str) -> io::Result<()> {
+    if source.is_empty() {
+        writeln!(f, "{:>width$} |", number, width = width)
+    } else {
+        writeln!(f, "{:>width$} | {}", number, source, width = width)
+    }
+}
 
-                    if g == "\n" || g == "\r\n" {
-                        break;
-                    }
 
-                    output.push_str(g);
-                }
-            }
 
-            // write header
-            write!(f, "{}:{}:{}\n", filename, line, i - std::cmp::min(i, line_start))?;
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use crate::error::{CompileError, ErrorReason};
+    use crate::syntax::ast;
 
-            // write the line
-            for g in UnicodeSegmentation::graphemes(output.as_str(), true) {
-                match g {
-                    "\n" => write!(f, " ")?,
-                    _ => write!(f, "{}", g)?
-                }
-            }
+    #[test]
+    fn display() {
+        assert_eq!(CompileError::new_name(3).to_string(), "3: Only alphabetic ascii-chars can be used for names");
+        assert_eq!(
+            CompileError::new_macro_redefinition(20, "print".into(), 5).to_string(),
+            "20: Redefinition of 'print'. Macros cannot be redefined, first defined at 5"
+        );
+    }
 
-            write!(f, "\n")?;
-            
-            // write error message aligned to the line
-            let iter = EscapeIter::new(&output, line_start + 1, &[TokenType::CodeBegin, TokenType::CodeEnd]);
-            let mut offset_str = String::new();
-            
-            // find the offset string
-            for (_, i, g) in iter {
-                match g {
-                    " " | "\t" => offset_str.push_str(g),
-                    _ => offset_str.push_str(" ")
-                }
+    #[test]
+    fn code() {
+        let err = ast("{{ pipa_ñ }}").unwrap_err();
+        let mut message = Vec::new();
 
-                if i == first_char {
-                    break;
-                }
-            }
+        err.write_message(&mut message, "test.pipa", "{{ pipa_ñ }}").unwrap();
 
-            // write message
-            write!(f, "{}", offset_str)?;
-            write!(f, "^")?;
+        assert_eq!(err.code(), "P0002");
+        assert!(String::from_utf8(message).unwrap().contains("[P0002] Only alphabetic ascii-chars can be used for names"));
+    }
 
+    #[cfg(feature = "json")]
+    #[test]
+    fn to_json() {
+        let code = "<p>\n{{ first";
+        let err = ast(code).unwrap_err();
 
-            write!(f, "\n{}{}\n", offset_str, message)?;
-        }
+        assert_eq!(
+            err.to_json(code),
+            r#"{"line":2,"column":7,"code":"P0001","message":"Expected: '}}'"}"#
+        );
+    }
 
-        if g == "\n" || g == "\r\n" {
-            line_start = i + 1;
-            line += 1;
-            output.clear();
+    #[test]
+    fn position_in_line() {
+        let code = "first line\r\n{{ second }}\nthird {{ line }}";
+        let cases = [
+            (0, (1, 0), "first line"),
+            (6, (1, 6), "first line"),
+            (15, (2, 3), "{{ second }}"),
+            (31, (3, 6), "third {{ line }}"),
+            (code.len(), (3, 16), "third {{ line }}"),
+        ];
+
+        for (first_char, position, line) in cases {
+            let err = CompileError::new_name(first_char);
+
+            assert_eq!(err.position_in_line(code), position, "{}", first_char);
+            assert_eq!(err.source_line(code), line, "{}", first_char);
         }
+    }
+
+    #[test]
+    fn tabs_before_error() {
+        let code = "\t{{\tпять | \"$(_)\" }}";
+        let err = ast(code).unwrap_err();
+        let mut message = Vec::new();
+
+        err.write_message(&mut message, "test.pipa", code).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        let lines: Vec<&str> = message.lines().collect();
 
+        // tabs of the line are kept, so the caret is under the error with any tab width
+        assert_eq!(lines[1], "1 | \t{{\tпять | \"$(_)\" }}");
+        assert_eq!(lines[2], "  | \t  \t^");
+        assert!(lines[3].starts_with("  | \t  \t["), "{}", lines[3]);
     }
 
-    Ok(())
-}
+    #[test]
+    fn context_lines() {
+        let write = |code: &str, first_char: usize| {
+            let mut message = Vec::new();
+
+            CompileError::new_name(first_char).write_message(&mut message, "test.pipa", code).unwrap();
+            String::from_utf8(message).unwrap()
+        };
+        let msg = "[P0002] Only alphabetic ascii-chars can be used for names";
+
+        assert_eq!(
+            write("first\r\n{{ second }}\n\tthird", 10),
+            format!("test.pipa:2:3\n1 | first\n2 | {{{{ second }}}}\n  |    ^\n  |    {}\n3 | \tthird\n", msg)
+        );
+        // no line before the first one and after the last one
+        assert_eq!(write("{{ x }}", 3), format!("test.pipa:1:3\n1 | {{{{ x }}}}\n  |    ^\n  |    {}\n", msg));
+        // the gutter is as wide as the widest line number
+        let code = "\n".repeat(8) + "{{ x }}\n";
+        assert_eq!(
+            write(&code, 11),
+            format!("test.pipa:9:3\n 8 |\n 9 | {{{{ x }}}}\n   |    ^\n   |    {}\n10 |\n", msg)
+        );
+    }
+
+    #[test]
+    fn hash() {
+        let hash = |err: &CompileError| {
+            let mut hasher = DefaultHasher::new();
+
+            err.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        assert_eq!(hash(&CompileError::new_syntax(5, &[])), hash(&CompileError::new_syntax(5, &[])));
+        assert_ne!(hash(&CompileError::new_syntax(5, &[])), hash(&CompileError::new_syntax(6, &[])));
+        assert_ne!(hash(&CompileError::new_syntax(5, &[])), hash(&CompileError::new_name(5)));
 
+        let errors = HashSet::from([CompileError::new_syntax(5, &[]), CompileError::new_syntax(5, &[]), CompileError::new_name(5)]);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn non_ascii_name() {
+        let err = ast("{{ first }} {{ pipa_ñ }}").unwrap_err();
+
+        assert_eq!(err.reason(), &ErrorReason::NameError);
+        assert_eq!(err.offset(), 15);
+        assert_eq!(err.clone(), err);
+    }
+}
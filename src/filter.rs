@@ -0,0 +1,324 @@
+use unicode_segmentation::UnicodeSegmentation;
+use std::fmt;
+use crate::error::ErrorReason;
+
+
+/// Argument of a filter call, only literals are allowed, so filters are checked at compile time
+#[derive(Debug, PartialEq, Clone)]
+pub enum FilterArg {
+    Int(u64),
+    String(String),
+}
+
+/// Largest number of fractional digits of `fixed`, so a template can't request a huge output
+pub const MAX_DECIMALS: u64 = 255;
+
+/// Filters are applied to the piped value, e.g. `first | truncate(10, "…")`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Filter {
+    /// Keeps the first `len` graphemes, appends `suffix` if the value was longer
+    Truncate {
+        len: u64,
+        suffix: String,
+    },
+    /// Reads the value as an integer count of the smallest unit, e.g. cents,
+    /// and renders it with `decimals` fractional digits: `500 | fixed(2)` is "5.00".
+    /// Values that are not unsigned integers are kept as is
+    Fixed {
+        decimals: u64,
+    },
+    /// Replaces all occurrences of `from` with `to`, `from` can't be empty
+    Replace {
+        from: String,
+        to: String,
+    },
+    /// Replaces an empty value, e.g. of an undefined var, with `value`
+    Default {
+        value: String,
+    },
+    /// Wraps the value into an attribute with a leading space: `cls | attr("class")` is ` class="cls"`.
+    /// An empty value renders nothing, so there's no `class=""`. The value isn't escaped
+    Attr {
+        name: String,
+    },
+    /// Removes ANSI escape sequences like colors, `"\x1b[31mred\x1b[0m"` becomes "red"
+    StripAnsi,
+    /// Decodes HTML entities: `&amp;`, `&lt;`, `&gt;`, `&quot;`, `&apos;`, `&nbsp;` and numeric ones
+    /// like `&#65;` or `&#x41;`. Unknown entities are kept as is
+    #[cfg(feature = "html")]
+    HtmlDecode,
+}
+
+impl Filter {
+    pub fn new(name: &str, args: Vec<FilterArg>) -> Result<Self, ErrorReason> {
+        let invalid_args = |expected| ErrorReason::FilterArgs { name: name.into(), expected };
+
+        match name {
+            "truncate" => {
+                let mut args = args.into_iter();
+
+                match (args.next(), args.next(), args.next()) {
+                    (Some(FilterArg::Int(len)), None, None) => Ok(Filter::Truncate { len, suffix: String::new() }),
+                    (Some(FilterArg::Int(len)), Some(FilterArg::String(suffix)), None) => Ok(Filter::Truncate { len, suffix }),
+                    _ => Err(invalid_args("(Int) or (Int, String)")),
+                }
+            },
+            "fixed" => match args.as_slice() {
+                [FilterArg::Int(decimals)] if *decimals <= MAX_DECIMALS => Ok(Filter::Fixed { decimals: *decimals }),
+                _ => Err(invalid_args("(Int up to 255)")),
+            },
+            "replace" => match args.as_slice() {
+                [FilterArg::String(from), FilterArg::String(to)] if !from.is_empty() => Ok(Filter::Replace { from: from.clone(), to: to.clone() }),
+                _ => Err(invalid_args("(non-empty String, String)")),
+            },
+            "default" => match args.as_slice() {
+                [FilterArg::String(value)] => Ok(Filter::Default { value: value.clone() }),
+                _ => Err(invalid_args("(String)")),
+            },
+            "attr" => match args.as_slice() {
+                [FilterArg::String(name)] if !name.is_empty() => Ok(Filter::Attr { name: name.clone() }),
+                _ => Err(invalid_args("(non-empty String)")),
+            },
+            "strip_ansi" => match args.as_slice() {
+                [] => Ok(Filter::StripAnsi),
+                _ => Err(invalid_args("()")),
+            },
+            #[cfg(feature = "html")]
+            "html_decode" => match args.as_slice() {
+                [] => Ok(Filter::HtmlDecode),
+                _ => Err(invalid_args("()")),
+            },
+            _ => Err(ErrorReason::UnknownFilter { name: name.into() }),
+        }
+    }
+
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Filter::Truncate { len, suffix } => {
+                let mut graphemes = UnicodeSegmentation::graphemes(value, true);
+                let mut output: String = graphemes.by_ref().take(usize::try_from(*len).unwrap_or(usize::MAX)).collect();
+
+                if graphemes.next().is_some() {
+                    output.push_str(suffix);
+                }
+
+                output
+            },
+            Filter::Fixed { decimals } => {
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return value.into();
+                }
+
+                // digits are shifted as a string, so values of any length don't overflow
+                let decimals = usize::try_from(*decimals).unwrap_or(usize::MAX);
+                let digits = value.trim_start_matches('0');
+                // the width is padded by hand, format! widths must fit into u16
+                let padding = decimals.saturating_add(1).saturating_sub(digits.len());
+                let digits: String = std::iter::repeat_n('0', padding).chain(digits.chars()).collect();
+                let (int, fraction) = digits.split_at(digits.len() - decimals);
+
+                match fraction.is_empty() {
+                    true => int.into(),
+                    false => format!("{}.{}", int, fraction),
+                }
+            },
+            Filter::Replace { from, to } => value.replace(from.as_str(), to),
+            Filter::Default { value: default } => match value.is_empty() {
+                true => default.clone(),
+                false => value.into(),
+            },
+            Filter::Attr { name } => match value.is_empty() {
+                true => String::new(),
+                false => format!(" {}=\"{}\"", name, value),
+            },
+            Filter::StripAnsi => strip_ansi(value),
+            #[cfg(feature = "html")]
+            Filter::HtmlDecode => html_decode(value),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Truncate { len, suffix } => write!(f, "truncate({}, {:?})", len, suffix),
+            Filter::Fixed { decimals } => write!(f, "fixed({})", decimals),
+            Filter::Replace { from, to } => write!(f, "replace({:?}, {:?})", from, to),
+            Filter::Default { value } => write!(f, "default({:?})", value),
+            Filter::Attr { name } => write!(f, "attr({:?})", name),
+            Filter::StripAnsi => write!(f, "strip_ansi()"),
+            #[cfg(feature = "html")]
+            Filter::HtmlDecode => write!(f, "html_decode()"),
+        }
+    }
+}
+
+// control sequences are ESC, '[', parameter and intermediate bytes, then a final byte in '@'..='~'.
+// Other escapes are kept, an unterminated sequence at the end is dropped
+fn strip_ansi(value: &str) -> String {
+    enum State {
+        Text,
+        Escape,
+        Sequence,
+    }
+
+    let mut output = String::with_capacity(value.len());
+    let mut state = State::Text;
+
+    for c in value.chars() {
+        state = match (state, c) {
+            (State::Text, '\x1b') => State::Escape,
+            (State::Text, c) => {
+                output.push(c);
+                State::Text
+            },
+            (State::Escape, '[') => State::Sequence,
+            (State::Escape, c) => {
+                output.push('\x1b');
+                output.push(c);
+                State::Text
+            },
+            (State::Sequence, '@'..='~') => State::Text,
+            (State::Sequence, _) => State::Sequence,
+        };
+    }
+
+    output
+}
+
+// entities are decoded in one pass, so "&amp;lt;" becomes "&lt;"
+#[cfg(feature = "html")]
+fn html_decode(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest.find(';').and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => '\u{a0}',
+                entity => {
+                    let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => entity.strip_prefix('#')?.parse().ok()?,
+                    };
+
+                    char::from_u32(code)?
+                },
+            };
+
+            Some((c, end))
+        });
+
+        match decoded {
+            Some((c, end)) => {
+                output.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::filter::Filter;
+
+    #[test]
+    fn truncate() {
+        let filter = Filter::Truncate { len: 5, suffix: "…".into() };
+
+        assert_eq!(filter.apply(""), "");
+        assert_eq!(filter.apply("short"), "short");
+        assert_eq!(filter.apply("longer"), "longe…");
+        // counted by graphemes like PutName
+        assert_eq!(filter.apply("пятый аргумент"), "пятый…");
+        assert_eq!(filter.apply("🇺🇦🇺🇦🇺🇦🇺🇦🇺🇦🇺🇦"), "🇺🇦🇺🇦🇺🇦🇺🇦🇺🇦…");
+    }
+
+    #[test]
+    fn fixed() {
+        let filter = Filter::Fixed { decimals: 2 };
+
+        assert_eq!(filter.apply("500"), "5.00");
+        assert_eq!(filter.apply("1999"), "19.99");
+        assert_eq!(filter.apply("5"), "0.05");
+        assert_eq!(filter.apply("0"), "0.00");
+        assert_eq!(filter.apply("000123"), "1.23");
+        assert_eq!(filter.apply("123456789012345678901234567890"), "1234567890123456789012345678.90");
+        assert_eq!(Filter::Fixed { decimals: 0 }.apply("500"), "500");
+        // wider than a format! width
+        assert_eq!(Filter::Fixed { decimals: 70000 }.apply("5"), format!("0.{}5", "0".repeat(69999)));
+        // not a count of units
+        assert_eq!(filter.apply(""), "");
+        assert_eq!(filter.apply("-5"), "-5");
+        assert_eq!(filter.apply("5.5"), "5.5");
+    }
+
+    #[test]
+    fn replace() {
+        let filter = Filter::Replace { from: "a".into(), to: "o".into() };
+
+        assert_eq!(filter.apply("banana"), "bonono");
+        assert_eq!(filter.apply("first"), "first");
+        assert_eq!(filter.apply(""), "");
+        assert_eq!(Filter::Replace { from: ", ".into(), to: "".into() }.apply("a, b, c"), "abc");
+        assert_eq!(Filter::Replace { from: "🇺🇦".into(), to: "UA".into() }.apply("🇺🇦 пятый"), "UA пятый");
+    }
+
+    #[test]
+    fn default() {
+        let filter = Filter::Default { value: "guest".into() };
+
+        assert_eq!(filter.apply("admin"), "admin");
+        assert_eq!(filter.apply(""), "guest");
+        // only empty values are replaced
+        assert_eq!(filter.apply(" "), " ");
+    }
+
+    #[test]
+    fn strip_ansi() {
+        let filter = Filter::StripAnsi;
+
+        assert_eq!(filter.apply("\x1b[1;31merror\x1b[0m: \x1b[4mfile\x1b[24m not found"), "error: file not found");
+        assert_eq!(filter.apply("plain text"), "plain text");
+        // cursor movements are control sequences too, other escapes are kept
+        assert_eq!(filter.apply("\x1b[2Kпятый\x1b7 🇺🇦"), "пятый\x1b7 🇺🇦");
+        assert_eq!(filter.apply("cut\x1b[31"), "cut");
+    }
+
+    #[test]
+    fn attr() {
+        let filter = Filter::Attr { name: "class".into() };
+
+        assert_eq!(filter.apply("big red"), " class=\"big red\"");
+        assert_eq!(filter.apply(""), "");
+        assert_eq!(filter.apply(" "), " class=\" \"");
+    }
+
+    #[cfg(feature = "html")]
+    #[test]
+    fn html_decode() {
+        let filter = Filter::HtmlDecode;
+
+        assert_eq!(filter.apply("&amp;lt;"), "&lt;");
+        assert_eq!(filter.apply("&lt;p class=&quot;a&quot;&gt;&#65;&#x41;&#X42;&lt;/p&gt;"), "<p class=\"a\">AAB</p>");
+        // unknown and broken entities are kept
+        assert_eq!(filter.apply("&copy; & &#xZZ; &#1114112; &amp"), "&copy; & &#xZZ; &#1114112; &amp");
+        assert_eq!(filter.apply("пятый &amp; 🇺🇦"), "пятый & 🇺🇦");
+    }
+}
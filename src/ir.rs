@@ -1,47 +1,129 @@
 use std::fmt;
-use std::collections::{HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::Write;
-use crate::syntax::{Node, TokenType, InnerNode};
+use std::sync::Arc;
+use crate::syntax::{Node, TokenType, InnerNode, CompileOptions, DEFAULT_OPTIONS, ast_with_options, code_block_spans};
 use crate::error::CompileError;
 use crate::analysis::{evaluate_expr, unique_constants_expr, OptOptions};
+use crate::filter::Filter;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Op {
     PutStr {
-        value: String,
+        value: Arc<str>,
     },
     Flush,
     Collapse,
     PutName {
-        start: Option<usize>,
-        end: Option<usize>,
-        name: String,
+        start: Option<u64>,
+        end: Option<u64>,
+        name: Arc<str>,
     },
     SetCounter {
-        value: usize,
+        value: u64,
     },
     IncCounter,
     LoadCounter,
+    LoadCounterFmt {
+        width: u64,
+        pad: char,
+    },
     CmpCounterLessJmp {
         op_index: usize,
-        value: Option<usize>,
+        value: Option<u64>,
         name: String,
     },
     CmpArrayEmptyJmp {
         op_index: usize,
-        start: Option<usize>,
-        end: Option<usize>,
+        start: Option<u64>,
+        end: Option<u64>,
         name: String,
     },
     LoadArrayItem {
         name: String,
     },
     PutScopeVar {
-        name: String,
+        name: Arc<str>,
     },
     DestroyScope,
+    Assert {
+        name: Box<str>,
+        expected: Box<str>,
+    },
+    Comment {
+        text: Box<str>,
+    },
+    CmpBoolJmp {
+        op_index: usize,
+        value: bool,
+        name: String,
+    },
+    LoadStringGraphemes {
+        name: String,
+    },
+    LoadConcatArrays {
+        names: Vec<String>,
+    },
+    LoadZipArrays {
+        a: String,
+        b: String,
+    },
+    Filter {
+        filter: Filter,
+    },
+    Noop,
+    PutInterned {
+        index: u32,
+    },
+    Halt,
+    PushIndent {
+        width: u64,
+    },
+    PopIndent {
+        width: u64,
+    },
 }
 
+impl Op {
+    /// Name of the variant, e.g. `PutStr`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Op::PutStr { .. } => "PutStr",
+            Op::Flush => "Flush",
+            Op::Collapse => "Collapse",
+            Op::PutName { .. } => "PutName",
+            Op::SetCounter { .. } => "SetCounter",
+            Op::IncCounter => "IncCounter",
+            Op::LoadCounter => "LoadCounter",
+            Op::LoadCounterFmt { .. } => "LoadCounterFmt",
+            Op::CmpCounterLessJmp { .. } => "CmpCounterLessJmp",
+            Op::CmpArrayEmptyJmp { .. } => "CmpArrayEmptyJmp",
+            Op::LoadArrayItem { .. } => "LoadArrayItem",
+            Op::PutScopeVar { .. } => "PutScopeVar",
+            Op::DestroyScope => "DestroyScope",
+            Op::Assert { .. } => "Assert",
+            Op::Comment { .. } => "Comment",
+            Op::CmpBoolJmp { .. } => "CmpBoolJmp",
+            Op::LoadStringGraphemes { .. } => "LoadStringGraphemes",
+            Op::LoadConcatArrays { .. } => "LoadConcatArrays",
+            Op::LoadZipArrays { .. } => "LoadZipArrays",
+            Op::Filter { .. } => "Filter",
+            Op::Noop => "Noop",
+            Op::PutInterned { .. } => "PutInterned",
+            Op::Halt => "Halt",
+            Op::PushIndent { .. } => "PushIndent",
+            Op::PopIndent { .. } => "PopIndent",
+        }
+    }
+
+    /// Index of the op a jump op sets pc to, None for other ops
+    pub fn jump_target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Op::CmpCounterLessJmp { op_index, .. } | Op::CmpArrayEmptyJmp { op_index, .. } | Op::CmpBoolJmp { op_index, .. } => Some(op_index),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Op {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -67,6 +149,9 @@ impl fmt::Display for Op {
             Op::LoadCounter => {
                 write!(f, "LoadCounter")
             },
+            Op::LoadCounterFmt { width, pad } => {
+                write!(f, "LoadCounterFmt {} {:?}", width, pad)
+            },
             Op::CmpCounterLessJmp { op_index, value, name } => {
                 write!(f, "CmpCounterLessJmp {} {} {}", op_index, value.unwrap_or_default(), name)
             }
@@ -82,11 +167,47 @@ impl fmt::Display for Op {
             Op::DestroyScope => {
                 write!(f, "DestroyScope")
             },
+            Op::Assert { name, expected } => {
+                write!(f, "Assert {} {:?}", name, expected)
+            },
+            Op::Comment { text } => {
+                write!(f, "Comment {}", text)
+            },
+            Op::CmpBoolJmp { op_index, value, name } => {
+                write!(f, "CmpBoolJmp {} {} {}", op_index, value, name)
+            },
+            Op::Filter { filter } => {
+                write!(f, "Filter {}", filter)
+            },
+            Op::Noop => {
+                write!(f, "Noop")
+            },
+            Op::PutInterned { index } => {
+                write!(f, "PutInterned {}", index)
+            },
+            Op::Halt => {
+                write!(f, "Halt")
+            },
+            Op::PushIndent { width } => {
+                write!(f, "PushIndent {}", width)
+            },
+            Op::PopIndent { width } => {
+                write!(f, "PopIndent {}", width)
+            },
+            Op::LoadStringGraphemes { name } => {
+                write!(f, "LoadStringGraphemes {}", name)
+            },
+            Op::LoadConcatArrays { names } => {
+                write!(f, "LoadConcatArrays {}", names.join(" "))
+            },
+            Op::LoadZipArrays { a, b } => {
+                write!(f, "LoadZipArrays {} {}", a, b)
+            },
         }
     }
 }
 
-#[derive(Copy, PartialEq, Debug, Clone)]
+#[derive(Copy, PartialEq, Eq, Debug, Clone)]
 pub enum Type {
     String, 
     Int,
@@ -121,24 +242,97 @@ pub fn is_name_array(name: &str) -> bool {
     }) && name.len() > 1
 }
 
-fn in_scope(first_char: usize, name: &str, scope: &mut HashSet<Box<str>>) -> Result<(), CompileError> {
-    if !scope.contains(name) && is_name_reserved(&name) {
-        return Err(CompileError::new_undefined_var(first_char, name.to_string()));
+/// Naming conventions used by the generator, pass the same config to `Vm::with_naming`,
+/// so vars are classified the same way at runtime
+#[derive(Clone, Copy, Debug)]
+pub struct GenIrConfig {
+    pub is_array: fn(&str) -> bool,
+    pub is_reserved: fn(&str) -> bool,
+}
+
+impl Default for GenIrConfig {
+    fn default() -> Self {
+        Self {
+            is_array: is_name_array,
+            is_reserved: is_name_reserved,
+        }
+    }
+}
+
+fn in_scope(first_char: usize, expanded_at: Option<(usize, usize)>, name: &str, scope: &mut HashSet<Box<str>>, config: &GenIrConfig) -> Result<(), CompileError> {
+    if !scope.contains(name) && (config.is_reserved)(name) {
+        return Err(CompileError::new_undefined_var(first_char, name.to_string()).in_expansion(expanded_at));
+    }
+
+    Ok(())
+}
+
+// the parser recognizes arrays by the default convention, names it took for arrays or values must agree with the config
+fn check_array(first_char: usize, expanded_at: Option<(usize, usize)>, name: &str, config: &GenIrConfig) -> Result<(), CompileError> {
+    if !(config.is_array)(name) {
+        return Err(CompileError::new_type_error(first_char, Type::Array, Type::Name).in_expansion(expanded_at));
+    }
+
+    Ok(())
+}
+
+// Scope vars are checked on the chain before optimizations, because string evaluation can
+// drop a reference that is made before its defining pipe.
+fn check_expr_scope(code: &str, node: &Node, mut scope: HashSet<Box<str>>, config: &GenIrConfig) -> Result<(), CompileError> {
+    let mut tail = node;
+
+    loop {
+        match *tail.inner {
+            InnerNode::String { ref children } => {
+                for child in children {
+                    if let InnerNode::Name { .. } = *child.inner {
+                        in_scope(child.first_char, child.expanded_at, child.as_str(code), &mut scope, config)?;
+                    }
+                }
+            },
+            InnerNode::Name { .. } => {
+                in_scope(tail.first_char, tail.expanded_at, tail.as_str(code), &mut scope, config)?;
+            },
+            // filters are applied to the piped value
+            InnerNode::Filter { .. } => {
+                in_scope(tail.first_char, tail.expanded_at, "_", &mut scope, config)?;
+            },
+            InnerNode::FanOut { ref branches } => {
+                in_scope(tail.first_char, tail.expanded_at, "_", &mut scope, config)?;
+
+                for branch in branches {
+                    check_expr_scope(code, branch, scope.clone(), config)?;
+                }
+            },
+            _ => {},
+        }
+
+        match tail.children.first() {
+            Some(child) => {
+                scope.insert("_".into());
+                tail = child;
+            },
+            None => break,
+        }
     }
 
     Ok(())
 }
 
-fn gen_primitive_ir(code: &str, node: &Node, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>) -> Result<(), CompileError> {
+fn gen_primitive_ir(code: &str, node: &Node, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>, config: &GenIrConfig) -> Result<(), CompileError> {
     match *node.inner {
         InnerNode::Literal { .. } => {
             let value = node.as_escaped_string(code, &[TokenType::CodeBegin, TokenType::CodeEnd]).into();
             ops.push(Op::PutStr { value });
         },
         InnerNode::Name { start, end } => {
-            let name: String = node.as_str(code).into();
+            let name: Arc<str> = node.as_str(code).into();
 
-            in_scope(node.first_char, &name, scope)?;
+            in_scope(node.first_char, node.expanded_at, &name, scope, config)?;
+
+            if (config.is_array)(&name) {
+                return Err(CompileError::new_array_pipe(node.first_char).in_expansion(node.expanded_at));
+            }
 
             ops.push(Op::PutName { name, start, end });
         },
@@ -151,9 +345,14 @@ fn gen_primitive_ir(code: &str, node: &Node, scope: &mut HashSet<Box<str>>, ops:
     Ok(())
 }
 
-fn gen_string_ir(code: &str, children: &Vec<Node>, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>) -> Result<(), CompileError> {
+fn gen_string_ir(code: &str, children: &Vec<Node>, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>, config: &GenIrConfig) -> Result<(), CompileError> {
+    // empty string still has a value, which can be piped
+    if children.is_empty() {
+        ops.push(Op::PutStr { value: "".into() });
+    }
+
     for n in children {
-        gen_primitive_ir(code, n, scope, ops)?;
+        gen_primitive_ir(code, n, scope, ops, config)?;
     }
 
     if children.len() > 1 {
@@ -163,25 +362,35 @@ fn gen_string_ir(code: &str, children: &Vec<Node>, scope: &mut HashSet<Box<str>>
     Ok(())
 }
 
-fn gen_expr_ir(code: &str, mut node: Node, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>) -> Result<(), CompileError> {
+// scope_dirty is set when a PutScopeVar is emitted, so a loop knows if its body needs DestroyScope
+fn gen_expr_ir(code: &str, mut node: Node, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>, config: &GenIrConfig, scope_dirty: &mut bool) -> Result<(), CompileError> {
 
     loop {
         match *node.inner {
             InnerNode::String { ref children } => {
-                gen_string_ir(code, children, scope, ops)?;
+                gen_string_ir(code, children, scope, ops, config)?;
             },
             InnerNode::Name { .. } => {
-                gen_primitive_ir(code, &node, scope, ops)?;
+                gen_primitive_ir(code, &node, scope, ops, config)?;
             },
             InnerNode::Int { .. } => {
-                gen_primitive_ir(code, &node, scope, ops)?;
+                gen_primitive_ir(code, &node, scope, ops, config)?;
+            },
+            InnerNode::Filter { ref filter } => {
+                ops.push(Op::Filter { filter: filter.clone() });
+            },
+            InnerNode::FanOut { ref mut branches } => {
+                gen_fan_out_ir(code, std::mem::take(branches), scope, ops, config, scope_dirty)?;
             },
             _ => unreachable!(),
         }
 
         if let Some(child) = node.children.pop() {
-            scope.insert("_".into());
-            ops.push(Op::PutScopeVar{ name: "_".into() });
+            if !takes_stack_value(&child) {
+                scope.insert("_".into());
+                ops.push(Op::PutScopeVar{ name: "_".into() });
+                *scope_dirty = true;
+            }
 
             node = child;
         } else {
@@ -192,15 +401,167 @@ fn gen_expr_ir(code: &str, mut node: Node, scope: &mut HashSet<Box<str>>, ops: &
     Ok(())
 }
 
-pub fn gen_ir(code: &str, ast: Vec<Node>, opt: OptOptions) -> Result<Vec<Op>, CompileError> {
-    let mut scope = HashSet::new();
+// filters and fan-outs take the piped value from the stack instead of '_'
+fn takes_stack_value(node: &Node) -> bool {
+    matches!(*node.inner, InnerNode::Filter { .. } | InnerNode::FanOut { .. })
+}
+
+// Every branch reads the piped value from a scope var, so they all see the same '_'.
+// Results are kept in scope vars until the last branch is done, because Collapse takes the whole stack.
+fn gen_fan_out_ir(code: &str, branches: Vec<Node>, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>, config: &GenIrConfig, scope_dirty: &mut bool) -> Result<(), CompileError> {
+    // position of the fan-out keeps the names unique if fan-outs are nested
+    let id = ops.len();
+    let input: Arc<str> = format!("_fanin{}_", id).into();
+    let mut outputs = Vec::with_capacity(branches.len());
+
+    ops.push(Op::PutScopeVar { name: input.clone() });
+    *scope_dirty = true;
+
+    for (i, branch) in branches.into_iter().enumerate() {
+        ops.push(Op::PutName { name: input.clone(), start: None, end: None });
+
+        if !takes_stack_value(&branch) {
+            scope.insert("_".into());
+            ops.push(Op::PutScopeVar { name: "_".into() });
+        }
+
+        gen_expr_ir(code, branch, scope, ops, config, scope_dirty)?;
+
+        let output: Arc<str> = format!("_fanout{}_{}_", id, i).into();
+        ops.push(Op::PutScopeVar { name: output.clone() });
+        outputs.push(output);
+    }
+
+    for name in &outputs {
+        ops.push(Op::PutName { name: name.clone(), start: None, end: None });
+    }
+
+    if outputs.len() > 1 {
+        ops.push(Op::Collapse);
+    }
+
+    Ok(())
+}
+
+pub fn gen_ir(code: &str, ast: &[Node], opt: OptOptions) -> Result<Vec<Op>, CompileError> {
+    gen_ir_with_config(code, ast, opt, &GenIrConfig::default())
+}
+
+/// The ast is borrowed, so it can be compiled again, e.g. with other options
+pub fn gen_ir_with_config(code: &str, ast: &[Node], opt: OptOptions, config: &GenIrConfig) -> Result<Vec<Op>, CompileError> {
     let mut ops = Vec::with_capacity(ast.len());
+
+    // optimizations rewrite the nodes, so they work on a copy
+    gen_nodes_ir(code, ast.to_vec(), opt, config, &mut ops)?;
+
+    Ok(compress_noops(ops))
+}
+
+/// Compiles only `code[start..end]`, e.g. a block selected in an editor. Positions of errors point into `code`.
+/// The span must not split a code block, and macros defined outside of it are undefined.
+pub fn compile_span(code: &str, start: usize, end: usize, opt: OptOptions) -> Result<Vec<Op>, CompileError> {
+    compile_span_with_options(code, start, end, opt, &DEFAULT_OPTIONS)
+}
+
+/// Same as [`compile_span`], but the code is lexed with the delimiters of `options`.
+pub fn compile_span_with_options(
+    code: &str,
+    start: usize,
+    end: usize,
+    opt: OptOptions,
+    options: &CompileOptions,
+) -> Result<Vec<Op>, CompileError> {
+    // only the delimiters are scanned, so errors outside of the span don't fail it
+    let blocks = code_block_spans(code, options);
+    for boundary in [start, end] {
+        if blocks.iter().any(|&(first, last)| first < boundary && boundary < last) {
+            return Err(CompileError::new_syntax(boundary, &[TokenType::CodeEnd]));
+        }
+    }
+
+    let span = code.get(start..end).ok_or_else(|| CompileError::new_syntax(start, &[]))?;
+    let shift = |mut err: CompileError| {
+        err.first_char += start;
+        err.expanded_at = err.expanded_at.map(|(first, last)| (first + start, last + start));
+        err
+    };
+
+    let nodes = ast_with_options(span, options).map_err(shift)?;
+    gen_ir(span, &nodes, opt).map_err(shift)
+}
+
+/// Moves literals of `PutStr` ops into a table of unique strings and replaces the ops with `PutInterned`,
+/// so a literal repeated in the template, e.g. an html tag, is stored once.
+pub fn intern_strings(ir: Vec<Op>) -> (Vec<Op>, Vec<Arc<str>>) {
+    let mut indices: HashMap<Arc<str>, u32> = HashMap::new();
+    let mut strings = vec![];
+
+    let ir = ir.into_iter()
+        .map(|op| match op {
+            Op::PutStr { value } => {
+                let index = *indices.entry(value).or_insert_with_key(|value| {
+                    strings.push(value.clone());
+                    (strings.len() - 1) as u32
+                });
+
+                Op::PutInterned { index }
+            },
+            op => op,
+        })
+        .collect();
+
+    (ir, strings)
+}
+
+/// Removes `Noop` ops left by optimizations in place of removed ops and moves jump targets accordingly,
+/// so optimizations don't have to renumber jumps themselves.
+///
+/// If a jump continues at a position preceded only by `Noop` ops, it can't be expressed without them,
+/// then the IR is returned unchanged.
+pub fn compress_noops(ir: Vec<Op>) -> Vec<Op> {
+    // noops[i] is the number of Noop ops before i
+    let mut noops = Vec::with_capacity(ir.len() + 1);
+    let mut count = 0;
+
+    for op in &ir {
+        noops.push(count);
+
+        if let Op::Noop = op {
+            count += 1;
+        }
+    }
+    noops.push(count);
+
+    if count == 0 {
+        return ir;
+    }
+
+    let compressed: Option<Vec<Op>> = ir.iter()
+        .filter(|op| **op != Op::Noop)
+        .cloned()
+        .map(|mut op| {
+            if let Some(op_index) = op.jump_target_mut() {
+                // pc is incremented after a jump, so the op after op_index is run next
+                let next = std::cmp::min(*op_index + 1, noops.len() - 1);
+
+                *op_index = (next - noops[next]).checked_sub(1)?;
+            }
+
+            Some(op)
+        })
+        .collect();
+
+    compressed.unwrap_or(ir)
+}
+
+fn gen_nodes_ir(code: &str, ast: Vec<Node>, opt: OptOptions, config: &GenIrConfig, ops: &mut Vec<Op>) -> Result<(), CompileError> {
+    let mut scope = HashSet::new();
     let mut iter = ast.into_iter().peekable();
 
     while let Some(mut node) = iter.next() {
         match *node.inner {
             InnerNode::Literal => {
-                gen_primitive_ir(code, &node, &mut scope, &mut ops)?;
+                gen_primitive_ir(code, &node, &mut scope, ops, config)?;
 
                 // buffer must be flushed at the end of execution
                 if iter.peek().is_none() {
@@ -208,12 +569,22 @@ pub fn gen_ir(code: &str, ast: Vec<Node>, opt: OptOptions) -> Result<Vec<Op>, Co
                 }
             },
             InnerNode::String { .. } | InnerNode::Int { .. } | InnerNode::Name { .. } => {
+                check_expr_scope(code, &node, HashSet::new(), config)?;
+                let comment = match opt.debug_comments && !node.children.is_empty() {
+                    true => Some(format!("pipe of {}", node.as_str(code))),
+                    false => None,
+                };
 
                 // optimize node if it is an expr
                 if opt.string_evaluation {
                     match evaluate_expr(node, code) {
                         Some(n) => node = n,
                         None => {
+                            // buffer must be flushed at the end of execution
+                            if iter.peek().is_none() && !ops.is_empty() {
+                                ops.push(Op::Flush);
+                            }
+
                             scope.clear();
                             continue;
                         }
@@ -225,7 +596,11 @@ pub fn gen_ir(code: &str, ast: Vec<Node>, opt: OptOptions) -> Result<Vec<Op>, Co
                     ops.push(Op::Flush);
                 }
 
-                gen_expr_ir(code, node, &mut scope, &mut ops)?;
+                if let Some(text) = comment {
+                    ops.push(Op::Comment { text: text.into() });
+                }
+
+                gen_expr_ir(code, node, &mut scope, ops, config, &mut false)?;
 
                 // buffer must be flushed at the end of execution
                 if iter.peek().is_none() {
@@ -234,110 +609,484 @@ pub fn gen_ir(code: &str, ast: Vec<Node>, opt: OptOptions) -> Result<Vec<Op>, Co
 
                 scope.clear();
             },
-            InnerNode::Array { name, start, end } => {
-                // node is an array, so previous ops should be flushed
+            InnerNode::Condition { name, negate, body } => {
+                in_scope(node.first_char, node.expanded_at, &name, &mut scope, config)?;
+
+                // the body may be skipped, so previous ops should be flushed
+                if !ops.is_empty() {
+                    ops.push(Op::Flush);
+                }
+
+                // skip the body if the condition doesn't hold
+                let op_index_begin = ops.len();
+                ops.push(Op::CmpBoolJmp { op_index: 0, value: negate, name });
+
+                gen_nodes_ir(code, body, opt, config, ops)?;
+
+                // execution continues after the last op of the body
+                let op_index_end = ops.len() - 1;
+                if let Op::CmpBoolJmp { op_index, .. } = &mut ops[op_index_begin] {
+                    *op_index = op_index_end;
+                }
+            },
+            InnerNode::Indent { width, body } => {
+                // the output before the block isn't indented
                 if !ops.is_empty() {
                     ops.push(Op::Flush);
                 }
 
-                // optimize child node if it is an expr
-                let child = match opt.string_evaluation {
-                    true => {
-                        let node = evaluate_expr(node.children.pop().expect("Should be handled during syntax analysis"), code);
+                ops.push(Op::PushIndent { width });
+                gen_nodes_ir(code, body, opt, config, ops)?;
 
-                        match node {
-                            Some(n) => n,
-                            None => {
-                                scope.clear();
-                                continue;
-                            }
-                        }
-                    },
-                    false => {
-                        node.children.pop().expect("Should be handled during syntax analysis")
-                    }
-                };
+                // the body is flushed while its indentation is active
+                if ops.last() != Some(&Op::Flush) {
+                    ops.push(Op::Flush);
+                }
+                ops.push(Op::PopIndent { width });
+            },
+            // a macro with a filter or a fan-out expanded outside of a pipe
+            InnerNode::Filter { .. } | InnerNode::FanOut { .. } => {
+                return Err(CompileError::new_pipe_no_parent(node.first_char, false).in_expansion(node.expanded_at));
+            },
+            InnerNode::Stop => {
+                // ends the program, the vm flushes the stack itself
+                ops.push(Op::Halt);
+            },
+            InnerNode::Assert { name, expected } => {
+                in_scope(node.first_char, node.expanded_at, &name, &mut scope, config)?;
 
-                // counter can contain any value, so we need to set it to left range arg
-                ops.push(Op::SetCounter { value: start.unwrap_or(0) });
-                // prepare state for current iteration
-                let op_index_begin = ops.len();
-                ops.push(Op::CmpArrayEmptyJmp{ op_index: 0, start, end, name: name.clone().into() });
-                // load constants
-                if opt.constant_evaluation {
-                    // try using evaluted string, fallback to generating new one
-                    let constants = match opt.string_evaluation {
-                        true => unique_constants_expr(&child, code),
-                        // slow path
-                        false => {
-                            let node = match evaluate_expr(child.clone(), code) {
-                                Some(n) => n,
-                                None => {
-                                    scope.clear();
-                                    continue;
-                                }
-                            };
-
-                            unique_constants_expr(&node, code)
-                        },
-                    };
-
-                    // don't load unused constants
-                    if constants.contains("_item_") {
-                        ops.push(Op::LoadArrayItem { name: name.clone().into() });
-                        ops.push(Op::PutScopeVar { name: "_item_".into() });
-                        scope.insert("_item_".into());
-                    }
+                ops.push(Op::Assert { name: name.into(), expected: expected.into() });
 
-                    if constants.contains("_index_") {
-                        ops.push(Op::LoadCounter);
-                        ops.push(Op::PutScopeVar { name: "_index_".into() });
-                        scope.insert("_index_".into());
-                    }
+                // buffer must be flushed at the end of execution
+                if iter.peek().is_none() {
+                    ops.push(Op::Flush);
+                }
+            },
+            InnerNode::Array { name, start, end, pad } => {
+                check_array(node.first_char, node.expanded_at, &name, config)?;
 
-                } else {
-                    // no optimizations
-                    ops.push(Op::LoadArrayItem { name: name.clone().into() });
-                    ops.push(Op::PutScopeVar { name: "_item_".into() });
-                    scope.insert("_item_".into());
+                let source = LoopSource { counter: load_counter(pad), ..LoopSource::array(name, start, end) };
 
-                    ops.push(Op::LoadCounter);
-                    ops.push(Op::PutScopeVar { name: "_index_".into() });
-                    scope.insert("_index_".into());
+                gen_loop_ir(code, node.children, source, opt, config, &mut scope, ops)?;
+            },
+            InnerNode::ArrayConcat { names, start, end, pad } => {
+                for name in &names {
+                    check_array(node.first_char, node.expanded_at, name, config)?;
                 }
 
-                // loop body
-                gen_expr_ir(code, child, &mut scope, &mut ops)?;
+                // the joined array is staged under the names joined with '+'
+                let load = Op::LoadConcatArrays { names: names.clone() };
+                let source = LoopSource { load: Some(load), counter: load_counter(pad), ..LoopSource::array(names.join("+"), start, end) };
 
-                // prepare state for the next iteration
-                ops.push(Op::Flush);
-                ops.push(Op::DestroyScope);
-                scope.clear();
+                gen_loop_ir(code, node.children, source, opt, config, &mut scope, ops)?;
+            },
+            InnerNode::Chars { name, start, end } => {
+                in_scope(node.first_char, node.expanded_at, &name, &mut scope, config)?;
 
-                ops.push(Op::IncCounter);
+                let load = Op::LoadStringGraphemes { name: name.clone() };
+                let source = LoopSource { load: Some(load), ..LoopSource::array(name, start, end) };
 
-                // set op_index for loop begin
-                let op_index_end = ops.len();
-                if let Op::CmpArrayEmptyJmp { op_index, .. } = &mut ops[op_index_begin] {
-                    *op_index = op_index_end;
-                } else {
-                    panic!("Expected CmpArrayEmptyJmp, found {:#?}", &ops[op_index_begin]);
+                gen_loop_ir(code, node.children, source, opt, config, &mut scope, ops)?;
+            },
+            InnerNode::Zip { a, b } => {
+                check_array(node.first_char, node.expanded_at, &a, config)?;
+                check_array(node.first_char, node.expanded_at, &b, config)?;
+
+                // the length of the shorter array is staged under the names joined with ','
+                let load = Op::LoadZipArrays { a: a.clone(), b: b.clone() };
+                let source = LoopSource {
+                    name: format!("{},{}", a, b),
+                    start: None,
+                    end: None,
+                    load: Some(load),
+                    counter: Op::LoadCounter,
+                    items: vec![("_a_", a), ("_b_", b)],
+                };
+
+                gen_loop_ir(code, node.children, source, opt, config, &mut scope, ops)?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+// #padN annotation of a loop
+fn load_counter(pad: Option<u64>) -> Op {
+    match pad {
+        Some(width) => Op::LoadCounterFmt { width, pad: '0' },
+        None => Op::LoadCounter,
+    }
+}
+
+// what a loop iterates
+struct LoopSource {
+    // the loop runs over the range of this array
+    name: String,
+    start: Option<u64>,
+    end: Option<u64>,
+    // stages the array before the loop
+    load: Option<Op>,
+    // pushes _index_
+    counter: Op,
+    // scope var and the array it is read from at the counter
+    items: Vec<(&'static str, String)>,
+}
+
+impl LoopSource {
+    fn array(name: String, start: Option<u64>, end: Option<u64>) -> Self {
+        Self { items: vec![("_item_", name.clone())], name, start, end, load: None, counter: Op::LoadCounter }
+    }
+}
+
+fn gen_loop_ir(code: &str, mut children: Vec<Node>, source: LoopSource, opt: OptOptions, config: &GenIrConfig, scope: &mut HashSet<Box<str>>, ops: &mut Vec<Op>) -> Result<(), CompileError> {
+    let LoopSource { name, start, end, load, counter, items } = source;
+
+    // node is an array, so previous ops should be flushed
+    if !ops.is_empty() {
+        ops.push(Op::Flush);
+    }
+
+    if let Some(child) = children.last() {
+        let loop_scope = items.iter().map(|(var, _)| (*var).into()).chain(["_index_".into()]).collect();
+        check_expr_scope(code, child, loop_scope, config)?;
+    }
+
+    // optimize child node if it is an expr
+    let child = match opt.string_evaluation {
+        true => {
+            let node = evaluate_expr(children.pop().expect("Should be handled during syntax analysis"), code);
+
+            match node {
+                Some(n) => n,
+                None => {
+                    scope.clear();
+                    return Ok(());
                 }
+            }
+        },
+        false => {
+            children.pop().expect("Should be handled during syntax analysis")
+        }
+    };
 
-                // jmp
-                ops.push(Op::CmpCounterLessJmp { name: name, value: end, op_index: op_index_begin });
+    if opt.debug_comments {
+        ops.push(Op::Comment { text: format!("array loop for {}", name).into() });
+    }
 
+    if let Some(op) = load {
+        ops.push(op);
+    }
+
+    // counter can contain any value, so we need to set it to left range arg
+    ops.push(Op::SetCounter { value: start.unwrap_or(0) });
+    // prepare state for current iteration
+    let op_index_begin = ops.len();
+    let mut scope_dirty = false;
+    ops.push(Op::CmpArrayEmptyJmp{ op_index: 0, start, end, name: name.clone() });
+    // load constants
+    if opt.constant_evaluation {
+        // try using evaluted string, fallback to generating new one
+        let constants = match opt.string_evaluation {
+            true => unique_constants_expr(&child, code),
+            // slow path
+            false => {
+                let node = match evaluate_expr(child.clone(), code) {
+                    Some(n) => n,
+                    None => {
+                        scope.clear();
+                        return Ok(());
+                    }
+                };
+
+                unique_constants_expr(&node, code)
             },
+        };
+
+        // don't load unused constants
+        for (var, array) in items {
+            if constants.contains(var) {
+                ops.push(Op::LoadArrayItem { name: array });
+                ops.push(Op::PutScopeVar { name: var.into() });
+                scope.insert(var.into());
+                scope_dirty = true;
+            }
         }
+
+        if constants.contains("_index_") {
+            ops.push(counter);
+            ops.push(Op::PutScopeVar { name: "_index_".into() });
+            scope.insert("_index_".into());
+            scope_dirty = true;
+        }
+
+    } else {
+        // no optimizations
+        for (var, array) in items {
+            ops.push(Op::LoadArrayItem { name: array });
+            ops.push(Op::PutScopeVar { name: var.into() });
+            scope.insert(var.into());
+        }
+
+        ops.push(counter);
+        ops.push(Op::PutScopeVar { name: "_index_".into() });
+        scope.insert("_index_".into());
+        scope_dirty = true;
     }
 
-    Ok(ops)
+    // loop body
+    gen_expr_ir(code, child, scope, ops, config, &mut scope_dirty)?;
+
+    // prepare state for the next iteration
+    ops.push(Op::Flush);
+
+    // the scope is empty if the body didn't define any scope vars
+    if scope_dirty {
+        ops.push(Op::DestroyScope);
+    }
+    scope.clear();
+
+    ops.push(Op::IncCounter);
+
+    // set op_index for loop begin
+    let op_index_end = ops.len();
+    if let Op::CmpArrayEmptyJmp { op_index, .. } = &mut ops[op_index_begin] {
+        *op_index = op_index_end;
+    } else {
+        panic!("Expected CmpArrayEmptyJmp, found {:#?}", &ops[op_index_begin]);
+    }
+
+    // jmp
+    ops.push(Op::CmpCounterLessJmp { name, value: end, op_index: op_index_begin });
+
+    Ok(())
 }
 
-pub fn dump_ir(w: &mut impl Write, ir: &Vec<Op>) -> std::io::Result<()> {
+/// Distinct kinds of ops used by the program, so a host can reject e.g. templates with loops
+pub fn op_kinds(ir: &[Op]) -> BTreeSet<&'static str> {
+    ir.iter().map(Op::kind).collect()
+}
+
+pub fn dump_ir(w: &mut impl Write, ir: &[Op]) -> std::io::Result<()> {
     write!(w, "IR:\n")?;
     for i in 0..ir.len() {
         write!(w, "{}: {}\n", i, &ir[i])?;
     }
     Ok(())
 }
+
+/// Returns the output of `dump_ir` as a string, e.g. for logging
+pub fn disassemble(ir: &[Op]) -> String {
+    let mut out = Vec::new();
+
+    dump_ir(&mut out, ir).expect("Writing to a Vec never fails");
+    // ops are displayed from strings only
+    String::from_utf8(out).expect("Display of ops is valid UTF-8")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use crate::ir::{gen_ir, gen_ir_with_config, compile_span, compile_span_with_options, compress_noops, disassemble, dump_ir, op_kinds, GenIrConfig, Op, Type, is_name_array};
+    use crate::syntax::{ast, CompileOptions, TokenType, DEFAULT_OPTIONS};
+    use crate::vm::Vm;
+    use crate::analysis::{OptOptions, FULL_OPT, NO_OPT};
+    use crate::error::ErrorReason;
+    use crate::utils::{VARS, ARRAYS};
+
+    fn run(ir: &[Op]) -> String {
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.run(&mut out, ir).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn debug_comments() {
+        let code = "{{ first | \"<p>$(_)</p>\" }}{{ ARGS[:] | \"$(_item_)\" }}";
+        let opt = OptOptions { debug_comments: true, ..FULL_OPT };
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let ir_comments = gen_ir(code, &ast(code).unwrap(), opt).unwrap();
+
+        let comments: Vec<&Op> = ir_comments.iter().filter(|op| matches!(op, Op::Comment { .. })).collect();
+
+        assert_eq!(comments, [
+            &Op::Comment { text: "pipe of first".into() },
+            &Op::Comment { text: "array loop for ARGS".into() },
+        ]);
+        assert!(!ir.iter().any(|op| matches!(op, Op::Comment { .. })));
+        assert_eq!(run(&ir), run(&ir_comments));
+    }
+
+    #[test]
+    fn custom_reserved_names() {
+        let config = GenIrConfig { is_reserved: |name| name.starts_with("loc"), is_array: is_name_array };
+        let code = "{{ first | \"$(_)\" }}{{ locname }}";
+        let nodes = ast(code).unwrap();
+
+        assert!(gen_ir(code, &nodes, NO_OPT).is_ok());
+        assert_eq!(
+            gen_ir_with_config(code, &nodes, NO_OPT, &config).unwrap_err().reason,
+            ErrorReason::UndefinedVar { name: "locname".into() }
+        );
+    }
+
+    #[test]
+    fn custom_array_names() {
+        let config = GenIrConfig { is_array: |name| name == "ARGS" || name == "first", ..Default::default() };
+        let error = |code: &str| gen_ir_with_config(code, &ast(code).unwrap(), NO_OPT, &config).unwrap_err().reason;
+
+        let code = "{{ ARGS[:] | \"$(_item_)\" }}";
+        assert!(gen_ir_with_config(code, &ast(code).unwrap(), NO_OPT, &config).is_ok());
+        assert_eq!(error("{{ PHONES[:] | \"$(_item_)\" }}"), ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+        assert_eq!(error("{{ ARGS + PHONES[:] | \"$(_item_)\" }}"), ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+        assert_eq!(error("{{ zip(ARGS, PHONES) | \"$(_a_)\" }}"), ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+        assert_eq!(error("{{ first }}"), ErrorReason::ArrayNotPiped);
+    }
+
+    #[test]
+    fn compress_noops_moves_jumps() {
+        let code = "{{ unless on }}{{ ARGS[1:] | \"$(_index_):$(_item_) \" }}{{ end }}{{ PHONES[:2] | \"$(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        // every op is followed by a Noop, so op i moves to 2i and the jumps continue at 2(i + 1)
+        let with_noops: Vec<Op> = ir.iter().cloned().flat_map(|mut op| {
+            match &mut op {
+                Op::CmpCounterLessJmp { op_index, .. } | Op::CmpArrayEmptyJmp { op_index, .. } | Op::CmpBoolJmp { op_index, .. } => {
+                    *op_index = *op_index * 2 + 1;
+                },
+                _ => {},
+            }
+
+            [op, Op::Noop]
+        }).collect();
+
+        assert_eq!(run(&with_noops), run(&ir));
+
+        let compressed = compress_noops(with_noops);
+
+        assert!(!compressed.contains(&Op::Noop));
+        assert_eq!(compressed, ir);
+        assert_eq!(run(&compressed), "1:second element 2:third element 555-123-4567555-987-6543");
+
+        // the jump continues at 1, after a Noop only
+        let ir = vec![Op::Noop, Op::CmpBoolJmp { op_index: 0, value: true, name: "on".into() }, Op::Flush];
+        assert_eq!(compress_noops(ir.clone()), ir);
+    }
+
+    #[test]
+    fn destroy_scope_only_if_dirty() {
+        let has_destroy = |code: &str, opt| gen_ir(code, &ast(code).unwrap(), opt).unwrap().contains(&Op::DestroyScope);
+
+        assert!(!has_destroy("{{ ARGS[:] | \"-\" }}", FULL_OPT));
+        assert!(has_destroy("{{ ARGS[:] | \"-\" }}", NO_OPT));
+        assert!(has_destroy("{{ ARGS[:] | \"$(_index_)\" }}", FULL_OPT));
+        assert!(has_destroy("{{ ARGS[:] | \"-\" | truncate(1) || \"$(_)\" }}", FULL_OPT));
+    }
+
+    #[test]
+    fn all_features_op_kinds() {
+        let code = include_str!("../examples/all_features.pipa");
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+
+        assert_eq!(op_kinds(&ir), BTreeSet::from([
+            "CmpArrayEmptyJmp", "CmpCounterLessJmp", "Collapse", "DestroyScope", "Flush", "IncCounter",
+            "LoadArrayItem", "LoadCounter", "PutName", "PutScopeVar", "PutStr", "SetCounter",
+        ]));
+        assert_eq!(op_kinds(&[]), BTreeSet::new());
+    }
+
+    #[test]
+    fn cloned_program_shares_strings() {
+        let code = "<p>{{ ARGS[:] | \"<b>$(_item_)</b>\" }}</p>";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let shared = Arc::new(ir.clone());
+
+        for (op, copy) in ir.iter().zip(shared.iter()) {
+            match (op, copy) {
+                (Op::PutStr { value }, Op::PutStr { value: copy }) => assert!(Arc::ptr_eq(value, copy)),
+                (Op::PutName { name, .. }, Op::PutName { name: copy, .. }) => assert!(Arc::ptr_eq(name, copy)),
+                _ => assert_eq!(op, copy),
+            }
+        }
+
+        let threads: Vec<_> = (0..2).map(|_| {
+            let ir = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                let mut out = Vec::new();
+                Vm::new(&VARS, &ARRAYS).run(&mut out, &ir).unwrap();
+                String::from_utf8(out).unwrap()
+            })
+        }).collect();
+
+        for t in threads {
+            assert_eq!(t.join().unwrap(), "<p><b>first element</b><b>second element</b><b>third element</b></p>");
+        }
+    }
+
+    #[test]
+    fn span() {
+        let code = "<h1>{{ first }}</h1>\n<ul>{{ ARGS[:] | \"<li>$(_item_)</li>\" }}</ul>\n{{ second }}";
+        let start = code.find("{{ ARGS").unwrap();
+        let end = code.find("</ul>").unwrap();
+        let render = |ir: &[Op]| {
+            let mut out = Vec::new();
+            Vm::new(&VARS, &ARRAYS).run(&mut out, ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let ir = compile_span(code, start, end, FULL_OPT).unwrap();
+        assert_eq!(render(&ir), "<li>first element</li><li>second element</li><li>third element</li>");
+        assert_eq!(render(&compile_span(code, 0, code.find("\n").unwrap(), NO_OPT).unwrap()), "<h1>first arg</h1>");
+        assert_eq!(compile_span(code, 0, code.len(), FULL_OPT), gen_ir(code, &ast(code).unwrap(), FULL_OPT));
+
+        // the span splits a code block
+        let err = compile_span(code, start + 3, end, FULL_OPT).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+        let err = compile_span(code, start, end - 3, FULL_OPT).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+        assert!(compile_span(code, start, code.len() + 1, FULL_OPT).is_err());
+
+        // errors outside of the span don't matter
+        let code = "{{ fir:st }} \\{{ {{ \"}\" }}{{ first }}{{ second";
+        let start = code.find("{{ first").unwrap();
+        assert_eq!(render(&compile_span(code, start, start + 11, FULL_OPT).unwrap()), "first arg");
+        let err = compile_span(code, start, code.len() - 3, FULL_OPT).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+
+        // positions are in the whole code
+        let code = "<h1>{{ first }}</h1>{{ first | \"$(_item_)\" }}";
+        let err = compile_span(code, 20, code.len(), FULL_OPT).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::UndefinedVar { name: "_item_".into() });
+        assert_eq!(&code[err.first_char..err.first_char + 6], "_item_");
+
+        // custom delimiters, braces are a literal then
+        let options = CompileOptions { open: '<', close: '>', ..DEFAULT_OPTIONS };
+        let code = "fn f() {{ }}\n<< first >> << second >>";
+        let start = code.find("<< first").unwrap();
+        let ir = compile_span_with_options(code, start, start + 11, FULL_OPT, &options).unwrap();
+        assert_eq!(render(&ir), "first arg");
+        let ir = compile_span_with_options(code, 0, start, FULL_OPT, &options).unwrap();
+        assert_eq!(render(&ir), "fn f() {{ }}\n");
+        let err = compile_span_with_options(code, start + 3, code.len(), FULL_OPT, &options).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+        let inside_braces = code.find(" }}").unwrap();
+        assert!(compile_span_with_options(code, 0, inside_braces, FULL_OPT, &options).is_ok());
+        assert!(compile_span(code, 0, inside_braces, FULL_OPT).is_err());
+    }
+
+    #[test]
+    fn disassemble_matches_dump() {
+        let code = "<p>{{ first }}</p>{{ ARGS[:] | \"$(_index_) $(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let mut out = Vec::new();
+
+        dump_ir(&mut out, &ir).unwrap();
+
+        assert_eq!(disassemble(&ir), String::from_utf8(out).unwrap());
+        assert!(disassemble(&ir).starts_with("IR:\n0: PutStr\n"));
+        assert_eq!(disassemble(&[]), "IR:\n");
+    }
+}
@@ -5,3 +5,6 @@ pub mod error;
 pub mod analysis;
 pub mod utils;
 pub mod args;
+pub mod template;
+pub mod visit;
+pub mod filter;
@@ -2,11 +2,12 @@ use unicode_segmentation::{UnicodeSegmentation, Graphemes};
 use std::iter::{Enumerate, Peekable};
 use std::collections::HashMap;
 use std::fmt;
-use crate::error::CompileError;
+use crate::error::{CompileError, ErrorReason, LexError};
 use crate::ir::{is_name_array, Type};
+use crate::filter::{Filter, FilterArg};
 
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TokenType {
     Int,
     Name,
@@ -28,6 +29,23 @@ pub enum TokenType {
     MacroDef,
     MacroExp,
     Pipe,
+    FanOut,
+    Keyword,
+    Args,
+}
+
+/// Words that are keywords where a statement starts, elsewhere they are names,
+/// so a var named like a keyword added later keeps working
+pub const KEYWORDS: &[&str] = &["assert", "if", "unless", "end", "version", "chars", "stop", "indent", "endindent"];
+
+/// Largest width accepted by `indent N` and `#padN`, wider ones are rejected at compile time
+pub const MAX_WIDTH: u64 = 1024;
+
+/// Version of pipa checked by the `version` directive
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name)
 }
 
 impl Into<TokenType> for &str {
@@ -53,6 +71,12 @@ impl Into<TokenType> for &str {
     }
 }
 
+impl TokenType {
+    pub fn is_whitespace(&self) -> bool {
+        matches!(self, TokenType::Space | TokenType::NewLine)
+    }
+}
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -66,6 +90,7 @@ impl fmt::Display for TokenType {
             Self::Quote => "'\"'",
             Self::Range => "Range",
             Self::Pipe => "'|'",
+            Self::FanOut => "'||'",
             Self::RangeBegin => "'['",
             Self::RangeSep => "':'",
             Self::RangeEnd => "']'",
@@ -76,6 +101,8 @@ impl fmt::Display for TokenType {
             Self::String => "String",
             Self::ExprBegin => "'('",
             Self::ExprEnd => "')'",
+            Self::Keyword => "Keyword",
+            Self::Args => "Args",
         };
 
         write!(f, "{}", s)
@@ -102,6 +129,57 @@ impl Token {
         &code[self.first_char..self.end_char]
     }
 
+    /// Compares token types ignoring positions
+    pub fn same_type(&self, other: &Token) -> bool {
+        self.token_type == other.token_type
+    }
+
+    pub fn matches_type(&self, t: TokenType) -> bool {
+        self.token_type == t
+    }
+
+    /// Same as `matches_type`, reads better in conditions
+    pub fn is_a(&self, t: TokenType) -> bool {
+        self.matches_type(t)
+    }
+
+    pub fn is_whitespace(&self) -> bool {
+        self.token_type.is_whitespace()
+    }
+
+    pub fn is_code_delimiter(&self) -> bool {
+        matches!(self.token_type, TokenType::CodeBegin | TokenType::CodeEnd)
+    }
+
+    /// Tokens that can start an expression as a value
+    pub fn is_value(&self) -> bool {
+        matches!(self.token_type, TokenType::Name | TokenType::Int | TokenType::String | TokenType::Literal)
+    }
+
+    pub fn display<'a>(&self, code: &'a str) -> TokenDisplay<'a> {
+        TokenDisplay {
+            token: *self,
+            code,
+        }
+    }
+
+}
+
+/// Displays a token with its source slice, e.g. `Name("fname")` or `Int(42)`.
+pub struct TokenDisplay<'a> {
+    token: Token,
+    code: &'a str,
+}
+
+impl fmt::Display for TokenDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = self.token.as_str(self.code);
+
+        match self.token.token_type {
+            TokenType::Int => write!(f, "{:?}({})", self.token.token_type, s),
+            t => write!(f, "{:?}({:?})", t, s),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -110,17 +188,59 @@ pub enum InnerNode {
         children: Vec<Node>
     },
     Int {
-        value: usize,
+        value: u64,
     },
     Array {
         name: String,
-        start: Option<usize>,
-        end: Option<usize>,
+        start: Option<u64>,
+        end: Option<u64>,
+        pad: Option<u64>,
     },
     Literal,
     Name {
-        start: Option<usize>,
-        end: Option<usize>,
+        start: Option<u64>,
+        end: Option<u64>,
+    },
+    Assert {
+        name: String,
+        expected: String,
+    },
+    /// Ends rendering, the output before it is kept
+    Stop,
+    Filter {
+        filter: Filter,
+    },
+    /// `if`/`unless` block, the body is rendered when the bool var equals `!negate`
+    Condition {
+        name: String,
+        negate: bool,
+        body: Vec<Node>,
+    },
+    /// `indent`/`endindent` block, every line of the body's output is prefixed with `width` spaces
+    Indent {
+        width: u64,
+        body: Vec<Node>,
+    },
+    /// `|| a || b` pipe, every branch gets the same value and the results are concatenated
+    FanOut {
+        branches: Vec<Node>,
+    },
+    /// `A + B` loop over the concatenation of arrays
+    ArrayConcat {
+        names: Vec<String>,
+        start: Option<u64>,
+        end: Option<u64>,
+        pad: Option<u64>,
+    },
+    Zip {
+        a: String,
+        b: String,
+    },
+    /// `chars` loop over the graphemes of a string var, piped like an array
+    Chars {
+        name: String,
+        start: Option<u64>,
+        end: Option<u64>,
     },
 }
 
@@ -131,6 +251,9 @@ pub struct Node {
     pub end_char: usize,
     pub inner: Box<InnerNode>,
     pub children: Vec<Node>,
+    /// Span of the `?name` token if the node comes from an expanded macro,
+    /// positions of the node itself point into the definition
+    pub expanded_at: Option<(usize, usize)>,
 }
 
 impl Node {
@@ -140,7 +263,40 @@ impl Node {
             end_char,
             inner: Box::new(inner),
             children,
+            expanded_at: None,
+        }
+    }
+
+    /// Nodes inside this one: parts of a string, branches of a fan-out or the body of a block.
+    /// Empty for other nodes. Pipe targets are in the `children` field.
+    pub fn children(&self) -> &[Node] {
+        match &*self.inner {
+            InnerNode::String { children } => children,
+            InnerNode::FanOut { branches } => branches,
+            InnerNode::Condition { body, .. } | InnerNode::Indent { body, .. } => body,
+            _ => &[],
+        }
+    }
+
+    // a copy of the macro body marked with the expansion site, including the nodes inside it
+    fn expand(&self, site: &Token) -> Node {
+        fn mark(node: &mut Node, site: (usize, usize)) {
+            node.expanded_at = Some(site);
+
+            match &mut *node.inner {
+                InnerNode::String { children } => children.iter_mut().for_each(|n| mark(n, site)),
+                InnerNode::FanOut { branches } => branches.iter_mut().for_each(|n| mark(n, site)),
+                InnerNode::Condition { body, .. } | InnerNode::Indent { body, .. } => body.iter_mut().for_each(|n| mark(n, site)),
+                _ => {},
+            }
+
+            node.children.iter_mut().for_each(|n| mark(n, site));
         }
+
+        let mut node = self.clone();
+        mark(&mut node, (site.first_char, site.end_char));
+
+        node
     }
 
     pub fn as_str<'a>(&self, code: &'a str) -> &'a str {
@@ -154,6 +310,7 @@ impl Node {
             match (is_escaping, t) {
                 (true, "n") => "\n",
                 (true, "t") => "\t",
+                (true, "\n") | (true, "\r\n") => "",
                 (_, &_) => t,
             }
         }).collect()
@@ -215,11 +372,88 @@ impl<'a> Iterator for EscapeIter<'a> {
     }
 }
 
-fn lex(code: &str) -> Result<Vec<Token>, CompileError> {
+/// Delimiters of code blocks. Each one is doubled, so the default is `{{` and `}}`.
+/// They must differ from each other and from the symbols used in code.
+///
+/// Loops and macros can be disabled for templates from untrusted users,
+/// then they are rejected with `FeatureDisabled`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CompileOptions {
+    pub open: char,
+    pub close: char,
+    /// loops over arrays, `zip` and `chars`
+    pub allow_loops: bool,
+    /// definitions, expansions and imports of macros
+    pub allow_macros: bool,
+}
+
+pub const DEFAULT_OPTIONS: CompileOptions = CompileOptions { open: '{', close: '}', allow_loops: true, allow_macros: true };
+
+#[deprecated(note = "use DEFAULT_OPTIONS, the options are not only delimiters anymore")]
+pub const DEFAULT_DELIMITERS: CompileOptions = DEFAULT_OPTIONS;
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        DEFAULT_OPTIONS
+    }
+}
+
+pub fn lex(code: &str) -> Result<Vec<Token>, CompileError> {
+    lex_with_options(code, &DEFAULT_OPTIONS)
+}
+
+/// Offsets of all tokens of the type, e.g. to find every macro expansion
+pub fn all_token_positions(code: &str, token_type: TokenType) -> Result<Vec<usize>, CompileError> {
+    Ok(lex(code)?.iter().filter(|t| t.is_a(token_type)).map(|t| t.first_char).collect())
+}
+
+/// Validates UTF-8 and lexes the bytes. A byte order mark at the start is skipped,
+/// positions of tokens and errors are still offsets in `data`.
+pub fn lex_bytes(data: &[u8]) -> Result<Vec<Token>, LexError> {
+    const BOM: &str = "\u{feff}";
+
+    let code = std::str::from_utf8(data)?;
+    let (offset, code) = match code.strip_prefix(BOM) {
+        Some(code) => (BOM.len(), code),
+        None => (0, code),
+    };
+
+    let mut tokens = lex(code).map_err(|mut e| {
+        e.first_char += offset;
+        e
+    })?;
+
+    for t in &mut tokens {
+        t.first_char += offset;
+        t.end_char += offset;
+    }
+
+    Ok(tokens)
+}
+
+// symbols that have a meaning inside code blocks, they can't be delimiters
+const CODE_SYMBOLS: &str = "@?\"#|[]():,+$\\";
+
+fn check_delimiters(options: &CompileOptions) -> Result<(), CompileError> {
+    let is_code = |c: char| c.is_alphanumeric() || c.is_whitespace() || c == '_' || CODE_SYMBOLS.contains(c);
+
+    if options.open == options.close || is_code(options.open) || is_code(options.close) {
+        return Err(CompileError::new_invalid_delimiters(options.open, options.close));
+    }
+
+    Ok(())
+}
+
+pub fn lex_with_options(code: &str, options: &CompileOptions) -> Result<Vec<Token>, CompileError> {
+    check_delimiters(options)?;
     let mut tokens = vec![];
     let mut literal_begin = 0;
     let mut literal_end = literal_begin;
     let mut iter = EscapeIter::new(code, 0, &[TokenType::CodeBegin, TokenType::CodeEnd]);
+    let open = options.open.to_string();
+    let close = options.close.to_string();
+    // if/unless/indent blocks that aren't closed yet, `end` is only a keyword inside of one
+    let mut open_blocks = 0;
 
     if code.len() == 0 {
         return Ok(tokens);
@@ -228,23 +462,23 @@ fn lex(code: &str) -> Result<Vec<Token>, CompileError> {
     while let Some((is_escaping, i, t)) = iter.next() {
         
         match (is_escaping, t) {
-            (false, "{") => {
+            (false, t) if t == open => {
                 // push literal
                 literal_end = i;
                 if i != 0 {
                     tokens.push(Token::new(literal_begin, literal_end, TokenType::Literal));
                 } 
                 // process code block
-                expect_symbol(&mut iter, &[TokenType::CodeBegin], false)?;
-                let begin = i + 2;
+                expect_delimiter(&mut iter, &open, TokenType::CodeBegin)?;
+                let begin = i + 2 * open.len();
                 let mut end = 0;
                 // find code end
                 while let Some((is_escaping, ni, t)) = iter.next() {
                     match (is_escaping, t) {
-                        (false, "{") => {
+                        (false, t) if t == open => {
                             return Err(CompileError::new_syntax(ni, &[TokenType::CodeEnd]));
                         },
-                        (false, "}") => {
+                        (false, t) if t == close => {
                             end = ni;
                             break;
                         },
@@ -258,7 +492,7 @@ fn lex(code: &str) -> Result<Vec<Token>, CompileError> {
                 // brake diagnostics message.
                 //
                 // For code level this isn't required and thus not happening  
-                if let Err(err) = expect_symbol(&mut iter, &[TokenType::CodeEnd], false) {
+                if let Err(err) = expect_delimiter(&mut iter, &close, TokenType::CodeEnd) {
 
                     if err.first_char == 0 {
                         return Err(CompileError::new_syntax(end, &[TokenType::CodeEnd]));
@@ -267,32 +501,110 @@ fn lex(code: &str) -> Result<Vec<Token>, CompileError> {
                     return Err(err);
                 }
                 // set new literal boundary
-                literal_begin = end + 2; // len("}}") == 2
+                literal_begin = end + 2 * close.len();
                 // push code block
+                let first_token = tokens.len();
                 lex_code(begin, &code[begin..end], &mut tokens)?;
+                mark_keywords(&mut tokens[first_token..], code, &mut open_blocks);
             },
-            (false, "}") => {
+            (false, t) if t == close => {
                 return Err(CompileError::new_syntax(i, &[TokenType::CodeBegin]));
             }
             (true, &_) => {
-                if t != "{" && t != "}" {
+                // escaped new line joins the lines of a literal
+                if t != open && t != close && t != "\n" && t != "\r\n" {
                     return Err(CompileError::new_syntax(i, &[TokenType::CodeBegin, TokenType::CodeEnd]));
                 }
-                literal_end = i;
+                literal_end = i + t.len();
             }
             (_, &_) => {
-                literal_end = i;
+                literal_end = i + t.len();
             }
         }
     }
     // push last literal token
-    if literal_begin < literal_end + 1 {
-        tokens.push(Token::new(literal_begin, literal_end + 1, TokenType::Literal));
+    if literal_begin < literal_end {
+        tokens.push(Token::new(literal_begin, literal_end, TokenType::Literal));
     }
 
     Ok(tokens)
 }
 
+/// Spans of code blocks including the delimiters of `options`, found without lexing their content,
+/// so an error in one block doesn't hide the others. An unclosed block spans to the end of the code.
+pub fn code_block_spans(code: &str, options: &CompileOptions) -> Vec<(usize, usize)> {
+    let open = options.open.to_string();
+    let close = options.close.to_string();
+    let mut spans = vec![];
+    let mut begin = None;
+    let mut iter = EscapeIter::new(code, 0, &[TokenType::CodeBegin, TokenType::CodeEnd]).peekable();
+
+    while let Some((is_escaping, i, t)) = iter.next() {
+        let doubled = !is_escaping && iter.next_if(|(is_escaping, _, next)| !is_escaping && *next == t).is_some();
+
+        match begin {
+            None if doubled && t == open => begin = Some(i),
+            Some(first) if doubled && t == close => {
+                spans.push((first, i + 2 * close.len()));
+                begin = None;
+            },
+            _ => {},
+        }
+    }
+
+    if let Some(first) = begin {
+        spans.push((first, code.len()));
+    }
+
+    spans
+}
+
+// like expect_symbol, but for delimiters that don't have their own token type
+fn expect_delimiter<'a>(iter: &mut impl Iterator<Item = (bool, usize, &'a str)>, delimiter: &str, token_type: TokenType) -> Result<(), CompileError> {
+    match iter.next() {
+        Some((true, i, t)) if t == delimiter => Err(CompileError::new_syntax(i - 1, &[token_type])),
+        Some((false, _, t)) if t == delimiter => Ok(()),
+        Some((_, i, _)) => Err(CompileError::new_syntax(i, &[token_type])),
+        None => Err(CompileError::new_syntax(0, &[token_type])),
+    }
+}
+
+// marks the names of a code block that are keywords: a keyword starts a statement and isn't used as a value,
+// e.g. `{{ version }}` prints the var, because the directive needs an argument, and `{{ end }}` outside of a block too
+fn mark_keywords(tokens: &mut [Token], code: &str, open_blocks: &mut usize) {
+    // the previous token needs an operand, so this one can't start a statement
+    let mut is_operand = false;
+
+    for i in 0..tokens.len() {
+        let t = tokens[i];
+        let next = tokens.get(i + 1).map(|n| n.token_type);
+        // a word followed by these is used as a value
+        let is_value = matches!(next, Some(TokenType::Range | TokenType::Args | TokenType::Pipe | TokenType::FanOut));
+        let marked = !is_operand && !is_value && t.is_a(TokenType::Name) && match t.as_str(code) {
+            "assert" | "if" | "unless" | "chars" | "version" | "indent" => next.is_some_and(|n| n != TokenType::NewLine),
+            "end" | "endindent" => *open_blocks > 0,
+            "stop" => true,
+            _ => false,
+        };
+
+        if marked {
+            tokens[i].token_type = TokenType::Keyword;
+            match t.as_str(code) {
+                "if" | "unless" | "indent" => *open_blocks += 1,
+                "end" | "endindent" => *open_blocks -= 1,
+                _ => {},
+            }
+        }
+
+        is_operand = match t.token_type {
+            TokenType::Pipe | TokenType::FanOut | TokenType::MacroDef => true,
+            TokenType::Name => t.as_str(code) == "+" || marked && !matches!(t.as_str(code), "end" | "endindent" | "stop"),
+            TokenType::NewLine => is_operand,
+            _ => false,
+        };
+    }
+}
+
 fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<(), CompileError> {
     let mut iter = EscapeIter::new(code, first_char, &[TokenType::Quote, TokenType::FormatSymbol]);
 
@@ -345,6 +657,10 @@ fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<()
                     tokens.push(Token::new(i, i + 1, TokenType::NewLine));
                 }
             },
+            (false, "|") if code[i - first_char..].starts_with("||") => {
+                iter.next();
+                tokens.push(Token::new(i, i + 2, TokenType::FanOut));
+            },
             (false, "|") => {
                 tokens.push(Token::new(i, i + 1, TokenType::Pipe));
             },
@@ -358,9 +674,8 @@ fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<()
             }
             (false, " ") | (false, "\t")  => {},
             (false, &_) => {
-                let end = find_boundary(i, &mut iter, &[TokenType::Literal, TokenType::Int], &[TokenType::Space, TokenType::NewLine, TokenType::RangeBegin])?;
-                tokens.push(Token::new(i, end, TokenType::Name));
-
+                let end = find_boundary(i, &mut iter, &[TokenType::Literal, TokenType::Int], &[TokenType::Space, TokenType::NewLine, TokenType::RangeBegin, TokenType::ExprBegin])
+                    .map_err(|err| delimiter_in_name(err, code, first_char))?;
                 let token = code.get(i - first_char..end - first_char);
 
                 match token {
@@ -368,6 +683,9 @@ fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<()
                         if !t.is_ascii() {
                             return Err(CompileError::new_name(i));
                         }
+
+                        // keywords are marked by mark_keywords when the whole block is lexed
+                        tokens.push(Token::new(i, end, TokenType::Name));
                     },
                     None => return Err(CompileError::new_name(i))
                 }
@@ -376,6 +694,10 @@ fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<()
                     let nend = find_symbol(&mut iter, &[TokenType::RangeEnd])?;
                     tokens.push(Token::new(end, nend + 1, TokenType::Range));
                 }
+                else if code[end - first_char..].starts_with('(') {
+                    let nend = find_args_end(end, &mut iter)?;
+                    tokens.push(Token::new(end, nend + 1, TokenType::Args));
+                }
             },
             (true, &_) => {
                 // forbid escaping
@@ -387,6 +709,25 @@ fn lex_code(first_char: usize, code: &str, tokens: &mut Vec<Token>) -> Result<()
     Ok(())
 }
 
+// finds the parenthesis closing filter arguments, skipping the ones in strings
+fn find_args_end<'a>(first_char: usize, iter: &mut impl Iterator<Item = (bool, usize, &'a str)>) -> Result<usize, CompileError> {
+    let mut c = first_char;
+    let mut in_string = false;
+
+    for (is_escaping, i, t) in iter {
+        c = i;
+
+        match (is_escaping, t) {
+            (false, "\"") => in_string = !in_string,
+            (false, ")") if !in_string => return Ok(i),
+            (_, "\n") | (_, "\r\n") => break,
+            (_, &_) => {},
+        }
+    }
+
+    Err(CompileError::new_syntax(c, &[TokenType::ExprEnd]))
+}
+
 fn expect_symbol<'a>(iter: &mut impl Iterator<Item = (bool, usize, &'a str)>, expected: &[TokenType], ignore_whitespace: bool) -> Result<&'a str, CompileError>
 {
     while let Some((is_escaping, i, t)) = iter.next() {
@@ -452,16 +793,41 @@ fn find_boundary<'a>(first_char: usize, iter: &mut impl Iterator<Item = (bool, u
     Ok(c + 1)
 }
 
-fn parse_int(t: Token, code: &str) -> Node {
-    let value = t.as_str(code).parse::<usize>().unwrap();
-    Node::new(t.first_char, t.end_char, InnerNode::Int { value }, vec![])
+// names never contain the range separator, so a name like a:b is reported as a name error
+// instead of a missing terminator, a stray ']' is still a mismatched brace.
+// `offset` is the position of `code` in the source
+fn delimiter_in_name(err: CompileError, code: &str, offset: usize) -> CompileError {
+    match code.get(err.first_char - offset..) {
+        Some(rest) if rest.starts_with(':') => CompileError::new_name(err.first_char),
+        _ => err,
+    }
+}
+
+fn parse_int(t: Token, code: &str) -> Result<Node, CompileError> {
+    let value = t.as_str(code).parse::<u64>().map_err(|_| CompileError::new_int_overflow(t.first_char))?;
+    Ok(Node::new(t.first_char, t.end_char, InnerNode::Int { value }, vec![]))
+}
+
+/// Consumes the name after `$` written without parentheses, returns its end.
+/// The name ends at the first grapheme that can't be a part of it.
+fn parse_short_name<'a>(iter: &mut Peekable<impl Iterator<Item = (bool, usize, &'a str)>>) -> Option<usize> {
+    let is_name_char = |(is_escaping, _, t): &(bool, usize, &str)| {
+        !is_escaping && t.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let mut end = None;
+
+    while let Some((_, i, t)) = iter.next_if(is_name_char) {
+        end = Some(i + t.len());
+    }
+
+    end
 }
 
 fn parse_string(first_char: usize, end_char: usize, string: &str, code: &str) -> Result<Node, CompileError> {
     let mut first_literal = first_char + 1;
     let mut end_literal = 0;
     let mut nodes = vec![];
-    let mut iter = EscapeIter::new(string, first_char, &[TokenType::Quote, TokenType::FormatSymbol, TokenType::ExprBegin, TokenType::ExprEnd]);
+    let mut iter = EscapeIter::new(string, first_char, &[TokenType::Quote, TokenType::FormatSymbol, TokenType::ExprBegin, TokenType::ExprEnd]).peekable();
 
     while let Some((is_escaping, i, t)) = iter.next() {
         match (is_escaping, t) {
@@ -469,9 +835,21 @@ fn parse_string(first_char: usize, end_char: usize, string: &str, code: &str) ->
                 if i - first_literal != 0 {
                     nodes.push(Node::new(first_literal, i, InnerNode::Literal, vec![]));
                 }
+                // shorthand without parentheses, e.g. $_item_
+                if let Some(end) = parse_short_name(&mut iter) {
+                    let node = Node::new(i + 1, end, InnerNode::Name{ start: None, end: None }, vec![]);
+                    if is_name_array(node.as_str(code)) {
+                        return Err(CompileError::new_name(node.first_char));
+                    }
+                    nodes.push(node);
+                    first_literal = end;
+                    end_literal = end;
+                    continue;
+                }
                 expect_symbol(&mut iter, &[TokenType::ExprBegin], false)?;
                 expect_symbol(&mut iter, &[TokenType::Literal], false)?;
-                first_literal = find_boundary(i, &mut iter, &[TokenType::Literal, TokenType::Int], &[TokenType::ExprEnd])?;
+                first_literal = find_boundary(i, &mut iter, &[TokenType::Literal, TokenType::Int], &[TokenType::ExprEnd])
+                    .map_err(|err| delimiter_in_name(err, code, 0))?;
                 let node = Node::new(i + 2, first_literal, InnerNode::Name{ start: None, end: None }, vec![]);
                 // check if name is correct
                 if is_name_array(node.as_str(code)) {
@@ -510,213 +888,1448 @@ fn parse_array(parent: Node, child: Token, code: &str) -> Result<Node, CompileEr
         Ok(_) => {
             separator = find_boundary(0, &mut iter, &[TokenType::Int], &[TokenType::RangeSep])?;
             let token = &range[1..separator - child.first_char];
-            start = Some(token.parse::<usize>().unwrap());
+            start = Some(token.parse::<u64>().map_err(|_| CompileError::new_int_overflow(child.first_char + 1))?);
         },
         Err(_) => {
+            if !range[1..].starts_with(':') {
+                return Err(CompileError::new_syntax(child.first_char + 1, &[TokenType::Int, TokenType::RangeSep]));
+            }
             separator += child.first_char + 1;
         }
     }
 
-    if let Ok(_) = expect_symbol(&mut iter, &[TokenType::Int], false) {
-        let boundary = find_boundary(0, &mut iter, &[TokenType::Int], &[TokenType::RangeEnd])?;
-        let token = &range[separator - child.first_char + 1..boundary - child.first_char];
-        end = Some(token.parse::<usize>().unwrap());
+    match expect_symbol(&mut iter, &[TokenType::Int], false) {
+        Ok(_) => {
+            let boundary = find_boundary(0, &mut iter, &[TokenType::Int], &[TokenType::RangeEnd])?;
+            let token = &range[separator - child.first_char + 1..boundary - child.first_char];
+            end = Some(token.parse::<u64>().map_err(|_| CompileError::new_int_overflow(separator + 1))?);
+        },
+        // bounds are always integers, e.g. NAME[1:b] is not a range up to the end
+        Err(_) => {
+            if &range[separator - child.first_char + 1..] != "]" {
+                return Err(CompileError::new_syntax(separator + 1, &[TokenType::Int, TokenType::RangeEnd]));
+            }
+        }
     }
 
-    let name: String = parent.as_str(code).into();
-    let n = match is_name_array(name.as_ref()) { 
-        true => Node::new(parent.first_char, parent.end_char, InnerNode::Array { name, start, end }, vec![]),
-        false => Node::new(parent.first_char, parent.end_char, InnerNode::Name { start, end }, vec![]),
+    let n = match parse_annotation(&parent, code)? {
+        Some((name, pad)) => Node::new(parent.first_char, parent.end_char, InnerNode::Array { name: name.into(), start, end, pad }, vec![]),
+        None => Node::new(parent.first_char, parent.end_char, InnerNode::Name { start, end }, vec![]),
     };
 
     Ok(n)
 }
 
-fn parse_expr<'a>(macro_table: &HashMap<Box<str>, Node>, mut parent: Node, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
-    let mut tail = &mut parent;
-
-    loop {
+// array loops can be annotated after the name, e.g. ARR#pad3 left-pads indices to 3 digits
+// returns None if the name is not an array
+fn parse_annotation<'a>(parent: &Node, code: &'a str) -> Result<Option<(&'a str, Option<u64>)>, CompileError> {
+    let name = parent.as_str(code);
+    let (name, annotation) = match name.split_once('#') {
+        Some((name, annotation)) => (name, Some(annotation)),
+        None => (name, None),
+    };
 
-        match iter.next() {
-            Some(t) => {
-                match t.token_type {
-                    TokenType::Literal => {
-                        return Err(CompileError::new_pipe_no_children(tail.first_char))
-                    },
-                    TokenType::Int => {
-                        return Err(CompileError::new_type_error(t.first_char, Type::String, Type::Int));
-                    },
-                    TokenType::Name => {
-                        return Err(CompileError::new_type_error(t.first_char, Type::String, Type::Name));
-                    }
-                    TokenType::Pipe => {
-                        return Err(CompileError::new_syntax(tail.first_char, &[TokenType::String]))
-                    },
-                    TokenType::String => {
-                        let s = parse_string(t.first_char, t.end_char, t.as_str(code), code)?;
+    if !is_name_array(name) {
+        return Ok(None);
+    }
 
-                        tail.children.push(s);
-                        tail = &mut tail.children[0];
-                    },
-                    TokenType::Range => {
-                        return Err(CompileError::new_syntax(tail.first_char, &[TokenType::String]))
-                    },
-                    TokenType::MacroDef => {
-                        return Err(CompileError::new_nested_macro(tail.first_char))
-                    },
-                    TokenType::MacroExp => {
-                        let name: String = t.as_str(code).into();
-                        let child = macro_table.get(&name.as_str()[1..]).ok_or_else(|| CompileError::new_undefined_macro(t.first_char, name[1..].into()))?;
-
-                        // append macro nodes into tail
-                        tail.children.push(child.clone());
-                        tail = &mut tail.children[0];
-                        // walk through the macro children to get tail
-                        loop {
-                            if tail.children.get(0).is_some() {
-                                tail = &mut tail.children[0];
-                            } else {
-                                break;
-                            }
-                        }
+    let annotation = match annotation {
+        Some(a) => a,
+        None => return Ok(Some((name, None))),
+    };
+    let first_char = parent.first_char + name.len();
 
-                    },
-                    _ => {}
-                }
-            },
-            None => {
-                return Err(CompileError::new_pipe_no_children(tail.first_char))
+    match annotation.strip_prefix("pad") {
+        Some(width) if !width.is_empty() && width.chars().all(|c| c.is_ascii_digit()) => {
+            let width = width.parse::<u64>().map_err(|_| CompileError::new_int_overflow(first_char + 4))?;
+            if width > MAX_WIDTH {
+                return Err(CompileError::new_width_too_large(first_char + 4, MAX_WIDTH));
             }
+            Ok(Some((name, Some(width))))
+        },
+        _ => Err(CompileError::new_unknown_annotation(first_char, annotation.into())),
+    }
+}
+
+// A + B[range] iterates items of A, then B, the range and the annotation of the last array apply to the joined sequence
+fn parse_concat<'a>(first: Node, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let mut names: Vec<String> = vec![first.as_str(code).into()];
+    let mut end_char = first.end_char;
+
+    while let Some(plus) = iter.next_if(|t| t.is_a(TokenType::Name) && t.as_str(code) == "+") {
+        let t = match iter.next() {
+            Some(t) if t.is_a(TokenType::Name) => t,
+            Some(t) => return Err(CompileError::new_syntax(t.first_char, &[TokenType::Name])),
+            None => return Err(CompileError::new_syntax(plus.end_char, &[TokenType::Name])),
+        };
+        let node = Node::new(t.first_char, t.end_char, InnerNode::Name { start: None, end: None }, vec![]);
+
+        if let Some(range) = iter.next_if(|t| t.is_a(TokenType::Range)) {
+            let last = parse_array(node, *range, code)?;
+
+            return match *last.inner {
+                InnerNode::Array { name, start, end, pad } => {
+                    names.push(name);
+                    Ok(Node::new(first.first_char, last.end_char, InnerNode::ArrayConcat { names, start, end, pad }, vec![]))
+                },
+                _ => Err(CompileError::new_type_error(t.first_char, Type::Array, Type::Name)),
+            };
         }
 
-        if !has_expr(iter) {
-            break;
+        if !is_name_array(t.as_str(code)) {
+            return Err(CompileError::new_type_error(t.first_char, Type::Array, Type::Name));
         }
-    }
 
-    if parent.children.is_empty() {
-        return Err(CompileError::new_pipe_no_children(parent.first_char))
+        names.push(t.as_str(code).into());
+        end_char = t.end_char;
     }
 
-    Ok(parent)
+    Err(CompileError::new_syntax(end_char, &[TokenType::RangeBegin]))
 }
 
-fn has_expr<'a>(iter: &mut Peekable<impl Iterator<Item=&'a Token>>) -> bool {
-    // skip whitespace
-    loop {
+// zip(A, B) iterates both arrays in lockstep and stops at the shorter one
+fn parse_zip(name: &Token, args: &Token, code: &str) -> Result<Node, CompileError> {
+    let mut first_char = args.first_char + 1;
+    let mut names = vec![];
 
-        if let Some(t) = iter.peek() {
-            match t.token_type {
-                TokenType::Space | TokenType::NewLine => {},
-                TokenType::Pipe => {
-                    let _ = iter.next();
-                    return true;
-                },
-                _ => return false,
-            }
+    for arg in code[first_char..args.end_char - 1].split(',') {
+        let next = first_char + arg.len() + 1;
+        let offset = arg.len() - arg.trim_start().len();
+        let arg = arg.trim();
 
-            let _ = iter.next();
-        } else {
-            break;
+        if arg.is_empty() || names.len() == 2 {
+            return Err(CompileError::new_syntax(first_char + offset, &[TokenType::Name]));
+        }
+        if !is_name_array(arg) {
+            return Err(CompileError::new_type_error(first_char + offset, Type::Array, Type::Name));
         }
 
+        names.push(arg.to_string());
+        first_char = next;
     }
 
-
-    false
+    match <[String; 2]>::try_from(names) {
+        Ok([a, b]) => Ok(Node::new(name.first_char, args.end_char, InnerNode::Zip { a, b }, vec![])),
+        Err(_) => Err(CompileError::new_syntax(args.end_char - 1, &[TokenType::Name])),
+    }
 }
 
-pub fn ast(code: &str) -> Result<Vec<Node>, CompileError> {
-    let tokens = lex(code)?;
-    let mut nodes = vec![];
-    let mut macro_table: HashMap<Box<str>, Node> = HashMap::new();
-    let mut iter = tokens.iter().peekable();
+// a name, an array loop or a zip with the pipe
+fn parse_name<'a>(t: &Token, macro_table: &MacroTable, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let mut parent = Node::new(t.first_char, t.end_char, InnerNode::Name{ start: None, end: None }, vec![]);
+    let is_arr = is_name_array(parent.as_str(code));
 
-    while let Some(t) = iter.next() {
-        match t.token_type {
-            TokenType::Literal => {
-                nodes.push(Node::new(t.first_char, t.end_char, InnerNode::Literal, vec![]));
-            },
-            TokenType::Int => {
-                let mut int = parse_int(*t, code);
-                if has_expr(&mut iter) {
-                    int = parse_expr(&macro_table, int, &mut iter, code)?;
-                }
-                nodes.push(int);
+    // handle arrays
+    if let Some(child) = iter.peek() {
+        match child.token_type {
+            TokenType::Range => {
+                parent = parse_array(parent, **child, code)?;
+                iter.next();
             },
-            TokenType::Name => {
-                let mut parent = Node::new(t.first_char, t.end_char, InnerNode::Name{ start: None, end: None }, vec![]);
-                let is_arr = is_name_array(parent.as_str(code));
-
-                // handle arrays
-                match iter.peek() {
-                    Some(child) => {
-                        match child.token_type {
-                            TokenType::Range => {
-                                parent = parse_array(parent, **child, code)?;
-                                iter.next();
-                            },
-                            _ => {
-                                if is_arr {
-                                    return Err(CompileError::new_syntax(parent.first_char, &[TokenType::RangeBegin]))
-                                }
-                            }
-                        }
-                    },
-                    None => {}
-                }
-                // handle expr
-                if has_expr(&mut iter) {
-                    parent = parse_expr(&macro_table, parent, &mut iter, code)?;
-                } else if is_arr {
-                    return Err(CompileError::new_array_pipe(parent.first_char));
-                }
-
-                nodes.push(parent);
-            }
-            TokenType::Pipe => {
-                return Err(CompileError::new_pipe_no_parent(t.first_char));
+            TokenType::Args if parent.as_str(code) == "zip" => {
+                parent = parse_zip(t, child, code)?;
+                iter.next();
             },
-            TokenType::String => {
-                let mut s = parse_string(t.first_char, t.end_char, t.as_str(code), code)?;
-                if has_expr(&mut iter) {
-                    s = parse_expr(&macro_table, s, &mut iter, code)?;
-                }
-                nodes.push(s);
+            // filters need a value to be applied to
+            TokenType::Args => {
+                return Err(CompileError::new_pipe_no_parent(parent.first_char, false))
             },
-            TokenType::Range => {
-                return Err(CompileError::new_syntax(t.first_char, &[TokenType::Name]));
+            TokenType::Name if is_arr && child.as_str(code) == "+" => {
+                parent = parse_concat(parent, iter, code)?;
             },
-            TokenType::MacroDef => {
-                // It doesn't metter what node type we assing here,
-                // because it won't be used anyway. However parse_expr function requires some sort
-                // of a parent node to work, so we pass a Name there
-                let mut m = Node::new(t.first_char, t.end_char, InnerNode::Name { start: None, end: None }, vec![]);
-
-                // assert body
-                if let None = iter.peek() {
-                    return Err(CompileError::new_empty_macro(t.first_char));
-                }
-
-                m = parse_expr(&macro_table, m, &mut iter, code)?;
-                // check macro redifinition
-                let name = &m.as_str(code)[1..];
-                if macro_table.contains_key(name) {
-                    return Err(CompileError::new_macro_redefinition(t.first_char, name.into()))
+            _ => {
+                if is_arr {
+                    return Err(CompileError::new_syntax(parent.first_char, &[TokenType::RangeBegin]))
                 }
-
-                // update table
-                macro_table.insert(name.into(), m.children.pop().unwrap());
-            },
-            TokenType::MacroExp => {
-                let name: String = t.as_str(code).into();
-                let child = macro_table.get(&name.as_str()[1..]).ok_or_else(|| CompileError::new_undefined_macro(t.first_char, name.as_str()[1..].into()))?;
-                nodes.push(child.clone());                
-            },
-            _ => {}
+            }
         }
     }
+    // handle expr
+    if has_expr(iter) {
+        parent = parse_expr(macro_table, parent, iter, code)?;
+    } else if is_arr || matches!(*parent.inner, InnerNode::Zip { .. }) {
+        return Err(CompileError::new_array_pipe(parent.first_char));
+    }
 
-    Ok(nodes)
+    Ok(parent)
+}
+
+// loops can't be piped into, so a macro expands them only on its own
+fn is_loop(node: &Node) -> bool {
+    matches!(*node.inner, InnerNode::Array { .. } | InnerNode::ArrayConcat { .. } | InnerNode::Zip { .. })
+}
+
+// a name starting a loop, the annotation is not a part of it
+fn is_loop_name(name: &str) -> bool {
+    name == "zip" || is_name_array(name.split('#').next().unwrap_or_default())
+}
+
+// macro name -> (position of its definition, body)
+type MacroTable = HashMap<Box<str>, (usize, Node)>;
+
+// keywords are followed by a name of a single value
+fn parse_keyword_name<'a>(keyword: &Token, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<&'a Token, CompileError> {
+    match iter.next() {
+        Some(t) if t.is_a(TokenType::Name) && !is_name_array(t.as_str(code)) => Ok(t),
+        Some(t) => Err(CompileError::new_syntax(t.first_char, &[TokenType::Name])),
+        None => Err(CompileError::new_syntax(keyword.end_char, &[TokenType::Name])),
+    }
 }
 
+// path of `@import "lib.pipa"` and the end of the directive
+fn parse_import_path<'a>(t: &Token, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<(String, usize), CompileError> {
+    while iter.next_if(|n| n.is_a(TokenType::NewLine)).is_some() {}
+
+    match iter.next() {
+        Some(n) if n.is_a(TokenType::String) => {
+            let s = parse_string(n.first_char, n.end_char, n.as_str(code), code)?;
+
+            Ok((literal_string(&s, code)?, n.end_char))
+        },
+        Some(n) => Err(CompileError::new_syntax(n.first_char, &[TokenType::String])),
+        None => Err(CompileError::new_syntax(t.end_char, &[TokenType::String])),
+    }
+}
+
+fn parse_assert<'a>(keyword: &Token, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let name = parse_keyword_name(keyword, iter, code)?;
+
+    let s = match iter.next() {
+        Some(t) if t.is_a(TokenType::String) => parse_string(t.first_char, t.end_char, t.as_str(code), code)?,
+        Some(t) => return Err(CompileError::new_syntax(t.first_char, &[TokenType::String])),
+        None => return Err(CompileError::new_syntax(name.end_char, &[TokenType::String])),
+    };
+
+    // expected value must be known at compile time, so interpolation is forbidden
+    let expected = literal_string(&s, code)?;
+
+    Ok(Node::new(keyword.first_char, s.end_char, InnerNode::Assert { name: name.as_str(code).into(), expected }, vec![]))
+}
+
+fn parse_chars<'a>(keyword: &Token, macro_table: &MacroTable, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let t = parse_keyword_name(keyword, iter, code)?;
+    let mut parent = Node::new(t.first_char, t.end_char, InnerNode::Name { start: None, end: None }, vec![]);
+
+    if let Some(range) = iter.next_if(|t| t.is_a(TokenType::Range)) {
+        parent = parse_array(parent, *range, code)?;
+    }
+
+    // graphemes are iterated like array items, so they must be piped as well
+    if !has_expr(iter) {
+        return Err(CompileError::new_array_pipe(keyword.first_char));
+    }
+
+    let mut node = parse_expr(macro_table, parent, iter, code)?;
+    let (start, end) = match *node.inner {
+        InnerNode::Name { start, end } => (start, end),
+        _ => unreachable!("Parsed above"),
+    };
+
+    node.inner = Box::new(InnerNode::Chars { name: t.as_str(code).into(), start, end });
+
+    Ok(node)
+}
+
+// the directive produces nothing, it only fails if the template requires a newer pipa
+fn check_version<'a>(keyword: &Token, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<(), CompileError> {
+    let s = match iter.next() {
+        Some(t) if t.is_a(TokenType::String) => parse_string(t.first_char, t.end_char, t.as_str(code), code)?,
+        Some(t) => return Err(CompileError::new_syntax(t.first_char, &[TokenType::String])),
+        None => return Err(CompileError::new_syntax(keyword.end_char, &[TokenType::String])),
+    };
+    let required = literal_string(&s, code)?;
+
+    match parse_version(&required) {
+        Some(version) if version > parse_version(VERSION).expect("Crate version must be valid") => {
+            Err(CompileError::new_version_too_high(keyword.first_char, required, VERSION.into()))
+        },
+        Some(_) => Ok(()),
+        None => Err(CompileError::new_invalid_version(s.first_char, required)),
+    }
+}
+
+// major.minor.patch, the missing parts are zeros
+fn parse_version(version: &str) -> Option<[u64; 3]> {
+    let mut parts = [0; 3];
+
+    for (i, part) in version.split('.').enumerate() {
+        if i == parts.len() || part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        parts[i] = part.parse().ok()?;
+    }
+
+    Some(parts)
+}
+
+// value of a string without interpolations
+fn literal_string(s: &Node, code: &str) -> Result<String, CompileError> {
+    let mut value = String::new();
+
+    if let InnerNode::String { ref children } = *s.inner {
+        for child in children {
+            match *child.inner {
+                InnerNode::Literal => {
+                    value.push_str(&child.as_escaped_string(code, &[TokenType::CodeBegin, TokenType::CodeEnd]));
+                },
+                _ => return Err(CompileError::new_type_error(child.first_char, Type::Literal, Type::Name)),
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+// arguments are ints and strings without interpolations separated by ','
+fn parse_filter(name: &Token, args: &Token, code: &str) -> Result<Node, CompileError> {
+    let first_char = args.first_char + 1;
+    let end_char = args.end_char - 1;
+    let mut values = vec![];
+    let mut iter = EscapeIter::new(&code[first_char..end_char], first_char, &[]).peekable();
+    let is_space = |(_, _, t): &(bool, usize, &str)| *t == " " || *t == "\t";
+
+    let mut expect_arg = false;
+
+    while let Some((is_escaping, i, t)) = iter.by_ref().find(|t| !is_space(t)) {
+        expect_arg = false;
+
+        match (is_escaping, t) {
+            (false, "\"") => {
+                let end = iter.find(|(is_escaping, _, t)| !is_escaping && *t == "\"").map(|(_, i, _)| i + 1).unwrap_or(end_char);
+                let s = parse_string(i, end, &code[i..end], code)?;
+
+                values.push(FilterArg::String(literal_string(&s, code)?));
+            },
+            (false, t) if t.as_bytes()[0].is_ascii_digit() => {
+                let mut end = i + t.len();
+
+                while let Some((false, j, t)) = iter.peek() && t.as_bytes()[0].is_ascii_digit() {
+                    end = j + t.len();
+                    iter.next();
+                }
+
+                let value = code[i..end].parse::<u64>().map_err(|_| CompileError::new_int_overflow(i))?;
+                values.push(FilterArg::Int(value));
+            },
+            (_, &_) => return Err(CompileError::new_syntax(i, &[TokenType::Int, TokenType::String])),
+        }
+
+        match iter.by_ref().find(|t| !is_space(t)) {
+            Some((false, _, ",")) => expect_arg = true,
+            Some((_, i, _)) => return Err(CompileError::new_syntax(i, &[TokenType::ExprEnd])),
+            None => break,
+        }
+    }
+
+    // an argument must follow the comma
+    if expect_arg {
+        return Err(CompileError::new_syntax(end_char, &[TokenType::Int, TokenType::String]));
+    }
+
+    let filter = Filter::new(name.as_str(code), values).map_err(|reason| CompileError { first_char: name.first_char, expanded_at: None, reason })?;
+
+    Ok(Node::new(name.first_char, args.end_char, InnerNode::Filter { filter }, vec![]))
+}
+
+fn parse_expr<'a>(macro_table: &MacroTable, mut parent: Node, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let mut tail = &mut parent;
+
+    loop {
+        let target = match iter.peek() {
+            Some(t) if t.is_a(TokenType::FanOut) => Some(parse_fan_out(macro_table, iter, code)?),
+            Some(_) => parse_target(macro_table, tail.first_char, iter, code)?,
+            None => return Err(CompileError::new_pipe_no_children(tail.first_char)),
+        };
+
+        if let Some(target) = target {
+            tail.children.push(target);
+            tail = &mut tail.children[0];
+            // walk through the macro children to get tail
+            while !tail.children.is_empty() {
+                tail = &mut tail.children[0];
+            }
+        }
+
+        if !has_expr(iter) {
+            break;
+        }
+    }
+
+    if parent.children.is_empty() {
+        return Err(CompileError::new_pipe_no_children(parent.first_char))
+    }
+
+    Ok(parent)
+}
+
+// parses a single pipe target, a macro expands to its whole chain
+fn parse_target<'a>(macro_table: &MacroTable, parent_first_char: usize, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Option<Node>, CompileError> {
+    let t = match iter.next() {
+        Some(t) => t,
+        None => return Err(CompileError::new_pipe_no_children(parent_first_char)),
+    };
+
+    match t.token_type {
+        TokenType::Literal => {
+            Err(CompileError::new_pipe_no_children(parent_first_char))
+        },
+        TokenType::Int => {
+            Err(CompileError::new_unquoted_pipe_target(t.first_char, format!("\"{}\"", t.as_str(code))))
+        },
+        TokenType::Name => {
+            match iter.peek() {
+                Some(args) if args.is_a(TokenType::Args) => {
+                    let filter = parse_filter(t, args, code)?;
+                    iter.next();
+
+                    Ok(Some(filter))
+                },
+                _ => Err(CompileError::new_unquoted_pipe_target(t.first_char, format!("\"$({})\"", t.as_str(code)))),
+            }
+        }
+        TokenType::Pipe | TokenType::FanOut | TokenType::Range => {
+            Err(CompileError::new_syntax(parent_first_char, &[TokenType::String]))
+        },
+        TokenType::String => {
+            Ok(Some(parse_string(t.first_char, t.end_char, t.as_str(code), code)?))
+        },
+        TokenType::MacroDef => {
+            Err(CompileError::new_nested_macro(parent_first_char))
+        },
+        TokenType::MacroExp => {
+            let name: String = t.as_str(code).into();
+            let (_, child) = macro_table.get(&name.as_str()[1..]).ok_or_else(|| CompileError::new_undefined_macro(t.first_char, name[1..].into()))?;
+
+            if is_loop(child) {
+                return Err(CompileError::new_type_error(t.first_char, Type::String, Type::Array));
+            }
+
+            Ok(Some(child.expand(t)))
+        },
+        _ => Ok(None),
+    }
+}
+
+// every branch starts with '||', a '|' after the last one pipes the concatenation
+fn parse_fan_out<'a>(macro_table: &MacroTable, iter: &mut Peekable<impl Iterator<Item=&'a Token>>, code: &str) -> Result<Node, CompileError> {
+    let mut branches = vec![];
+    let first_char = iter.peek().map(|t| t.first_char).unwrap_or_default();
+    let mut end_char = first_char;
+
+    while let Some(t) = iter.next_if(|t| t.is_a(TokenType::FanOut)) {
+        let branch = parse_target(macro_table, t.first_char, iter, code)?
+            .ok_or_else(|| CompileError::new_syntax(t.end_char, &[TokenType::String]))?;
+
+        end_char = branch.end_char;
+        branches.push(branch);
+
+        // skip whitespace
+        while iter.next_if(|t| t.is_whitespace()).is_some() {}
+    }
+
+    Ok(Node::new(first_char, end_char, InnerNode::FanOut { branches }, vec![]))
+}
+
+// consumes the pipe, but leaves '||' to parse_expr, because it starts a fan-out
+fn has_expr<'a>(iter: &mut Peekable<impl Iterator<Item=&'a Token>>) -> bool {
+    // skip whitespace
+    loop {
+
+        if let Some(t) = iter.peek() {
+            match t.token_type {
+                kind if kind.is_whitespace() => {},
+                TokenType::Pipe => {
+                    let _ = iter.next();
+                    return true;
+                },
+                TokenType::FanOut => return true,
+                _ => return false,
+            }
+
+            let _ = iter.next();
+        } else {
+            break;
+        }
+
+    }
+
+
+    false
+}
+
+/// Removes `#` comments from code blocks. Comments end at a new line or at the end of the block.
+/// The new line itself is kept, because it separates expressions, so the result parses the same way.
+/// Literals and strings are copied as is.
+pub fn strip_comments(code: &str) -> String {
+    strip_comments_with_options(code, &DEFAULT_OPTIONS)
+}
+
+/// Same as `strip_comments` for code blocks between the delimiters of `options`
+pub fn strip_comments_with_options(code: &str, options: &CompileOptions) -> String {
+    #[derive(PartialEq)]
+    enum State {
+        Literal,
+        Code,
+        String,
+        Comment,
+    }
+
+    let mut output = String::with_capacity(code.len());
+    let mut state = State::Literal;
+    let mut chars = code.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (&state, c) {
+            (State::Comment, c) if c == options.close && chars.peek() == Some(&options.close) => {
+                output.push(c);
+                state = State::Literal;
+            },
+            (State::Comment, '\r') if chars.peek() == Some(&'\n') => {
+                output.push(c);
+            },
+            (State::Comment, '\n') => {
+                output.push(c);
+                state = State::Code;
+            },
+            (State::Comment, _) => {},
+            // escaped symbol is copied with the backslash
+            (_, '\\') => {
+                output.push(c);
+
+                if let Some(c) = chars.next() {
+                    output.push(c);
+                }
+            },
+            (State::Literal, c) if c == options.open => {
+                output.push(c);
+                state = State::Code;
+            },
+            (State::Code, c) if c == options.close => {
+                output.push(c);
+                state = State::Literal;
+            },
+            (State::Code, '"') => {
+                output.push(c);
+                state = State::String;
+            },
+            (State::Code, '#') => {
+                state = State::Comment;
+            },
+            // strings end at new lines
+            (State::String, '"') | (State::String, '\n') => {
+                output.push(c);
+                state = State::Code;
+            },
+            (_, _) => output.push(c),
+        }
+    }
+
+    output
+}
+
+pub fn ast(code: &str) -> Result<Vec<Node>, CompileError> {
+    ast_with_options(code, &DEFAULT_OPTIONS)
+}
+
+// a block opened by a keyword and closed by `end` or `endindent`
+enum Block {
+    Condition {
+        name: String,
+        negate: bool,
+    },
+    Indent {
+        width: u64,
+    },
+}
+
+impl Block {
+    fn closing_keyword(&self) -> &'static str {
+        match self {
+            Block::Condition { .. } => "end",
+            Block::Indent { .. } => "endindent",
+        }
+    }
+}
+
+pub fn ast_with_options(code: &str, options: &CompileOptions) -> Result<Vec<Node>, CompileError> {
+    let tokens = lex_with_options(code, options)?;
+
+    if !options.allow_macros && let Some(t) = tokens.iter().find(|t| t.is_a(TokenType::MacroDef) || t.is_a(TokenType::MacroExp)) {
+        return Err(CompileError::new_feature_disabled(t.first_char, "macros".into()));
+    }
+
+    let mut nodes = vec![];
+    let mut macro_table: MacroTable = HashMap::new();
+    let mut iter = tokens.iter().peekable();
+    // open blocks: keyword, kind and the nodes before the block
+    let mut blocks: Vec<(&Token, Block, Vec<Node>)> = vec![];
+
+    while let Some(t) = iter.next() {
+        match t.token_type {
+            TokenType::Literal => {
+                nodes.push(Node::new(t.first_char, t.end_char, InnerNode::Literal, vec![]));
+            },
+            TokenType::Int => {
+                let mut int = parse_int(*t, code)?;
+                if has_expr(&mut iter) {
+                    int = parse_expr(&macro_table, int, &mut iter, code)?;
+                }
+                nodes.push(int);
+            },
+            TokenType::Name => {
+                nodes.push(parse_name(t, &macro_table, &mut iter, code)?);
+            }
+            TokenType::Pipe => {
+                // a common mistake is starting a block with the pipe, as if the text before it was piped
+                let after_literal = nodes.last().is_some_and(|n| matches!(*n.inner, InnerNode::Literal) && !n.as_str(code).is_empty());
+                return Err(CompileError::new_pipe_no_parent(t.first_char, after_literal));
+            },
+            TokenType::String => {
+                let mut s = parse_string(t.first_char, t.end_char, t.as_str(code), code)?;
+                if has_expr(&mut iter) {
+                    s = parse_expr(&macro_table, s, &mut iter, code)?;
+                }
+                nodes.push(s);
+            },
+            TokenType::Range => {
+                return Err(CompileError::new_syntax(t.first_char, &[TokenType::Name]));
+            },
+            TokenType::MacroDef if t.as_str(code) == "@import" => {
+                // imports are inlined by resolve_imports before compilation
+                let (path, _) = parse_import_path(t, &mut iter, code)?;
+                return Err(CompileError::new_import_error(t.first_char, path));
+            },
+            TokenType::MacroDef => {
+                // It doesn't metter what node type we assing here,
+                // because it won't be used anyway. However parse_expr function requires some sort
+                // of a parent node to work, so we pass a Name there
+                let mut m = Node::new(t.first_char, t.end_char, InnerNode::Name { start: None, end: None }, vec![]);
+
+                // assert body
+                if let None = iter.peek() {
+                    return Err(CompileError::new_empty_macro(t.first_char));
+                }
+
+                let body = match iter.next_if(|n| n.is_a(TokenType::Name) && is_loop_name(n.as_str(code))) {
+                    // a loop is the whole body, it isn't a pipe target
+                    Some(n) => parse_name(n, &macro_table, &mut iter, code)?,
+                    None => {
+                        m = parse_expr(&macro_table, m, &mut iter, code)?;
+                        m.children.pop().unwrap()
+                    },
+                };
+                // check macro redifinition
+                let name = &t.as_str(code)[1..];
+                if let Some((first_defined, _)) = macro_table.get(name) {
+                    return Err(CompileError::new_macro_redefinition(t.first_char, name.into(), *first_defined))
+                }
+
+                // update table
+                macro_table.insert(name.into(), (t.first_char, body));
+            },
+            TokenType::MacroExp => {
+                let name: String = t.as_str(code).into();
+                let (_, child) = macro_table.get(&name.as_str()[1..]).ok_or_else(|| CompileError::new_undefined_macro(t.first_char, name.as_str()[1..].into()))?;
+                nodes.push(child.expand(t));
+            },
+            TokenType::Keyword => {
+                match t.as_str(code) {
+                    "assert" => nodes.push(parse_assert(t, &mut iter, code)?),
+                    "version" => check_version(t, &mut iter, code)?,
+                    "chars" => nodes.push(parse_chars(t, &macro_table, &mut iter, code)?),
+                    "stop" => nodes.push(Node::new(t.first_char, t.end_char, InnerNode::Stop, vec![])),
+                    keyword @ ("if" | "unless") => {
+                        let name = parse_keyword_name(t, &mut iter, code)?.as_str(code).into();
+
+                        blocks.push((t, Block::Condition { name, negate: keyword == "unless" }, std::mem::take(&mut nodes)));
+                    },
+                    "indent" => {
+                        let width = match iter.next() {
+                            Some(n) if n.is_a(TokenType::Int) => (n, n.as_str(code).parse::<u64>().map_err(|_| CompileError::new_int_overflow(n.first_char))?),
+                            Some(n) => return Err(CompileError::new_syntax(n.first_char, &[TokenType::Int])),
+                            None => return Err(CompileError::new_syntax(t.end_char, &[TokenType::Int])),
+                        };
+                        let width = match width {
+                            (n, width) if width > MAX_WIDTH => return Err(CompileError::new_width_too_large(n.first_char, MAX_WIDTH)),
+                            (_, width) => width,
+                        };
+
+                        blocks.push((t, Block::Indent { width }, std::mem::take(&mut nodes)));
+                    },
+                    end @ ("end" | "endindent") => {
+                        // `end` closes if/unless, `endindent` closes indent
+                        let end = if end == "end" { "end" } else { "endindent" };
+                        let (keyword, block, outer) = match blocks.pop() {
+                            Some(b) if b.1.closing_keyword() == end => b,
+                            _ => return Err(CompileError::new_unmatched_end(t.first_char, end)),
+                        };
+                        let body = std::mem::replace(&mut nodes, outer);
+                        let inner = match block {
+                            Block::Condition { name, negate } => InnerNode::Condition { name, negate, body },
+                            Block::Indent { width } => InnerNode::Indent { width, body },
+                        };
+
+                        nodes.push(Node::new(keyword.first_char, t.end_char, inner, vec![]));
+                    },
+                    _ => unreachable!("Unknown keyword {}", t.as_str(code)),
+                }
+            },
+            _ => {}
+        }
+    }
+
+    if let Some((keyword, block, _)) = blocks.pop() {
+        return Err(CompileError::new_unclosed_block(keyword.first_char, block.closing_keyword()));
+    }
+
+    if !options.allow_loops && let Some(node) = find_loop(&nodes) {
+        return Err(CompileError::new_feature_disabled(node.first_char, "loops".into()).in_expansion(node.expanded_at));
+    }
+
+    Ok(nodes)
+}
+
+fn find_loop(nodes: &[Node]) -> Option<&Node> {
+    nodes.iter().find_map(|node| {
+        match &*node.inner {
+            InnerNode::Array { .. } | InnerNode::ArrayConcat { .. } | InnerNode::Zip { .. } | InnerNode::Chars { .. } => Some(node),
+            _ => find_loop(node.children()).or_else(|| find_loop(&node.children)),
+        }
+    })
+}
+
+
+/// Parses a library of macro definitions into a map of names to the sources of their bodies.
+/// Anything but definitions, comments and whitespace is an error.
+pub fn extract_macros(code: &str) -> Result<HashMap<String, String>, CompileError> {
+    // reports redefinitions, undefined macros and other errors of the definitions
+    for node in ast(code)? {
+        if !matches!(*node.inner, InnerNode::Literal) || !node.as_str(code).trim().is_empty() {
+            return Err(CompileError::new_syntax(node.first_char, &[TokenType::MacroDef]));
+        }
+    }
+
+    let tokens = lex(code)?;
+    let mut macros = HashMap::new();
+    let mut iter = tokens.iter().filter(|t| t.token_type != TokenType::NewLine).peekable();
+
+    while let Some(t) = iter.next() {
+        if t.token_type != TokenType::MacroDef {
+            continue;
+        }
+
+        // body is a pipe chain, so it ends at the first part that isn't piped,
+        // filter arguments and ranges belong to the name before them
+        let first = iter.next().expect("Should be handled during syntax analysis");
+        let mut end_char = first.end_char;
+        let mut piped = first.is_a(TokenType::FanOut);
+        // arrays joined by '+' in loops
+        let is_plus = |t: &Token| t.is_a(TokenType::Name) && t.as_str(code) == "+";
+
+        while let Some(part) = iter.next_if(|t| piped || t.is_a(TokenType::Pipe) || t.is_a(TokenType::FanOut) || t.is_a(TokenType::Args) || t.is_a(TokenType::Range) || is_plus(t)) {
+            piped = part.is_a(TokenType::Pipe) || part.is_a(TokenType::FanOut) || is_plus(part);
+            end_char = part.end_char;
+        }
+
+        macros.insert(t.as_str(code)[1..].into(), code[first.first_char..end_char].into());
+    }
+
+    Ok(macros)
+}
+
+/// Resolves `@import "lib.pipa"` directives. `resolver` returns the source of a library of macro definitions,
+/// see `extract_macros`, and the macros of all libraries are inlined into the template.
+/// Libraries can't import other libraries, errors in a library point into its source.
+/// Errors in the bodies of imported macros point at the directive that imported them, see `Inlined::map_error`.
+pub fn resolve_imports(code: &str, mut resolver: impl FnMut(&str) -> Option<String>) -> Result<Inlined, CompileError> {
+    let tokens = lex(code)?;
+    let mut iter = tokens.iter().peekable();
+    let mut output = String::with_capacity(code.len());
+    let mut last = 0;
+    let mut macros = HashMap::new();
+    // where each macro was imported, to report collisions
+    let mut imported_at: HashMap<String, usize> = HashMap::new();
+
+    while let Some(t) = iter.next() {
+        if !t.is_a(TokenType::MacroDef) || t.as_str(code) != "@import" {
+            continue;
+        }
+
+        let (path, end_char) = parse_import_path(t, &mut iter, code)?;
+        let library = resolver(&path).ok_or_else(|| CompileError::new_import_error(t.first_char, path))?;
+
+        for (name, body) in extract_macros(&library)? {
+            if let Some(first_defined) = imported_at.get(&name) {
+                return Err(CompileError::new_macro_redefinition(t.first_char, name, *first_defined));
+            }
+
+            imported_at.insert(name.clone(), t.first_char);
+            macros.insert(name, body);
+        }
+
+        // the directive is blanked, so lines of the template don't shift
+        output.push_str(&code[last..t.first_char]);
+        for c in code[t.first_char..end_char].chars() {
+            match c {
+                '\n' | '\r' => output.push(c),
+                _ => output.extend(std::iter::repeat_n(' ', c.len_utf8())),
+            }
+        }
+        last = end_char;
+    }
+
+    output.push_str(&code[last..]);
+
+    match macros.is_empty() {
+        true => Ok(Inlined { code: output, prefix_len: 0, definitions: vec![] }),
+        false => inline_macros_at(&output, &macros, |name| imported_at[name]),
+    }
+}
+
+/// Renames a variable or an array in the template source, in code blocks and in strings.
+/// Filters, macros and keywords with the same name are kept.
+pub fn rename_var(code: &str, old_name: &str, new_name: &str) -> Result<String, CompileError> {
+    let is_valid = !new_name.is_empty()
+        && !is_keyword(new_name)
+        && !new_name.starts_with(|c: char| c.is_ascii_digit())
+        && new_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !is_valid {
+        return Err(CompileError::new_name(0));
+    }
+
+    // only valid templates are renamed
+    ast(code)?;
+
+    let tokens = lex(code)?;
+    let mut iter = tokens.iter().peekable();
+    let mut spans = vec![];
+
+    while let Some(t) = iter.next() {
+        match t.token_type {
+            TokenType::Name => {
+                // filters and zip look like names followed by args, arrays of zip are its args
+                if let Some(args) = iter.next_if(|n| n.is_a(TokenType::Args)) {
+                    if t.as_str(code) == "zip" {
+                        let mut offset = args.first_char + 1;
+
+                        for arg in args.as_str(code)[1..args.as_str(code).len() - 1].split(',') {
+                            let start = offset + arg.len() - arg.trim_start().len();
+
+                            if arg.trim() == old_name {
+                                spans.push((start, start + old_name.len()));
+                            }
+                            offset += arg.len() + 1;
+                        }
+                    }
+                    continue;
+                }
+
+                // annotations of arrays follow the name
+                let name = t.as_str(code).split('#').next().unwrap_or_default();
+
+                if name == old_name {
+                    spans.push((t.first_char, t.first_char + name.len()));
+                }
+            },
+            TokenType::String => {
+                let s = parse_string(t.first_char, t.end_char, t.as_str(code), code)?;
+
+                if let InnerNode::String { children } = *s.inner {
+                    for child in children {
+                        if matches!(*child.inner, InnerNode::Name { .. }) && child.as_str(code) == old_name {
+                            spans.push((child.first_char, child.end_char));
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut output = String::with_capacity(code.len());
+    let mut last = 0;
+
+    for (start, end) in spans {
+        output.push_str(&code[last..start]);
+        output.push_str(new_name);
+        last = end;
+    }
+
+    output.push_str(&code[last..]);
+
+    Ok(output)
+}
+
+/// Renames a macro in the template source: its definition `@old` and all expansions `?old`
+pub fn rename_macro(code: &str, old_name: &str, new_name: &str) -> Result<String, CompileError> {
+    let is_valid = !new_name.is_empty() && new_name != "import" && new_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !is_valid {
+        return Err(CompileError::new_name(0));
+    }
+
+    ast(code)?;
+
+    let tokens = lex(code)?;
+    let definition = |name: &str| tokens.iter().find(|t| t.is_a(TokenType::MacroDef) && &t.as_str(code)[1..] == name);
+
+    let old = definition(old_name).ok_or_else(|| CompileError::new_undefined_macro(0, old_name.into()))?;
+    if let Some(new) = definition(new_name) {
+        return Err(CompileError::new_macro_redefinition(old.first_char, new_name.into(), new.first_char));
+    }
+
+    let mut output = String::with_capacity(code.len());
+    let mut last = 0;
+
+    for t in &tokens {
+        if (t.is_a(TokenType::MacroDef) || t.is_a(TokenType::MacroExp)) && &t.as_str(code)[1..] == old_name {
+            // keep the sigil
+            output.push_str(&code[last..t.first_char + 1]);
+            output.push_str(new_name);
+            last = t.end_char;
+        }
+    }
+
+    output.push_str(&code[last..]);
+
+    Ok(output)
+}
+
+// names of the macros expanded in the body
+fn macro_deps(body: &str) -> Vec<String> {
+    let code = format!("{{{{ {} }}}}", body);
+
+    lex(&code).unwrap_or_default().iter()
+        .filter(|t| t.is_a(TokenType::MacroExp))
+        .map(|t| t.as_str(&code)[1..].into())
+        .collect()
+}
+
+/// A template with inlined macro definitions, see `inline_macros_mapped`.
+/// The definitions are prepended, so positions of the compiled code are mapped back to the template by `map_error`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Inlined {
+    code: String,
+    // length of the prepended definitions
+    prefix_len: usize,
+    // span of each definition in the prefix and the position in the template its errors point at
+    definitions: Vec<(usize, usize, usize)>,
+}
+
+impl Inlined {
+    /// Source to compile
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Position in the template of a position in `code`.
+    /// Positions in a macro body point at the start of the template or at the directive that imported the macro.
+    pub fn position(&self, pos: usize) -> usize {
+        match pos.checked_sub(self.prefix_len) {
+            Some(pos) => pos,
+            None => self.definitions.iter()
+                .find(|(start, end, _)| (*start..*end).contains(&pos))
+                .map_or(0, |(_, _, anchor)| *anchor),
+        }
+    }
+
+    /// Maps positions of an error of compiling `code` to the template, so it can be shown with the template source
+    pub fn map_error(&self, mut err: CompileError) -> CompileError {
+        err.first_char = self.position(err.first_char);
+        err.expanded_at = err.expanded_at.map(|(start, end)| (self.position(start), self.position(end)));
+
+        if let ErrorReason::MacroRedefinition { first_defined, .. } = &mut err.reason {
+            *first_defined = self.position(*first_defined);
+        }
+
+        err
+    }
+}
+
+/// Prepends macro definitions to the template, so it can use them
+pub fn inline_macros(code: &str, macros: &HashMap<String, String>) -> Result<String, CompileError> {
+    inline_macros_mapped(code, macros).map(|inlined| inlined.code)
+}
+
+/// Same as `inline_macros`, errors of the compiled code are mapped back to the template with `Inlined::map_error`
+pub fn inline_macros_mapped(code: &str, macros: &HashMap<String, String>) -> Result<Inlined, CompileError> {
+    inline_macros_at(code, macros, |_| 0)
+}
+
+// `anchor` is the position in the template errors in the body of a macro point at
+fn inline_macros_at(code: &str, macros: &HashMap<String, String>, anchor: impl Fn(&str) -> usize) -> Result<Inlined, CompileError> {
+    let mut names: Vec<&String> = macros.keys().collect();
+    names.sort();
+
+    for name in &names {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(CompileError::new_name(0));
+        }
+    }
+
+    // a macro must be defined before the ones that use it
+    let deps: HashMap<&String, Vec<String>> = names.iter().map(|name| (*name, macro_deps(&macros[*name]))).collect();
+    let mut defined: Vec<&String> = Vec::with_capacity(names.len());
+
+    while !names.is_empty() {
+        let ready = names.iter().position(|name| {
+            deps[name].iter().all(|dep| !names.iter().any(|n| n == &dep))
+        });
+
+        // cyclic definitions are reported by the compiler
+        defined.push(names.remove(ready.unwrap_or(0)));
+    }
+
+    let mut output = String::from("{{");
+    let mut definitions = Vec::with_capacity(defined.len());
+
+    for name in defined {
+        let start = output.len();
+
+        output.push_str(&format!(" @{} {}", name, macros[name]));
+        definitions.push((start, output.len(), anchor(name)));
+    }
+
+    output.push_str(" }}");
+    let prefix_len = output.len();
+    output.push_str(code);
+
+    Ok(Inlined { code: output, prefix_len, definitions })
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::syntax::{Token, TokenType, InnerNode, CompileOptions, lex, lex_bytes, ast_with_options, strip_comments, strip_comments_with_options, extract_macros, inline_macros, inline_macros_mapped, resolve_imports, rename_var, rename_macro, all_token_positions, DEFAULT_OPTIONS};
+    use crate::ir::gen_ir;
+    use crate::vm::Vm;
+    use crate::analysis::{NO_OPT, FULL_OPT};
+    use crate::error::{CompileError, ErrorReason, LexError};
+    use crate::utils::{VARS, ARRAYS};
+
+    const ANGLE: CompileOptions = CompileOptions { open: '<', close: '>', ..DEFAULT_OPTIONS };
+
+    fn render(code: &str, options: &CompileOptions) -> String {
+        let nodes = ast_with_options(code, options).unwrap();
+        let [no_opt, full_opt] = [NO_OPT, FULL_OPT].map(|opt| {
+            let ir = gen_ir(code, &nodes, opt).unwrap();
+            let mut out = Vec::new();
+
+            Vm::new(&VARS, &ARRAYS).run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        });
+
+        assert_eq!(no_opt, full_opt);
+        no_opt
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        let code = "fn main() { println!(\"<< first >>\"); }\n<< ARGS[0:2] | \"{$(_item_)}\" >>";
+
+        assert_eq!(render(code, &ANGLE), "fn main() { println!(\"first arg\"); }\n{first element}{second element}");
+        assert_eq!(render("a \\< b << 1 >>", &ANGLE), "a < b 1");
+        assert_eq!(render("%% first !! {{ first }}", &CompileOptions { open: '%', close: '!', ..DEFAULT_OPTIONS }), "first arg {{ first }}");
+    }
+
+    #[test]
+    fn custom_delimiters_errors() {
+        let reason = |code| ast_with_options(code, &ANGLE).unwrap_err().reason;
+
+        assert_eq!(reason("a > b"), ErrorReason::SyntaxError { expected: vec![TokenType::CodeBegin] });
+        assert_eq!(reason("<< first >"), ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+        assert_eq!(reason("<< first <<"), ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+    }
+
+    #[test]
+    fn invalid_delimiters() {
+        let reason = |open, close| ast_with_options("text", &CompileOptions { open, close, ..DEFAULT_OPTIONS }).unwrap_err().reason;
+
+        assert_eq!(reason('%', '%'), ErrorReason::InvalidDelimiters { open: '%', close: '%' });
+        assert_eq!(reason('[', ']'), ErrorReason::InvalidDelimiters { open: '[', close: ']' });
+        assert_eq!(reason('<', '|'), ErrorReason::InvalidDelimiters { open: '<', close: '|' });
+        assert_eq!(reason('a', '>'), ErrorReason::InvalidDelimiters { open: 'a', close: '>' });
+        assert_eq!(reason(' ', '>'), ErrorReason::InvalidDelimiters { open: ' ', close: '>' });
+    }
+
+    #[test]
+    fn node_children() {
+        let code = "{{ \"a$(first)b\" | \"$(_)\" 69 if on }}{{ first || \"x\" || \"y\" }}{{ end }}";
+        let nodes = ast_with_options(code, &DEFAULT_OPTIONS).unwrap();
+        let [string, int, condition] = &nodes[..] else { panic!("{:#?}", nodes) };
+
+        let parts: Vec<&str> = string.children().iter().map(|n| n.as_str(code)).collect();
+        assert_eq!(parts, ["a", "first", "b"]);
+        // the pipe target isn't inside the string
+        assert_eq!(string.children.len(), 1);
+
+        assert!(matches!(*int.inner, InnerNode::Int { value: 69 }));
+        assert!(int.children().is_empty());
+
+        let name = condition.children().iter().find(|n| matches!(*n.inner, InnerNode::Name { .. })).unwrap();
+        let [fan_out] = &name.children[..] else { panic!("{:#?}", name) };
+        assert_eq!(fan_out.children().len(), 2);
+    }
+
+    #[test]
+    fn disabled_features() {
+        const SANDBOX: CompileOptions = CompileOptions { allow_loops: false, allow_macros: false, ..DEFAULT_OPTIONS };
+        let error = |code: &str, options: &CompileOptions| ast_with_options(code, options).unwrap_err();
+        let disabled = |feature: &str| ErrorReason::FeatureDisabled { feature: feature.into() };
+
+        assert_eq!(render("<p>{{ first | \"$(_)!\" }}</p>{{ if on }}on{{ end }}", &SANDBOX), "<p>first arg!</p>");
+
+        let code = "{{ first }}{{ ARGS[:] | \"$(_item_)\" }}";
+        assert_eq!(error(code, &SANDBOX), CompileError::new_feature_disabled(14, "loops".into()));
+        assert_eq!(error("{{ zip(ARGS, PHONES) | \"$(_a_)\" }}", &SANDBOX).reason, disabled("loops"));
+        assert_eq!(error("{{ chars first | \"$(_item_)\" }}", &SANDBOX).reason, disabled("loops"));
+        assert_eq!(error("{{ if on }}{{ ARGS + PHONES[:] | \"-\" }}{{ end }}", &SANDBOX).reason, disabled("loops"));
+        assert_eq!(error("{{ first || \"-\" || \"$(_)\" }}{{ ARGS[:] | \"-\" }}", &SANDBOX).reason, disabled("loops"));
+
+        assert_eq!(error("{{ @p \"$(_)\" }}", &SANDBOX), CompileError::new_feature_disabled(3, "macros".into()));
+        assert_eq!(error("{{ first | ?p }}", &SANDBOX).reason, disabled("macros"));
+        assert_eq!(error("{{ @import \"lib.pipa\" }}", &SANDBOX).reason, disabled("macros"));
+
+        // a loop from a macro points to the expansion
+        let only_loops = CompileOptions { allow_loops: false, ..DEFAULT_OPTIONS };
+        let err = error("{{ @list ARGS[:] | \"-\" }}{{ ?list }}", &only_loops);
+        assert_eq!(err.reason, disabled("loops"));
+        assert_eq!(err.expanded_at, Some((28, 33)));
+
+        let only_macros = CompileOptions { allow_macros: false, ..DEFAULT_OPTIONS };
+        assert_eq!(render("{{ ARGS[:1] | \"$(_item_)\" }}", &only_macros), "first element");
+    }
+
+    fn display(code: &str, token_type: TokenType) -> String {
+        Token::new(0, code.len(), token_type).display(code).to_string()
+    }
+
+    #[test]
+    fn token_types() {
+        let tokens = lex("text{{ first | \"$(_)\" }}").unwrap();
+        let types = [TokenType::Literal, TokenType::Name, TokenType::Pipe, TokenType::String];
+
+        assert_eq!(tokens.len(), types.len());
+        for (token, t) in tokens.iter().zip(types) {
+            assert!(token.is_a(t), "{:?}", token);
+            assert!(token.matches_type(t), "{:?}", token);
+        }
+
+        assert!(tokens[1].same_type(&Token::new(0, 0, TokenType::Name)));
+        assert!(!tokens[1].same_type(&tokens[3]));
+    }
+
+    #[test]
+    fn bytes() {
+        let code = "text {{ first }}";
+        let with_bom = [b"\xef\xbb\xbf".as_slice(), code.as_bytes()].concat();
+
+        assert_eq!(lex_bytes(code.as_bytes()), Ok(lex(code).unwrap()));
+
+        let tokens = lex_bytes(&with_bom).unwrap();
+        let with_bom = std::str::from_utf8(&with_bom).unwrap();
+        assert_eq!(tokens.iter().map(|t| t.as_str(with_bom)).collect::<Vec<_>>(), ["text ", "first"]);
+
+        assert!(matches!(lex_bytes(b"text \xff {{ first }}"), Err(LexError::Utf8Error(_))));
+        assert!(matches!(lex_bytes(b"\xef\xbb\xbf{{ first"), Err(LexError::CompileError(e)) if e.first_char == 10));
+    }
+
+    #[test]
+    fn token_display() {
+        let cases = [
+            (TokenType::Int, "42", "Int(42)"),
+            (TokenType::Name, "fname", "Name(\"fname\")"),
+            (TokenType::Literal, "hello", "Literal(\"hello\")"),
+            (TokenType::CodeBegin, "{{", "CodeBegin(\"{{\")"),
+            (TokenType::CodeEnd, "}}", "CodeEnd(\"}}\")"),
+            (TokenType::ExprBegin, "(", "ExprBegin(\"(\")"),
+            (TokenType::ExprEnd, ")", "ExprEnd(\")\")"),
+            (TokenType::Quote, "\"", "Quote(\"\\\"\")"),
+            (TokenType::String, "\"value\"", "String(\"\\\"value\\\"\")"),
+            (TokenType::Space, " ", "Space(\" \")"),
+            (TokenType::NewLine, "\n", "NewLine(\"\\n\")"),
+            (TokenType::EscapeSymbol, "\\", "EscapeSymbol(\"\\\\\")"),
+            (TokenType::FormatSymbol, "$", "FormatSymbol(\"$\")"),
+            (TokenType::Range, "[1:2]", "Range(\"[1:2]\")"),
+            (TokenType::RangeBegin, "[", "RangeBegin(\"[\")"),
+            (TokenType::RangeSep, ":", "RangeSep(\":\")"),
+            (TokenType::RangeEnd, "]", "RangeEnd(\"]\")"),
+            (TokenType::MacroDef, "@print", "MacroDef(\"@print\")"),
+            (TokenType::MacroExp, "?print", "MacroExp(\"?print\")"),
+            (TokenType::Pipe, "|", "Pipe(\"|\")"),
+            (TokenType::Keyword, "assert", "Keyword(\"assert\")"),
+            (TokenType::Args, "(5, \"~\")", "Args(\"(5, \\\"~\\\")\")"),
+            (TokenType::FanOut, "||", "FanOut(\"||\")"),
+        ];
+
+        for (token_type, code, expected) in cases {
+            assert_eq!(display(code, token_type), expected);
+        }
+    }
+
+    #[test]
+    fn token_positions() {
+        let code = "{{ @p \"$(_)\" }}text {{ first | ?p }}{{ second | ?p }}";
+
+        assert_eq!(all_token_positions(code, TokenType::Name), Ok(vec![23, 39]));
+        assert_eq!(all_token_positions(code, TokenType::MacroExp), Ok(vec![31, 48]));
+        assert_eq!(all_token_positions(code, TokenType::Keyword), Ok(vec![]));
+        // keywords only start statements
+        assert_eq!(all_token_positions("{{ version }}{{ if end }}{{ end }}", TokenType::Keyword), Ok(vec![16, 28]));
+        assert_eq!(all_token_positions("{{ end }}{{ first | stop }}", TokenType::Keyword), Ok(vec![]));
+        assert_eq!(all_token_positions("{{ first", TokenType::Name).unwrap_err().reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+    }
+
+    #[test]
+    fn token_predicates() {
+        use TokenType::*;
+
+        // whitespace, code delimiter, value
+        let cases = [
+            (Int, false, false, true),
+            (Name, false, false, true),
+            (Literal, false, false, true),
+            (CodeBegin, false, true, false),
+            (CodeEnd, false, true, false),
+            (ExprBegin, false, false, false),
+            (ExprEnd, false, false, false),
+            (Quote, false, false, false),
+            (String, false, false, true),
+            (Space, true, false, false),
+            (NewLine, true, false, false),
+            (EscapeSymbol, false, false, false),
+            (FormatSymbol, false, false, false),
+            (Range, false, false, false),
+            (RangeBegin, false, false, false),
+            (RangeSep, false, false, false),
+            (RangeEnd, false, false, false),
+            (MacroDef, false, false, false),
+            (MacroExp, false, false, false),
+            (Pipe, false, false, false),
+            (FanOut, false, false, false),
+            (Keyword, false, false, false),
+            (Args, false, false, false),
+        ];
+
+        for (token_type, whitespace, delimiter, value) in cases {
+            let token = Token { token_type, first_char: 0, end_char: 0 };
+
+            assert_eq!(token_type.is_whitespace(), whitespace, "{:?}", token_type);
+            assert_eq!(token.is_whitespace(), whitespace, "{:?}", token_type);
+            assert_eq!(token.is_code_delimiter(), delimiter, "{:?}", token_type);
+            assert_eq!(token.is_value(), value, "{:?}", token_type);
+        }
+    }
+
+    #[test]
+    fn strip_comments_cases() {
+        let cases = [
+            ("# not a comment {{ first }}", "# not a comment {{ first }}"),
+            ("{{ first # comment }}", "{{ first }}"),
+            ("{{\n  # comment\n  first # comment\n}}", "{{\n  \n  first \n}}"),
+            ("{{\r\n  first # comment\r\n}}", "{{\r\n  first \r\n}}"),
+            ("{{ \"#not a comment\" # comment\n}}", "{{ \"#not a comment\" \n}}"),
+            ("{{ \"\\\"#not a comment\" }}", "{{ \"\\\"#not a comment\" }}"),
+            ("{{ first # comment }} # text {{ second }}", "{{ first }} # text {{ second }}"),
+            ("\\{\\{ # text \\}\\} {{ first }}", "\\{\\{ # text \\}\\} {{ first }}"),
+            ("{{ first # \"unclosed\n second }}", "{{ first \n second }}"),
+        ];
+
+        for (code, stripped) in cases {
+            assert_eq!(strip_comments(code), stripped, "{:?}", code);
+        }
+
+        // braces are text with other delimiters
+        let code = "fn f() { a # b }\n<< first # comment\n >><< second # comment >>";
+        assert_eq!(strip_comments_with_options(code, &ANGLE), "fn f() { a # b }\n<< first \n >><< second >>");
+        assert_eq!(render(&strip_comments_with_options(code, &ANGLE), &ANGLE), "fn f() { a # b }\nfirst argsecond arg");
+    }
+
+    #[test]
+    fn macro_library() {
+        let library = "{{\n  # wraps the value into a paragraph\n  @p \"<p>$(_)</p>\"\n  @item \"$(_item_)\"\n    | ?p\n}}\n{{ @hello \"hello\" }}\n";
+        let macros = extract_macros(library).unwrap();
+
+        assert_eq!(macros, HashMap::from([
+            ("p".to_string(), "\"<p>$(_)</p>\"".to_string()),
+            ("item".to_string(), "\"$(_item_)\"\n    | ?p".to_string()),
+            ("hello".to_string(), "\"hello\"".to_string()),
+        ]));
+
+        let inlined = inline_macros("{{ ARGS[0:2] | ?item }}\n{{ first | ?p }}", &macros).unwrap();
+
+        assert_eq!(render(&inlined, &DEFAULT_OPTIONS), "<p>first element</p><p>second element</p>\n<p>first arg</p>");
+    }
+
+    #[test]
+    fn inlined_error_positions() {
+        let macros = HashMap::from([
+            ("item".to_string(), "\"$(_item_)\"\n    | ?p".to_string()),
+            ("p".to_string(), "\"<p>$(_)</p>\"".to_string()),
+        ]);
+        let error = |template: &str| {
+            let inlined = inline_macros_mapped(template, &macros).unwrap();
+            let err = ast_with_options(inlined.code(), &DEFAULT_OPTIONS)
+                .and_then(|nodes| gen_ir(inlined.code(), &nodes, NO_OPT))
+                .unwrap_err();
+
+            inlined.map_error(err)
+        };
+
+        // positions point into the template, the multiline body of item doesn't shift lines
+        let template = "{{ ARGS[:] | ?item }}\n{{ first | ?missing }}";
+        let err = error(template);
+        assert_eq!(err.reason, ErrorReason::UndefinedMacro { name: "missing".into() });
+        assert_eq!(err.first_char, template.find("?missing").unwrap());
+        assert_eq!(err.position_in_line(template), (2, 11));
+
+        // errors in a body point at the start of the template, the expansion site stays in the template
+        let err = error("text {{ ?p }}");
+        assert_eq!(err.reason, ErrorReason::UndefinedVar { name: "_".into() });
+        assert_eq!((err.first_char, err.expanded_at), (0, Some((8, 10))));
+        assert!(matches!(error("{{ @p \"\" }}").reason, ErrorReason::MacroRedefinition { first_defined: 0, .. }));
+    }
+
+    #[test]
+    fn macro_library_filters_and_fan_outs() {
+        let library = "{{ @short truncate(3) | \"$(_)…\" @both || \"$(_)\" || ?short }}";
+        let macros = extract_macros(library).unwrap();
+
+        assert_eq!(macros, HashMap::from([
+            ("short".to_string(), "truncate(3) | \"$(_)…\"".to_string()),
+            ("both".to_string(), "|| \"$(_)\" || ?short".to_string()),
+        ]));
+
+        let inlined = inline_macros("{{ first | ?both }}", &macros).unwrap();
+
+        assert_eq!(render(&inlined, &DEFAULT_OPTIONS), "first argfir…");
+    }
+
+    #[test]
+    fn macro_library_loops() {
+        let library = "{{ @list ARGS#pad2[1:] | \"$(_index_) $(_item_);\" @all ARGS + PHONES[4:] | \"$(_item_)\" @none ARGS[:0] | \"-\" }}";
+        let macros = extract_macros(library).unwrap();
+
+        assert_eq!(macros, HashMap::from([
+            ("list".to_string(), "ARGS#pad2[1:] | \"$(_index_) $(_item_);\"".to_string()),
+            ("all".to_string(), "ARGS + PHONES[4:] | \"$(_item_)\"".to_string()),
+            ("none".to_string(), "ARGS[:0] | \"-\"".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn import_macros() {
+        let libraries = HashMap::from([
+            ("html.pipa", "{{\n  @p \"<p>$(_)</p>\"\n  @item \"$(_item_)\" | ?p\n}}\n"),
+            ("short.pipa", "{{ @short truncate(3) }}"),
+            ("other.pipa", "{{ @p \"$(_)\" }}"),
+            ("text.pipa", "{{ @short truncate(3) }} text"),
+        ]);
+        let resolve = |code| resolve_imports(code, |path| libraries.get(path).map(|s| s.to_string()));
+
+        let inlined = resolve("{{ @import \"html.pipa\"\n   @import \"short.pipa\" }}{{ first | ?short | ?p }}\n{{ ARGS[1:] | ?item }}").unwrap();
+        assert_eq!(render(inlined.code(), &DEFAULT_OPTIONS), "<p>fir</p>\n<p>second element</p><p>third element</p>");
+        // templates without imports are kept as is
+        assert_eq!(resolve("text {{ first }}").unwrap().code(), "text {{ first }}");
+
+        let reason = |code| resolve(code).unwrap_err().reason;
+
+        assert_eq!(reason("{{ @import \"missing.pipa\" }}"), ErrorReason::ImportError { path: "missing.pipa".into() });
+        assert_eq!(reason("{{ @import \"text.pipa\" }}"), ErrorReason::SyntaxError { expected: vec![TokenType::MacroDef] });
+        assert_eq!(reason("{{ @import first }}"), ErrorReason::SyntaxError { expected: vec![TokenType::String] });
+        assert_eq!(
+            reason("{{ @import \"html.pipa\" }}\n{{ @import \"other.pipa\" }}"),
+            ErrorReason::MacroRedefinition { name: "p".into(), first_defined: 3 }
+        );
+        // imported macros can't be redefined by the template, the first definition is the import
+        let inlined = resolve("{{ @import \"short.pipa\" @short \"\" }}").unwrap();
+        let err = inlined.map_error(ast_with_options(inlined.code(), &DEFAULT_OPTIONS).unwrap_err());
+        assert_eq!(err.reason, ErrorReason::MacroRedefinition { name: "short".into(), first_defined: 3 });
+        assert_eq!(err.first_char, 24);
+    }
+
+    #[test]
+    fn import_error_positions() {
+        let libraries = HashMap::from([
+            ("html.pipa", "{{\n  @p \"<p>$(_)</p>\"\n  @item \"$(_item_)\"\n    | ?p\n}}\n"),
+            ("short.pipa", "{{ @short truncate(1) }}"),
+        ]);
+        let error = |template: &str| {
+            let inlined = resolve_imports(template, |path| libraries.get(path).map(|s| s.to_string())).unwrap();
+            let err = ast_with_options(inlined.code(), &DEFAULT_OPTIONS)
+                .and_then(|nodes| gen_ir(inlined.code(), &nodes, NO_OPT))
+                .unwrap_err();
+
+            inlined.map_error(err)
+        };
+
+        let template = "{{ @import \"html.pipa\" }}\n{{ ARGS[:] | ?item }}\n{{ first | ?missing }}";
+        let err = error(template);
+        assert_eq!(err.reason, ErrorReason::UndefinedMacro { name: "missing".into() });
+        assert_eq!(err.position_in_line(template), (3, 11));
+
+        // an error in an imported body points at the import, the expansion site stays in the template
+        let template = "text\n{{ @import \"short.pipa\" }}{{ ?short }}";
+        let err = error(template);
+        assert_eq!(err.reason, ErrorReason::PipeNoParent { after_literal: false });
+        assert_eq!(err.first_char, 8);
+        assert_eq!(err.expanded_at, Some((34, 40)));
+    }
+
+    #[test]
+    fn rename() {
+        let rename = |code, old, new| rename_var(code, old, new).unwrap();
+
+        assert_eq!(rename("text {{ first }} {{ second }}", "first", "title"), "text {{ title }} {{ second }}");
+        assert_eq!(rename("{{ \"$(first)-$(first)$(firsts)\" }}", "first", "title"), "{{ \"$(title)-$(title)$(firsts)\" }}");
+        assert_eq!(rename("{{ first[1:3] | \"$(_)$(first)\" }}", "first", "title"), "{{ title[1:3] | \"$(_)$(title)\" }}");
+        assert_eq!(rename("{{ ARGS#pad2[:] | \"$(_item_)\" }}", "ARGS", "ITEMS"), "{{ ITEMS#pad2[:] | \"$(_item_)\" }}");
+        assert_eq!(rename("{{ zip(ARGS, PHONES) | \"$(_a_)\" }}", "PHONES", "TELS"), "{{ zip(ARGS, TELS) | \"$(_a_)\" }}");
+        assert_eq!(rename("{{ ARGS + PHONES[:] | \"$(_item_)\" }}", "PHONES", "TELS"), "{{ ARGS + TELS[:] | \"$(_item_)\" }}");
+        // keywords, macros and filters with the same name are kept
+        assert_eq!(
+            rename("{{ @on \"$(on)\" }}{{ if on }}{{ ?on }}{{ end }}", "on", "enabled"),
+            "{{ @on \"$(enabled)\" }}{{ if enabled }}{{ ?on }}{{ end }}"
+        );
+        assert_eq!(rename("{{ first | truncate(3) }}", "truncate", "cut"), "{{ first | truncate(3) }}");
+
+        assert_eq!(rename_var("{{ first }}", "first", "имя").unwrap_err().reason, ErrorReason::NameError);
+        assert_eq!(rename_var("{{ first }}", "first", "if").unwrap_err().reason, ErrorReason::NameError);
+        assert_eq!(rename_var("{{ first }}", "first", "").unwrap_err().reason, ErrorReason::NameError);
+        assert_eq!(rename_var("{{ first", "first", "title").unwrap_err().reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] });
+    }
+
+    #[test]
+    fn rename_macros() {
+        let rename = |code, old, new| rename_macro(code, old, new).unwrap();
+
+        assert_eq!(rename("{{ @p \"<p>$(_)</p>\" }}{{ first | ?p }}", "p", "para"), "{{ @para \"<p>$(_)</p>\" }}{{ first | ?para }}");
+        assert_eq!(
+            rename("{{ @p \"<p>$(_)</p>\"\n@both ?p || ?p }}{{ first | ?p }}{{ ?p }}{{ second | ?both }}", "p", "para"),
+            "{{ @para \"<p>$(_)</p>\"\n@both ?para || ?para }}{{ first | ?para }}{{ ?para }}{{ second | ?both }}"
+        );
+        // vars with the same name are kept
+        assert_eq!(rename("{{ @first \"$(first)\" }}{{ first }}{{ ?first }}", "first", "f"), "{{ @f \"$(first)\" }}{{ first }}{{ ?f }}");
+
+        let code = "{{ @p \"$(_)\" @h \"$(_)\" }}";
+        assert_eq!(rename_macro(code, "missing", "m").unwrap_err().reason, ErrorReason::UndefinedMacro { name: "missing".into() });
+        assert_eq!(rename_macro(code, "p", "h").unwrap_err().reason, ErrorReason::MacroRedefinition { name: "h".into(), first_defined: 13 });
+        assert_eq!(rename_macro(code, "p", "new name").unwrap_err().reason, ErrorReason::NameError);
+    }
+
+    #[test]
+    fn macro_library_errors() {
+        let reason = |code| extract_macros(code).unwrap_err().reason;
+
+        assert_eq!(reason("{{ @p \"$(_)\" }} text"), ErrorReason::SyntaxError { expected: vec![TokenType::MacroDef] });
+        assert_eq!(reason("{{ @p \"$(_)\" first }}"), ErrorReason::SyntaxError { expected: vec![TokenType::MacroDef] });
+        assert_eq!(reason("{{ @p \"$(_)\" @p \"\" }}"), ErrorReason::MacroRedefinition { name: "p".into(), first_defined: 3 });
+        assert_eq!(
+            inline_macros("", &HashMap::from([("not valid".to_string(), "\"\"".to_string())])).unwrap_err().reason,
+            ErrorReason::NameError
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::io::Write;
+use std::sync::Arc;
+use crate::syntax::ast;
+use crate::ir::{gen_ir, intern_strings, Op};
+use crate::vm::{Vm, VmError, StringVars, ArrayVars};
+use crate::error::CompileError;
+use crate::analysis::{OptOptions, FULL_OPT};
+
+
+/// The default template has no ops and renders nothing, e.g. a placeholder in a map of templates.
+///
+/// A template is immutable after compilation and is `Send + Sync`, so threads can render the same one
+/// concurrently, each with its own `Vm`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Template {
+    ir: Vec<Op>,
+    strings: Vec<Arc<str>>,
+}
+
+impl Template {
+    pub fn compile(code: &str, opt: OptOptions) -> Result<Self, CompileError> {
+        let nodes = ast(code)?;
+        let (ir, strings) = intern_strings(gen_ir(code, &nodes, opt)?);
+
+        Ok(Self { ir, strings })
+    }
+
+    /// Literals are interned, so the program must be run with `Vm::with_strings(template.strings())`
+    pub fn ir(&self) -> &[Op] {
+        &self.ir
+    }
+
+    /// Table of unique literals read by `PutInterned` ops
+    pub fn strings(&self) -> &[Arc<str>] {
+        &self.strings
+    }
+
+    /// Appends the program of `other`, so it's rendered right after this one, e.g. a header and a body.
+    /// Jumps of `other` are moved by the length of this program, its strings by the length of the table.
+    pub fn concat(mut self, other: Template) -> Template {
+        let offset = self.ir.len();
+        let strings_offset = self.strings.len() as u32;
+
+        self.ir.extend(other.ir.into_iter().map(|mut op| {
+            if let Some(op_index) = op.jump_target_mut() {
+                *op_index += offset;
+            }
+            if let Op::PutInterned { index } = &mut op {
+                *index += strings_offset;
+            }
+
+            op
+        }));
+        self.strings.extend(other.strings);
+
+        self
+    }
+
+    pub fn render(&self, vars: &StringVars, arrays: &ArrayVars) -> Result<String, VmError> {
+        let bytes = render_to_bytes(self, vars, arrays)?;
+
+        // SAFETY: the vm writes only the contents of strings
+        Ok(unsafe { render_to_string_unchecked(bytes) })
+    }
+}
+
+// keeps Template shareable between threads, a field that isn't fails the build here
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Template>();
+};
+
+pub fn render_to_bytes(template: &Template, vars: &StringVars, arrays: &ArrayVars) -> Result<Vec<u8>, VmError> {
+    let mut out = Vec::new();
+    let mut vm = Vm::new(vars, arrays).with_strings(&template.strings);
+
+    vm.run(&mut out, &template.ir)?;
+
+    Ok(out)
+}
+
+/// Errors of the one-shot functions that compile and render a template
+#[derive(Debug, PartialEq, Clone)]
+pub enum RenderError {
+    Compile(CompileError),
+    Vm(VmError),
+}
+
+impl From<CompileError> for RenderError {
+    fn from(err: CompileError) -> Self {
+        RenderError::Compile(err)
+    }
+}
+
+impl From<VmError> for RenderError {
+    fn from(err: VmError) -> Self {
+        RenderError::Vm(err)
+    }
+}
+
+/// Compiles the template with full optimizations and renders it straight into `w`, e.g. a socket.
+/// Returns the number of bytes written.
+pub fn render_to_writer(code: &str, vars: &StringVars, arrays: &ArrayVars, w: &mut impl Write) -> Result<u64, RenderError> {
+    let template = Template::compile(code, FULL_OPT)?;
+    let written = Vm::new(vars, arrays).with_strings(&template.strings).run(w, &template.ir)?;
+
+    Ok(written)
+}
+
+/// Converts the output of `render_to_bytes` without validation.
+///
+/// # Safety
+///
+/// `bytes` must be valid UTF-8. Output of the vm always is, since templates are built from strings.
+pub unsafe fn render_to_string_unchecked(bytes: Vec<u8>) -> String {
+    unsafe { String::from_utf8_unchecked(bytes) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use crate::template::{Template, RenderError, render_to_bytes, render_to_string_unchecked, render_to_writer};
+    use crate::error::ErrorReason;
+    use crate::syntax::TokenType;
+    use crate::analysis::FULL_OPT;
+    use crate::syntax::ast;
+    use crate::ir::{gen_ir, Op};
+    use crate::vm::{Vm, VmError};
+    use crate::utils::{VARS, ARRAYS};
+
+    #[test]
+    fn render_bytes() {
+        let template = Template::compile("<p>{{ fifth }}</p>{{ UTF[:1] | \"$(_item_)\" }}", FULL_OPT).unwrap();
+        let bytes = render_to_bytes(&template, &VARS, &ARRAYS).unwrap();
+
+        assert_eq!(bytes, "<p>第五の議論</p>первый".as_bytes());
+        assert_eq!(unsafe { render_to_string_unchecked(bytes) }, "<p>第五の議論</p>первый");
+        assert_eq!(template.render(&VARS, &ARRAYS).unwrap(), "<p>第五の議論</p>первый");
+    }
+
+    #[test]
+    fn concat() {
+        let header = Template::compile("<h1>{{ first }}</h1>{{ ARGS[1:] | \"<p>$(_item_)</p>\" }}", FULL_OPT).unwrap();
+        let body = Template::compile("{{ if on }}on{{ end }}{{ PHONES[:2] | \"$(_index_):$(_item_) \" }}{{ second }}", FULL_OPT).unwrap();
+        let mut out = Vec::new();
+
+        Vm::new(&VARS, &ARRAYS).with_strings(header.strings()).run(&mut out, header.ir()).unwrap();
+        Vm::new(&VARS, &ARRAYS).with_strings(body.strings()).run(&mut out, body.ir()).unwrap();
+
+        let page = header.concat(body);
+
+        assert_eq!(page.render(&VARS, &ARRAYS).unwrap(), String::from_utf8(out).unwrap());
+        assert_eq!(
+            page.render(&VARS, &ARRAYS).unwrap(),
+            "<h1>first arg</h1><p>second element</p><p>third element</p>0:555-123-4567 1:555-987-6543 second arg"
+        );
+    }
+
+    #[test]
+    fn render_writer() {
+        let code = "<h1>{{ first }}</h1>{{ ARGS[:] | \"<p>$(_index_) $(_item_)</p>\" }}";
+        let mut out = Vec::new();
+
+        let written = render_to_writer(code, &VARS, &ARRAYS, &mut out).unwrap();
+        let expected = Template::compile(code, FULL_OPT).unwrap().render(&VARS, &ARRAYS).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(written, expected.len() as u64);
+
+        match render_to_writer("{{ first", &VARS, &ARRAYS, &mut Vec::new()) {
+            Err(RenderError::Compile(err)) => assert_eq!(err.reason, ErrorReason::SyntaxError { expected: vec![TokenType::CodeEnd] }),
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_is_empty() {
+        let template = Template::default();
+
+        assert!(template.ir().is_empty());
+        assert_eq!(template.render(&VARS, &ARRAYS), Ok(String::new()));
+        assert_eq!(Template::compile("", FULL_OPT).unwrap().render(&VARS, &ARRAYS), template.render(&VARS, &ARRAYS));
+    }
+
+    #[test]
+    fn interned_strings() {
+        let code = "<ul>{{ ARGS[:] | \"<li>$(_item_)</li>\" }}</ul><ul>{{ PHONES[:] | \"<li>$(_item_)</li>\" }}</ul><ul></ul>";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let template = Template::compile(code, FULL_OPT).unwrap();
+        let mut out = Vec::new();
+
+        Vm::new(&VARS, &ARRAYS).run(&mut out, &ir).unwrap();
+        assert_eq!(template.render(&VARS, &ARRAYS).unwrap(), String::from_utf8(out).unwrap());
+
+        // every literal is stored once
+        let literals: usize = ir.iter().map(|op| match op {
+            Op::PutStr { value } => value.len(),
+            _ => 0,
+        }).sum();
+        let interned: usize = template.strings().iter().map(|s| s.len()).sum();
+
+        assert_eq!(template.strings().len(), 5);
+        assert!(interned < literals);
+        assert!(!template.ir().iter().any(|op| matches!(op, Op::PutStr { .. })));
+        // an op out of the table fails instead of panicking
+        assert_eq!(Vm::new(&VARS, &ARRAYS).run(&mut Vec::new(), template.ir()), Err(VmError::ArrayIndexOverflow));
+    }
+
+    #[test]
+    fn concurrent_render() {
+        let template = Template::compile("<h1>{{ first }}</h1>{{ ARGS[:] | \"<p>$(_index_) $(_item_)</p>\" }}", FULL_OPT).unwrap();
+
+        std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8).map(|i| {
+                let template = &template;
+
+                s.spawn(move || {
+                    let vars = BTreeMap::from([("first".to_string(), format!("thread {}", i))]);
+                    let arrays = BTreeMap::from([("ARGS".to_string(), vec![i.to_string(); i])]);
+
+                    (0..100).map(|_| template.render(&vars, &arrays).unwrap()).collect::<Vec<_>>()
+                })
+            }).collect();
+
+            for (i, t) in threads.into_iter().enumerate() {
+                let expected = format!("<h1>thread {}</h1>{}", i, (0..i).map(|n| format!("<p>{} {}</p>", n, i)).collect::<String>());
+
+                assert!(t.join().unwrap().iter().all(|out| *out == expected));
+            }
+        });
+    }
+}
@@ -1,7 +1,10 @@
 use std::sync::LazyLock;
 use std::collections::BTreeMap;
-use crate::vm::{StringVars, ArrayVars};
+use crate::vm::{Vm, VmError, StringVars, ArrayVars};
 use crate::error::{CompileError, ErrorReason};
+use crate::ir::{gen_ir, Op};
+use crate::syntax::ast;
+use crate::analysis::{NO_OPT, FULL_OPT};
 
 
 pub static VARS: LazyLock<StringVars> = LazyLock::new(|| {
@@ -44,6 +47,71 @@ pub fn err_reason<T>(r: Result<T, CompileError>) -> ErrorReason {
     }
 }
 
+fn to_vars(vars: &[(&str, &str)], arrays: &[(&str, &[&str])]) -> (StringVars, ArrayVars) {
+    let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    let arrays = arrays.iter().map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect())).collect();
+
+    (vars, arrays)
+}
+
+#[track_caller]
+fn compile(code: &str) -> [Vec<Op>; 2] {
+    let nodes = match ast(code) {
+        Ok(n) => n,
+        Err(e) => panic!("Failed to compile {:?}: {}", code, e),
+    };
+
+    [NO_OPT, FULL_OPT].map(|opt| match gen_ir(code, &nodes, opt) {
+        Ok(ir) => ir,
+        Err(e) => panic!("Failed to compile {:?} with {:?}: {}", code, opt, e),
+    })
+}
+
+fn run(ir: &[Op], vars: &StringVars, arrays: &ArrayVars) -> Result<String, VmError> {
+    let mut out = Vec::new();
+    let mut vm = Vm::new(vars, arrays);
+
+    vm.run(&mut out, ir)?;
+
+    Ok(String::from_utf8(out).expect("Output is not utf8"))
+}
+
+/// Renders `code` with and without optimizations and compares the output to `expected`
+#[track_caller]
+pub fn assert_renders_to(code: &str, vars: &[(&str, &str)], arrays: &[(&str, &[&str])], expected: &str) {
+    let (vars, arrays) = to_vars(vars, arrays);
+
+    for ir in compile(code) {
+        match run(&ir, &vars, &arrays) {
+            Ok(out) => assert_eq!(out, expected, "{:?}\n{:#?}", code, ir),
+            Err(e) => panic!("Failed to run {:?} {:?}\n{:#?}", code, e, ir),
+        }
+    }
+}
+
+/// Compiles `code` with and without optimizations and compares the error reason to `expected`
+#[track_caller]
+pub fn assert_compile_error(code: &str, expected: ErrorReason) {
+    let r = ast(code).and_then(|nodes| {
+        let no_opt = gen_ir(code, &nodes, NO_OPT).map(|_| ());
+
+        assert_eq!(no_opt, gen_ir(code, &nodes, FULL_OPT).map(|_| ()), "{:?}", code);
+        no_opt
+    });
+
+    assert_eq!(err_reason(r), expected, "{:?}", code);
+}
+
+/// Runs `code` with and without optimizations and compares the vm error to `expected`
+#[track_caller]
+pub fn assert_vm_error(code: &str, vars: &[(&str, &str)], arrays: &[(&str, &[&str])], expected: VmError) {
+    let (vars, arrays) = to_vars(vars, arrays);
+
+    for ir in compile(code) {
+        assert_eq!(run(&ir, &vars, &arrays), Err(expected.clone()), "{:?}\n{:#?}", code, ir);
+    }
+}
+
 #[macro_export]
 macro_rules! assert_matches {
     ($left:expr, $right:pat_param) => {
@@ -0,0 +1,104 @@
+use crate::syntax::{Node, InnerNode};
+use crate::filter::Filter;
+
+
+/// AST traversal. Every method visits the children by default,
+/// so a visitor overrides only the nodes it's interested in.
+pub trait Visitor {
+    /// Dispatches to the method of the node's kind, then visits the pipe.
+    /// Call `walk_node` when overriding it to keep the traversal going.
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+
+    fn visit_string(&mut self, _node: &Node, children: &[Node]) {
+        walk(children, self);
+    }
+
+    fn visit_int(&mut self, _node: &Node, _value: u64) {}
+
+    fn visit_array(&mut self, _node: &Node, _name: &str, _start: Option<u64>, _end: Option<u64>) {}
+
+    fn visit_literal(&mut self, _node: &Node) {}
+
+    fn visit_name(&mut self, _node: &Node, _start: Option<u64>, _end: Option<u64>) {}
+
+    fn visit_assert(&mut self, _node: &Node, _name: &str, _expected: &str) {}
+
+    fn visit_stop(&mut self, _node: &Node) {}
+
+    fn visit_filter(&mut self, _node: &Node, _filter: &Filter) {}
+
+    fn visit_fan_out(&mut self, _node: &Node, branches: &[Node]) {
+        walk(branches, self);
+    }
+
+    fn visit_array_concat(&mut self, _node: &Node, _names: &[String], _start: Option<u64>, _end: Option<u64>) {}
+
+    fn visit_zip(&mut self, _node: &Node, _a: &str, _b: &str) {}
+
+    fn visit_chars(&mut self, _node: &Node, _name: &str, _start: Option<u64>, _end: Option<u64>) {}
+
+    fn visit_condition(&mut self, _node: &Node, _name: &str, _negate: bool, body: &[Node]) {
+        walk(body, self);
+    }
+
+    fn visit_indent(&mut self, _node: &Node, _width: u64, body: &[Node]) {
+        walk(body, self);
+    }
+}
+
+pub fn walk<V: Visitor + ?Sized>(nodes: &[Node], visitor: &mut V) {
+    for node in nodes {
+        visitor.visit_node(node);
+    }
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match &*node.inner {
+        InnerNode::String { children } => visitor.visit_string(node, children),
+        InnerNode::Int { value } => visitor.visit_int(node, *value),
+        InnerNode::Array { name, start, end, .. } => visitor.visit_array(node, name, *start, *end),
+        InnerNode::Literal => visitor.visit_literal(node),
+        InnerNode::Name { start, end } => visitor.visit_name(node, *start, *end),
+        InnerNode::Assert { name, expected } => visitor.visit_assert(node, name, expected),
+        InnerNode::Stop => visitor.visit_stop(node),
+        InnerNode::Condition { name, negate, body } => visitor.visit_condition(node, name, *negate, body),
+        InnerNode::Indent { width, body } => visitor.visit_indent(node, *width, body),
+        InnerNode::Filter { filter } => visitor.visit_filter(node, filter),
+        InnerNode::FanOut { branches } => visitor.visit_fan_out(node, branches),
+        InnerNode::ArrayConcat { names, start, end, .. } => visitor.visit_array_concat(node, names, *start, *end),
+        InnerNode::Zip { a, b } => visitor.visit_zip(node, a, b),
+        InnerNode::Chars { name, start, end } => visitor.visit_chars(node, name, *start, *end),
+    }
+
+    walk(&node.children, visitor);
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::syntax::{Node, ast};
+    use crate::visit::{Visitor, walk};
+
+    struct NameCounter<'a> {
+        code: &'a str,
+        names: Vec<&'a str>,
+    }
+
+    impl<'a> Visitor for NameCounter<'a> {
+        fn visit_name(&mut self, node: &Node, _start: Option<u64>, _end: Option<u64>) {
+            self.names.push(node.as_str(self.code));
+        }
+    }
+
+    #[test]
+    fn count_names() {
+        let code = "{{ @m \"$(_)\" }}text {{ first second[0:2] | \"$(_) $(third)\" }}{{ ARGS[:] | \"$(_item_)\" | ?m 1 }}";
+        let mut counter = NameCounter { code, names: vec![] };
+
+        walk(&ast(code).unwrap(), &mut counter);
+
+        assert_eq!(counter.names, ["first", "second", "_", "third", "_item_", "_"]);
+    }
+}
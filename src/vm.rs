@@ -1,48 +1,554 @@
 use unicode_segmentation::UnicodeSegmentation;
-use std::collections::BTreeMap;
-use std::io::Write;
-use crate::ir::{Op, is_name_reserved};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::{fmt, str};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::ir::{Op, GenIrConfig, is_name_array, is_name_reserved};
 
 pub type StringVars = BTreeMap<String, String>;
 pub type ArrayVars = BTreeMap<String, Vec<String>>;
+pub type BoolVars = BTreeMap<String, bool>;
 
+static NO_BOOLS: BoolVars = BTreeMap::new();
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum VmError {
     EndOfProgram,
-    WriteError,
+    /// The writer failed, e.g. with `BrokenPipe` when the client is gone
+    WriteError(io::ErrorKind),
     EmptyStack,
     UndefinedScopeVar,
     ArrayIndexOverflow,
+    InvalidScopeVarName {
+        name: Box<str>,
+    },
+    AssertionFailed {
+        name: Box<str>,
+        expected: Box<str>,
+        got: Box<str>,
+    },
+    /// `{{ stop }}` halted, but a var of the same name was passed, so the template probably meant to print it
+    ShadowedVar {
+        name: Box<str>,
+    },
+}
+
+impl VmError {
+    /// Stable code of the error, runtime errors start with `P1`
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmError::EndOfProgram => "P1001",
+            VmError::WriteError(_) => "P1002",
+            VmError::EmptyStack => "P1003",
+            VmError::UndefinedScopeVar => "P1004",
+            VmError::ArrayIndexOverflow => "P1005",
+            VmError::InvalidScopeVarName { .. } => "P1006",
+            VmError::AssertionFailed { .. } => "P1007",
+            VmError::ShadowedVar { .. } => "P1008",
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+
+        match self {
+            VmError::EndOfProgram => write!(f, "End of program"),
+            VmError::WriteError(kind) => write!(f, "Cannot write the output: {}", kind),
+            VmError::EmptyStack => write!(f, "Empty stack"),
+            VmError::UndefinedScopeVar => write!(f, "Undefined scope variable"),
+            VmError::ArrayIndexOverflow => write!(f, "Array index overflow"),
+            VmError::InvalidScopeVarName { name } => write!(f, "Invalid scope variable name '{}'", name),
+            VmError::AssertionFailed { name, expected, got } => {
+                write!(f, "Assertion failed: '{}' is '{}', expected '{}'", name, got, expected)
+            },
+            VmError::ShadowedVar { name } => {
+                write!(f, "Var '{}' is shadowed by the directive, print it with \"$({})\"", name, name)
+            },
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Escaping of variable values for the context the output is used in.
+/// Values are escaped when they are written, so pipes and filters work with the values as is.
+/// The result of a filter applied to text made of literals and vars is escaped as a whole.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Escape {
+    #[default]
+    None,
+    /// Contents of a JSON string: quotes, backslashes and control chars are escaped
+    Json,
+}
+
+impl Escape {
+    pub fn apply(self, value: &str) -> Cow<'_, str> {
+        match self {
+            Escape::None => Cow::Borrowed(value),
+            Escape::Json if !value.chars().any(|c| c == '"' || c == '\\' || c < ' ') => Cow::Borrowed(value),
+            Escape::Json => {
+                let mut output = String::with_capacity(value.len() + 2);
+
+                for c in value.chars() {
+                    match c {
+                        '"' => output.push_str("\\\""),
+                        '\\' => output.push_str("\\\\"),
+                        '\n' => output.push_str("\\n"),
+                        '\r' => output.push_str("\\r"),
+                        '\t' => output.push_str("\\t"),
+                        '\u{8}' => output.push_str("\\b"),
+                        '\u{c}' => output.push_str("\\f"),
+                        c if c < ' ' => output.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => output.push(c),
+                    }
+                }
+
+                Cow::Owned(output)
+            },
+        }
+    }
+}
+
+/// A var passed by the host that templates can't reference as expected
+#[derive(Debug, PartialEq, Clone)]
+pub struct VarNameError {
+    pub name: String,
+    pub reason: String,
+}
+
+/// Warns about string vars with reserved names, they are shadowed by scope vars
+pub fn validate_var_names(vars: &StringVars) -> Vec<VarNameError> {
+    vars.keys()
+        .filter(|name| is_name_reserved(name))
+        .map(|name| VarNameError { name: name.clone(), reason: "names starting with '_' are reserved for scope vars".into() })
+        .collect()
+}
+
+/// Warns about arrays with names that aren't uppercase, templates parse them as string vars
+pub fn validate_array_names(arrays: &ArrayVars) -> Vec<VarNameError> {
+    arrays.keys()
+        .filter(|name| !is_name_array(name))
+        .map(|name| VarNameError { name: name.clone(), reason: "array names must be uppercase and longer than one char".into() })
+        .collect()
+}
+
+// ranges beyond usize::MAX can't fit in memory anyway, so they are clamped
+fn to_index(value: u64) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+struct CallbackWriter<'f, F: FnMut(&str)> {
+    callback: &'f mut F,
+}
+
+impl<F: FnMut(&str)> Write for CallbackWriter<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Flush writes the whole output at once, so buf is never a part of a char
+        let s = str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        (self.callback)(s);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum VarStore<'a> {
+    // copied on the first mutation
+    Local(Cow<'a, StringVars>),
+    // the base is shared between vms, e.g. by requests of a server, overrides are checked first
+    Shared { base: Arc<StringVars>, overrides: StringVars },
+}
+
+impl VarStore<'_> {
+    fn get(&self, name: &str) -> Option<&String> {
+        match self {
+            VarStore::Local(vars) => vars.get(name),
+            VarStore::Shared { base, overrides } => overrides.get(name).or_else(|| base.get(name)),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut StringVars {
+        match self {
+            VarStore::Local(vars) => vars.to_mut(),
+            VarStore::Shared { overrides, .. } => overrides,
+        }
+    }
+
+    // grapheme offsets of every var that can be sliced, overrides replace the offsets of the base
+    fn segment(&self, is_reserved: impl Fn(&str) -> bool) -> HashMap<Arc<str>, Vec<usize>> {
+        let vars: Vec<(&String, &String)> = match self {
+            VarStore::Local(vars) => vars.iter().collect(),
+            VarStore::Shared { base, overrides } => base.iter().chain(overrides.iter()).collect(),
+        };
+
+        vars.into_iter()
+            .filter(|(name, _)| !is_reserved(name))
+            .map(|(name, value)| (name.as_str().into(), grapheme_offsets(value)))
+            .collect()
+    }
+}
+
+// byte offsets of the graphemes followed by the length, so a range of graphemes is sliced by bytes
+fn grapheme_offsets(s: &str) -> Vec<usize> {
+    s.grapheme_indices(true).map(|(i, _)| i).chain([s.len()]).collect()
+}
+
+// keeps the end of the output, the head is dropped only when the buffer grows well past the limit,
+// so it isn't shifted on every write
+struct TailWriter {
+    buffer: String,
+    max_bytes: usize,
+}
+
+impl TailWriter {
+    // a grapheme cut by the dropped head is kept whole, it is removed by `tail` later
+    const MARGIN: usize = 64;
+
+    fn tail(mut self) -> String {
+        let start = self.buffer.len().saturating_sub(self.max_bytes);
+        let start = self.buffer.grapheme_indices(true)
+            .map(|(i, _)| i)
+            .find(|i| *i >= start)
+            .unwrap_or(self.buffer.len());
+
+        self.buffer.drain(..start);
+        self.buffer
+    }
+}
+
+impl Write for TailWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Flush writes the whole output at once, so buf is never a part of a char
+        let s = str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.buffer.push_str(s);
+
+        let keep = self.max_bytes.saturating_add(Self::MARGIN);
+
+        if self.buffer.len() > keep.saturating_mul(2) {
+            let mut cut = self.buffer.len() - keep;
+
+            while !self.buffer.is_char_boundary(cut) {
+                cut -= 1;
+            }
+
+            self.buffer.drain(..cut);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Output of a program split at flushes, see `Vm::run_chunks`
+pub struct Chunks<'v, 'a, 'p> {
+    vm: &'v mut Vm<'a>,
+    program: &'p [Op],
+    done: bool,
+}
+
+impl Iterator for Chunks<'_, '_, '_> {
+    type Item = Result<String, VmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = Vec::new();
+
+        while !self.done {
+            match self.vm.step(&mut out, self.program) {
+                Ok(()) if !out.is_empty() => {
+                    return Some(Ok(String::from_utf8(out).expect("The vm writes only strings")));
+                },
+                Ok(()) => {},
+                // `stop` flushes the output before the end
+                Err(VmError::EndOfProgram) if !out.is_empty() => {
+                    self.done = true;
+                    return Some(Ok(String::from_utf8(out).expect("The vm writes only strings")));
+                },
+                Err(VmError::EndOfProgram) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+
+        None
+    }
+}
+
+struct IterationHook<'a>(Box<dyn FnMut(usize) + 'a>);
+
+// callbacks can't be printed, only their presence is shown
+impl fmt::Debug for IterationHook<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IterationHook")
+    }
+}
+
+struct ScopeHook<'a>(Box<dyn FnMut(&StringVars) + 'a>);
+
+impl fmt::Debug for ScopeHook<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ScopeHook")
+    }
+}
+
+/// Counters of a cache, see `Vm::grapheme_cache_stats`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// text on the stack, values of vars are escaped only when they are written
+#[derive(Debug, Clone)]
+enum Value {
+    // template text, counters and scope vars set by the host are written as is
+    Literal(Arc<str>),
+    Var(Arc<str>),
+    // literals and vars joined by Collapse, the vars of `output` are escaped
+    Mixed {
+        raw: Arc<str>,
+        output: Arc<str>,
+    },
+}
+
+impl Value {
+    fn raw(&self) -> &Arc<str> {
+        match self {
+            Value::Literal(s) | Value::Var(s) | Value::Mixed { raw: s, .. } => s,
+        }
+    }
+
+    fn output(&self, escape: Escape) -> Cow<'_, str> {
+        match self {
+            Value::Literal(s) | Value::Mixed { output: s, .. } => Cow::Borrowed(s),
+            Value::Var(s) => escape.apply(s),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Vm<'a> {
-    counter: usize,
+    counter: u64,
+    // the counter set by the last SetCounter, iterations of the current loop are counted from it
+    loop_start: u64,
+    max_iterations: Option<u64>,
     pc: usize,
-    stack: Vec<String>,
-    vars: &'a StringVars,
-    arrays: &'a ArrayVars,
+    stack: Vec<Value>,
+    // table of PutInterned ops
+    strings: &'a [Arc<str>],
+    buffer: String,
+    bytes_written: u64,
+    // spaces prefixed to every line of the output, see `PushIndent`
+    indent: usize,
+    // the output is empty or ends with a line break
+    at_line_start: bool,
+    vars: VarStore<'a>,
+    arrays: Cow<'a, ArrayVars>,
+    bools: &'a BoolVars,
+    // arrays staged by load ops: graphemes of `chars` loops and concatenations
+    staged: ArrayVars,
+    // zip loops only need the length of the shorter array, the items are read from both arrays
+    staged_lens: BTreeMap<String, usize>,
     scope: StringVars,
+    // kinds of the scope vars set by PutScopeVar, the others are literals
+    scope_values: HashMap<String, Value>,
+    // computed when vars are bound, so slices in loops don't re-segment them
+    grapheme_offsets: HashMap<Arc<str>, Vec<usize>>,
+    grapheme_cache: CacheStats,
+    escape: Escape,
+    naming: GenIrConfig,
+    profile: Option<BTreeMap<&'static str, Duration>>,
+    on_iteration: Option<IterationHook<'a>>,
+    on_scope_destroy: Option<ScopeHook<'a>>,
 }
 
 impl<'a> Vm<'a> {
     pub fn new(vars: &'a StringVars, arrays: &'a ArrayVars) -> Vm<'a> {
+        Self::with_store(VarStore::Local(Cow::Borrowed(vars)), arrays)
+    }
+
+    /// Reads string vars from `overrides` first, then from `base`, so a large base can be shared
+    /// between threads without cloning.
+    pub fn with_shared_base(base: Arc<StringVars>, overrides: StringVars, arrays: &'a ArrayVars) -> Vm<'a> {
+        Self::with_store(VarStore::Shared { base, overrides }, arrays)
+    }
+
+    fn with_store(vars: VarStore<'a>, arrays: &'a ArrayVars) -> Vm<'a> {
+        let naming = GenIrConfig::default();
+        let grapheme_offsets = vars.segment(naming.is_reserved);
+
         Self {
             counter: 0,
+            loop_start: 0,
+            max_iterations: None,
             pc: 0,
             stack: Vec::with_capacity(15),
+            strings: &[],
+            buffer: String::new(),
+            bytes_written: 0,
+            indent: 0,
+            at_line_start: true,
             vars,
             scope: BTreeMap::new(),
-            arrays,
+            scope_values: HashMap::new(),
+            arrays: Cow::Borrowed(arrays),
+            bools: &NO_BOOLS,
+            staged: BTreeMap::new(),
+            staged_lens: BTreeMap::new(),
+            grapheme_offsets,
+            grapheme_cache: CacheStats::default(),
+            escape: Escape::None,
+            naming,
+            profile: None,
+            on_iteration: None,
+            on_scope_destroy: None,
         }
     }
 
 
+    /// Binds bool vars used by `if` and `unless`. When referenced as strings,
+    /// they are rendered as "true" or "false" unless a string var has the same name.
+    pub fn with_bools(mut self, bools: &'a BoolVars) -> Self {
+        self.bools = bools;
+        self
+    }
+
+    /// Escapes values of string and array vars, scope vars set by the host are used as is
+    pub fn with_escape(mut self, escape: Escape) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Classifies vars by the conventions the template was compiled with, see `gen_ir_with_config`.
+    /// Names starting with '_' stay reserved, the generator uses them for loop and pipe vars.
+    pub fn with_naming(mut self, naming: GenIrConfig) -> Self {
+        self.naming = naming;
+        self.grapheme_offsets = self.vars.segment(|name| is_name_reserved(name) || (naming.is_reserved)(name));
+        self
+    }
+
+    fn is_reserved(&self, name: &str) -> bool {
+        is_name_reserved(name) || (self.naming.is_reserved)(name)
+    }
+
+    /// Binds the table of strings read by `PutInterned` ops, see `intern_strings`
+    pub fn with_strings(mut self, strings: &'a [Arc<str>]) -> Self {
+        self.strings = strings;
+        self
+    }
+
+    /// Stops every array loop after `n` iterations, e.g. to show at most 100 rows of a table
+    pub fn with_max_iterations(mut self, n: u64) -> Self {
+        self.max_iterations = Some(n);
+        self
+    }
+
     pub fn clear_state(&mut self) {
         self.pc = 0;
         self.stack.clear();
         self.scope.clear();
+        self.scope_values.clear();
+        self.staged.clear();
+        self.staged_lens.clear();
+        self.bytes_written = 0;
+        self.indent = 0;
+        self.at_line_start = true;
+    }
+
+    /// String vars, changes are seen by the next run. Borrowed vars are copied on the first call,
+    /// a vm with a shared base returns its overrides.
+    pub fn vars_mut(&mut self) -> &mut StringVars {
+        // the cached offsets may be stale after the change, vars are segmented again on their next slice
+        self.grapheme_offsets.clear();
+        self.vars.get_mut()
+    }
+
+    /// Sets a string var, seen by the next run. Unlike `vars_mut`, the offsets of the other vars stay cached.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        if !self.is_reserved(name) {
+            self.grapheme_offsets.insert(name.into(), grapheme_offsets(value));
+        }
+        self.vars.get_mut().insert(name.into(), value.into());
+    }
+
+    /// Array vars, changes are seen by the next run. Borrowed arrays are copied on the first call.
+    pub fn arrays_mut(&mut self) -> &mut ArrayVars {
+        self.arrays.to_mut()
+    }
+
+    /// Scope vars of the running program, mostly useful for tests. Unlike `inject_scope`, names aren't checked
+    /// and changes bypass the cleanup done by DestroyScope: entries set here stay until the next DestroyScope or `clear_state`.
+    pub fn scope_mut(&mut self) -> &mut StringVars {
+        &mut self.scope
+    }
+
+    /// Sets a scope variable, so a program reading e.g. `$(_)` can run without the pipe
+    /// that normally defines it. Normal execution manages the scope by itself.
+    pub fn set_scope_var(&mut self, name: &str, value: &str) {
+        self.scope.insert(name.into(), value.into());
+        self.scope_values.remove(name);
+    }
+
+    /// Merges host provided variables into the scope, overwriting existing ones.
+    /// Names must be reserved, otherwise nothing is merged.
+    /// Like any scope variable, they are removed by DestroyScope at the end of a loop iteration.
+    pub fn inject_scope(&mut self, extra: &StringVars) -> Result<(), VmError> {
+        if let Some(name) = extra.keys().find(|name| !self.is_reserved(name)) {
+            return Err(VmError::InvalidScopeVarName { name: name.as_str().into() });
+        }
+
+        for (name, value) in extra {
+            self.scope.insert(name.clone(), value.clone());
+            self.scope_values.remove(name);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `callback` with the index at the start of every loop iteration, e.g. to report progress of long renders
+    pub fn on_iteration(&mut self, callback: impl FnMut(usize) + 'a) {
+        self.on_iteration = Some(IterationHook(Box::new(callback)));
+    }
+
+    /// Calls `callback` with the scope vars right before a scope is destroyed, e.g. at the end of a loop body,
+    /// to find out why a scope var isn't visible where it's expected
+    pub fn on_scope_destroy(&mut self, callback: impl FnMut(&StringVars) + 'a) {
+        self.on_scope_destroy = Some(ScopeHook(Box::new(callback)));
+    }
+
+    fn start_iteration(&mut self) {
+        if let Some(IterationHook(callback)) = &mut self.on_iteration {
+            callback(to_index(self.counter));
+        }
+    }
+
+    /// Hits and misses of the cache of grapheme offsets used to slice string vars, since the vm was created.
+    /// Vars are segmented by `new` and `set_var`, after `vars_mut` on their next use. Scope vars aren't cached.
+    pub fn grapheme_cache_stats(&self) -> CacheStats {
+        self.grapheme_cache
+    }
+
+    /// Starts accumulating the time spent per op kind, see `profile`
+    pub fn enable_profiling(&mut self) {
+        self.profile.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Time spent per op kind since profiling was enabled, empty if it wasn't
+    pub fn profile(&self) -> BTreeMap<&'static str, Duration> {
+        self.profile.clone().unwrap_or_default()
     }
 
     pub fn dump_state(&self, w: &mut impl Write) -> std::io::Result<()> {
@@ -50,26 +556,52 @@ impl<'a> Vm<'a> {
     }
 
     fn get_string_var(&self, name: &str) -> Result<&str, VmError> {
-        if is_name_reserved(name) {
+        if self.is_reserved(name) {
             let var = self.scope.get(name).ok_or_else(|| VmError::UndefinedScopeVar)?;
             return Ok(var)
         } else {
-            match self.vars.get(name) {
-                Some(s) => Ok(s),
-                None => Ok("")
+            match (self.vars.get(name), self.bools.get(name)) {
+                (Some(s), _) => Ok(s),
+                (None, Some(true)) => Ok("true"),
+                (None, Some(false)) => Ok("false"),
+                (None, None) => Ok(""),
             }
         }
     }
 
 
+    // string vars are parsed as a fallback, anything but "true" is false
+    fn get_bool_var(&self, name: &str) -> bool {
+        match self.bools.get(name) {
+            Some(value) => *value,
+            None => self.vars.get(name).is_some_and(|v| v == "true"),
+        }
+    }
+
+    // vars that aren't arrays by the naming convention are never read as arrays, staged arrays are named by their ops
     fn get_array_var(&self, name: &str) -> &[String] {
-        match self.arrays.get(name) {
+        let array = self.arrays.get(name).filter(|_| (self.naming.is_array)(name));
+
+        match array.or_else(|| self.staged.get(name)) {
             Some(arr) => arr,
             None => &[],
         }
     }
 
-    pub fn run(&mut self, w: &mut impl Write, program: &[Op]) -> Result<(), VmError> {
+    fn get_array_len(&self, name: &str) -> usize {
+        match self.staged_lens.get(name) {
+            Some(len) => *len,
+            None => self.get_array_var(name).len(),
+        }
+    }
+
+    /// Runs the program, returns the number of bytes written since the last `clear_state`
+    pub fn run(&mut self, w: &mut impl Write, program: &[Op]) -> Result<u64, VmError> {
+        // empty templates compile to no ops, step would only report EndOfProgram
+        if program.is_empty() {
+            return Ok(self.bytes_written);
+        }
+
         loop {
             match self.step(w, program) {
                 Err(VmError::EndOfProgram) => break,
@@ -78,7 +610,38 @@ impl<'a> Vm<'a> {
             }
         }
 
-        Ok(())
+        Ok(self.bytes_written)
+    }
+
+    /// Continues the program from the current op, e.g. after running a part of it with `step`.
+    /// The stack, scope and counter are kept, so the output is the same as of a single run.
+    pub fn resume(&mut self, w: &mut impl Write, program: &[Op]) -> Result<u64, VmError> {
+        self.run(w, program)
+    }
+
+    /// Runs the program passing the output of every Flush to the callback,
+    /// for hosts where implementing Write is inconvenient
+    pub fn run_with_callback<F: FnMut(&str)>(&mut self, mut callback: F, program: &[Op]) -> Result<u64, VmError> {
+        let mut w = CallbackWriter { callback: &mut callback };
+
+        self.run(&mut w, program)
+    }
+
+    /// Runs the program lazily, a chunk is the output of a Flush that wrote something.
+    /// Flushes happen before loops and conditions, after every loop iteration and at the end,
+    /// so a server can stream the output while it's rendered. Iteration stops after an error.
+    pub fn run_chunks<'v, 'p>(&'v mut self, program: &'p [Op]) -> Chunks<'v, 'a, 'p> {
+        Chunks { vm: self, program, done: false }
+    }
+
+    /// Runs the program keeping only the last `max_bytes` of the output, e.g. the last lines of a log.
+    /// The output is cut at a grapheme boundary, so it can be a bit shorter.
+    pub fn run_tail(&mut self, program: &[Op], max_bytes: usize) -> Result<String, VmError> {
+        let mut w = TailWriter { buffer: String::new(), max_bytes };
+
+        self.run(&mut w, program)?;
+
+        Ok(w.tail())
     }
 
     pub fn step(&mut self, w: &mut impl Write, program: &[Op]) -> Result<(), VmError> {
@@ -86,84 +649,948 @@ impl<'a> Vm<'a> {
             return Err(VmError::EndOfProgram);
         }
 
-        match &program[self.pc] {
+        let op = &program[self.pc];
+
+        if self.profile.is_none() {
+            return self.exec(w, op);
+        }
+
+        let now = Instant::now();
+        let r = self.exec(w, op);
+
+        if let Some(profile) = &mut self.profile {
+            *profile.entry(op.kind()).or_default() += now.elapsed();
+        }
+
+        r
+    }
+
+    fn exec(&mut self, w: &mut impl Write, op: &Op) -> Result<(), VmError> {
+        match op {
             Op::PutStr { value } => {
-                self.stack.push(value.clone());
+                // literals are shared with the program, so loops don't copy them on every iteration
+                self.stack.push(Value::Literal(value.clone()));
+            },
+            Op::PutInterned { index } => {
+                let value = self.strings.get(*index as usize).ok_or(VmError::ArrayIndexOverflow)?;
+                self.stack.push(Value::Literal(value.clone()));
             },
             Op::Flush => {
-                for s in self.stack.drain(..) {
-                    write!(w, "{}", s).map_err(|_| VmError::WriteError)?;
-                }
+                self.flush(w)?;
             },
             Op::Collapse => {
-                let mut output = String::new();
-
-                for s in self.stack.drain(..) {
-                    output.push_str(&s);
-                }
+                let raw: String = self.stack.iter().map(|v| v.raw().as_ref()).collect();
+                let all_literals = self.stack.iter().all(|v| matches!(v, Value::Literal(_)));
+                let all_vars = !self.stack.is_empty() && self.stack.iter().all(|v| matches!(v, Value::Var(_)));
 
-                self.stack.push(output.into());
+                // without escaping the kind doesn't matter
+                let value = match (self.escape, all_literals, all_vars) {
+                    (Escape::None, ..) | (_, true, _) => Value::Literal(raw.into()),
+                    (_, _, true) => Value::Var(raw.into()),
+                    (escape, ..) => {
+                        let output: String = self.stack.iter().map(|v| v.output(escape)).collect();
+                        Value::Mixed { raw: raw.into(), output: output.into() }
+                    },
+                };
 
+                self.stack.clear();
+                self.stack.push(value);
             },
             Op::PutName { start, end, name } => {
-                let mut output = String::new();
-                let var = self.get_string_var(&name)?;
-                let graphemes: Vec<&str> = UnicodeSegmentation::graphemes(var, true).collect();
-                let mut start = start.unwrap_or(0);                
-                let end = std::cmp::min(var.len(), end.unwrap_or(graphemes.len()));                
-
-                while start < end {
-                    output.push_str(graphemes[start]);                    
-                    
-                    start += 1;
+                // scope vars change during execution, so they are not cached
+                let is_reserved = self.is_reserved(name);
+                if !is_reserved {
+                    match self.grapheme_offsets.contains_key(name) {
+                        true => self.grapheme_cache.hits += 1,
+                        false => {
+                            let offsets = grapheme_offsets(self.get_string_var(name)?);
+                            self.grapheme_cache.misses += 1;
+                            self.grapheme_offsets.insert(name.clone(), offsets);
+                        },
+                    }
                 }
 
-                self.stack.push(output.into());
+                let var = self.get_string_var(name)?;
+                let segmented;
+                let offsets = match self.grapheme_offsets.get(name) {
+                    Some(offsets) if !is_reserved => offsets,
+                    _ => {
+                        segmented = grapheme_offsets(var);
+                        &segmented
+                    },
+                };
+                let len = (offsets.len() - 1) as u64;
+                let end = std::cmp::min(len, end.unwrap_or(len));
+                let start = std::cmp::min(end, start.unwrap_or(0));
+                let output = &var[offsets[to_index(start)]..offsets[to_index(end)]];
+
+                let value = match is_reserved {
+                    true => match self.scope_values.get(name.as_ref()) {
+                        // changed by the host, e.g. through scope_mut
+                        Some(v) if v.raw().as_ref() != var => Value::Literal(output.into()),
+                        Some(v) if output.len() == var.len() => v.clone(),
+                        Some(Value::Literal(_)) | None => Value::Literal(output.into()),
+                        // a part of a mixed value is escaped as a whole
+                        Some(_) => Value::Var(output.into()),
+                    },
+                    false => Value::Var(output.into()),
+                };
+
+                self.stack.push(value);
             },
             Op::SetCounter { value } => {
                 self.counter = *value;
+                self.loop_start = *value;
             },
             Op::IncCounter => {
                 self.counter += 1;
             },
             Op::LoadCounter => {
-                self.stack.push(self.counter.to_string().into());
+                self.stack.push(Value::Literal(self.counter.to_string().into()));
+            },
+            Op::LoadCounterFmt { width, pad } => {
+                let counter = self.counter.to_string();
+                let padding = to_index(*width).saturating_sub(counter.len());
+                let mut output = String::with_capacity(padding + counter.len());
+
+                output.extend(std::iter::repeat_n(*pad, padding));
+                output.push_str(&counter);
+                self.stack.push(Value::Literal(output.into()));
             },
             Op::CmpCounterLessJmp { op_index, value, name } => {
-                let len = self.get_array_var(&name).len();
+                let len = self.get_array_len(name) as u64;
 
                 let value = match value {
                     Some(v) => std::cmp::min(*v, len),
                     None => len,
                 };
+                let value = match self.max_iterations {
+                    Some(max) => std::cmp::min(value, self.loop_start.saturating_add(max)),
+                    None => value,
+                };
 
                 if self.counter < value {
                     self.pc = *op_index;
+                    self.start_iteration();
                 }
             },
             Op::CmpArrayEmptyJmp { op_index, start, end, name } => {
-                let len = self.get_array_var(&name).len();
+                let len = self.get_array_len(name) as u64;
                 let end = std::cmp::min(len, end.unwrap_or(len));
 
-                if start.unwrap_or(0) >= end || end == 0 {
+                if start.unwrap_or(0) >= end || end == 0 || self.max_iterations == Some(0) {
                     self.pc = *op_index;
+                } else {
+                    self.start_iteration();
                 }
             },
             Op::LoadArrayItem { name } => {
                 let arr = self.get_array_var(&name);
-                let item = arr.get(self.counter).ok_or_else(|| VmError::ArrayIndexOverflow)?;
-                self.stack.push(item.clone());
+                let index = usize::try_from(self.counter).map_err(|_| VmError::ArrayIndexOverflow)?;
+                let item = arr.get(index).ok_or(VmError::ArrayIndexOverflow)?;
+                self.stack.push(Value::Var(item.as_str().into()));
             },
             Op::PutScopeVar { name } => {
-                let var = self.stack.pop().ok_or_else(|| VmError::EmptyStack)?;
-                self.scope.insert(name.clone(), var.into());
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                self.scope.insert(name.to_string(), value.raw().to_string());
+
+                match value {
+                    Value::Literal(_) => self.scope_values.remove(name.as_ref()),
+                    value => self.scope_values.insert(name.to_string(), value),
+                };
             },
             Op::DestroyScope => {
+                if let Some(ScopeHook(callback)) = &mut self.on_scope_destroy {
+                    callback(&self.scope);
+                }
                 self.scope.clear();
+                self.scope_values.clear();
+            },
+            Op::Assert { name, expected } => {
+                let got = self.get_string_var(name)?;
+
+                if got != expected.as_ref() {
+                    return Err(VmError::AssertionFailed {
+                        name: name.clone(),
+                        expected: expected.clone(),
+                        got: got.into(),
+                    });
+                }
+            },
+            Op::Comment { .. } | Op::Noop => {},
+            Op::CmpBoolJmp { op_index, value, name } => {
+                if self.get_bool_var(name) == *value {
+                    self.pc = *op_index;
+                }
+            },
+            Op::LoadStringGraphemes { name } => {
+                let graphemes = UnicodeSegmentation::graphemes(self.get_string_var(name)?, true).map(String::from).collect();
+                self.staged.insert(name.clone(), graphemes);
+            },
+            Op::LoadConcatArrays { names } => {
+                // undefined arrays are empty
+                let items = names.iter().flat_map(|name| self.get_array_var(name)).cloned().collect();
+                self.staged.insert(names.join("+"), items);
+            },
+            Op::LoadZipArrays { a, b } => {
+                // the loop runs over the staged length, so it stops at the shorter one
+                let len = std::cmp::min(self.get_array_var(a).len(), self.get_array_var(b).len());
+                self.staged_lens.insert(format!("{},{}", a, b), len);
+            },
+            Op::Filter { filter } => {
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let output = filter.apply(value.raw()).into();
+
+                match value {
+                    Value::Literal(_) => self.stack.push(Value::Literal(output)),
+                    _ => self.stack.push(Value::Var(output)),
+                }
+            },
+            Op::PushIndent { width } => {
+                self.indent = self.indent.saturating_add(to_index(*width));
+            },
+            Op::PopIndent { width } => {
+                self.indent = self.indent.saturating_sub(to_index(*width));
+            },
+            Op::Halt => {
+                // templates made before the directive printed the var this way, so they must not be cut silently
+                if self.vars.get("stop").is_some() || self.bools.contains_key("stop") {
+                    return Err(VmError::ShadowedVar { name: "stop".into() });
+                }
+                // the output before `stop` is kept, pc stays, so the program can't be resumed
+                self.flush(w)?;
+                return Err(VmError::EndOfProgram);
             },
         }
 
         self.pc += 1;
         Ok(())
     }
+
+    fn flush(&mut self, w: &mut impl Write) -> Result<(), VmError> {
+        // coalesce the stack, so the writer gets a single call per flush
+        self.buffer.clear();
+
+        for value in self.stack.drain(..) {
+            let s = value.output(self.escape);
+
+            if self.indent == 0 {
+                self.buffer.push_str(&s);
+                continue;
+            }
+
+            // a line is indented when it starts inside the block, blank lines are kept empty
+            for line in s.split_inclusive('\n') {
+                if self.at_line_start && line != "\n" && line != "\r\n" {
+                    self.buffer.extend(std::iter::repeat_n(' ', self.indent));
+                }
+                self.buffer.push_str(line);
+                self.at_line_start = line.ends_with('\n');
+            }
+        }
+
+        if let Some(last) = self.buffer.as_bytes().last() {
+            self.at_line_start = *last == b'\n';
+        }
+
+        w.write_all(self.buffer.as_bytes()).map_err(|e| VmError::WriteError(e.kind()))?;
+        self.bytes_written += self.buffer.len() as u64;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::io::{self, Write};
+    use std::sync::Arc;
+    use crate::vm::{CacheStats, Vm, VmError, BoolVars, Escape, VarNameError, validate_var_names, validate_array_names};
+    use crate::ir::{gen_ir, GenIrConfig, Op};
+    use crate::syntax::ast;
+    use crate::analysis::{NO_OPT, FULL_OPT};
+    use crate::utils::{VARS, ARRAYS, assert_renders_to, assert_vm_error};
+
+    fn run(code: &str) -> Result<String, VmError> {
+        let nodes = ast(code).unwrap();
+        let ir = gen_ir(code, &nodes, FULL_OPT).unwrap();
+
+        run_ir(&ir)
+    }
+
+    fn run_ir(ir: &[Op]) -> Result<String, VmError> {
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.run(&mut out, ir)?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    struct CountingWriter {
+        writes: usize,
+        output: Vec<u8>,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FailingWriter(io::ErrorKind);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(self.0.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_error_kind() {
+        let code = "{{ ARGS[:] | \"$(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+
+        for kind in [io::ErrorKind::BrokenPipe, io::ErrorKind::WouldBlock] {
+            let err = Vm::new(&VARS, &ARRAYS).run(&mut FailingWriter(kind), &ir).unwrap_err();
+            assert_eq!(err, VmError::WriteError(kind));
+        }
+
+        assert_eq!(VmError::WriteError(io::ErrorKind::BrokenPipe).to_string(), "[P1002] Cannot write the output: broken pipe");
+    }
+
+    #[test]
+    fn flush_is_single_write() {
+        let ir = [
+            Op::PutStr { value: "one ".into() },
+            Op::PutName { name: "first".into(), start: None, end: None },
+            Op::PutStr { value: " two".into() },
+            Op::Flush,
+        ];
+        let mut w = CountingWriter { writes: 0, output: Vec::new() };
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        assert_eq!(vm.run(&mut w, &ir), Ok(17));
+        assert_eq!(w.writes, 1);
+        assert_eq!(w.output, b"one first arg two");
+    }
+
+    #[test]
+    fn put_str_is_shared() {
+        let sep: Arc<str> = ", ".into();
+        let ir = [
+            Op::PutStr { value: sep.clone() },
+            Op::PutStr { value: "other".into() },
+            Op::PutStr { value: sep.clone() },
+        ];
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        // literals aren't copied onto the stack
+        assert_eq!(vm.run(&mut Vec::new(), &ir), Ok(0));
+        assert!(Arc::ptr_eq(vm.stack[0].raw(), &sep));
+        assert!(Arc::ptr_eq(vm.stack[0].raw(), vm.stack[2].raw()));
+    }
+
+    #[test]
+    fn error_code() {
+        assert_eq!(VmError::UndefinedScopeVar.code(), "P1004");
+        assert_eq!(VmError::AssertionFailed { name: "lang".into(), expected: "pipa".into(), got: "".into() }.code(), "P1007");
+        assert_eq!(VmError::ShadowedVar { name: "stop".into() }.code(), "P1008");
+    }
+
+    #[test]
+    fn bytes_written() {
+        let code = "{{ ARGS[:] | \"$(_item_),\" }}{{ fifth }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        assert_eq!(vm.run(&mut out, &ir), Ok(out.len() as u64));
+        // counted in bytes, not chars
+        assert!(out.len() > String::from_utf8(out.clone()).unwrap().chars().count());
+
+        vm.clear_state();
+        assert_eq!(vm.run(&mut Vec::new(), &[]), Ok(0));
+    }
+
+    #[test]
+    fn multibyte_var_slicing() {
+        // 'четвертый аргумент' has 18 graphemes but 35 bytes
+        assert_eq!(run("{{ forth[10:30] }}"), Ok("аргумент".into()));
+        assert_eq!(run("{{ forth[:9] }}"), Ok("четвертый".into()));
+        assert_eq!(run("{{ forth[17:] }}"), Ok("т".into()));
+        assert_eq!(run("{{ forth[18:] }}"), Ok("".into()));
+        assert_eq!(run("{{ fifth[3:] }}"), Ok("議論".into()));
+        assert_eq!(run("{{ \"$(forth)\" | \"$(_)\" }}"), Ok("четвертый аргумент".into()));
+    }
+
+    #[test]
+    fn callback_per_flush() {
+        let code = "{{ ARGS[:] | \"$(_item_),\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let mut chunks = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.run_with_callback(|s| chunks.push(s.to_string()), &ir).unwrap();
+
+        assert_eq!(chunks, ["first element,", "second element,", "third element,"]);
+    }
+
+    #[test]
+    fn preset_scope_var() {
+        let ir = [
+            Op::PutStr { value: "<b>".into() },
+            Op::PutName { name: "_".into(), start: None, end: None },
+            Op::PutStr { value: "</b>".into() },
+            Op::Collapse,
+            Op::Flush,
+        ];
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        assert_eq!(vm.run(&mut out, &ir), Err(VmError::UndefinedScopeVar));
+
+        vm.clear_state();
+        vm.set_scope_var("_", "value");
+        vm.run(&mut out, &ir).unwrap();
+
+        assert_eq!(out, b"<b>value</b>");
+    }
+
+    #[test]
+    fn custom_naming() {
+        let naming = GenIrConfig { is_array: |name| name == "ARGS", is_reserved: |name| name.starts_with("loc") };
+        let code = "{{ locname }} {{ ARGS[:2] | \"$(_item_) \" }}{{ PHONES[:] | \"$(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS).with_naming(naming);
+
+        // loop vars stay reserved
+        vm.inject_scope(&BTreeMap::from([("locname".into(), "local".into()), ("_".into(), "x".into())])).unwrap();
+        assert_eq!(vm.inject_scope(&BTreeMap::from([("first".into(), "x".into())])), Err(VmError::InvalidScopeVarName { name: "first".into() }));
+        vm.run(&mut out, &ir).unwrap();
+
+        // PHONES isn't an array by the convention, so its loop is empty
+        assert_eq!(String::from_utf8(out).unwrap(), "local first element second element ");
+    }
+
+    #[test]
+    fn inject_scope() {
+        let ir = [
+            Op::PutName { name: "_request_id_".into(), start: None, end: None },
+            Op::PutName { name: "_".into(), start: None, end: None },
+            Op::Flush,
+        ];
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.set_scope_var("_", "old");
+        vm.inject_scope(&BTreeMap::from([
+            ("_request_id_".into(), "42:".into()),
+            ("_".into(), "new".into()),
+        ])).unwrap();
+        vm.run(&mut out, &ir).unwrap();
+
+        assert_eq!(out, b"42:new");
+    }
+
+    #[test]
+    fn inject_scope_between_iterations() {
+        let ir = [
+            Op::SetCounter { value: 0 },
+            Op::CmpArrayEmptyJmp { op_index: 11, start: None, end: Some(2), name: "ARGS".into() },
+            Op::LoadArrayItem { name: "ARGS".into() },
+            Op::PutScopeVar { name: "_item_".into() },
+            Op::PutName { name: "_ctx_".into(), start: None, end: None },
+            Op::PutName { name: "_item_".into(), start: None, end: None },
+            Op::Collapse,
+            Op::Flush,
+            Op::DestroyScope,
+            Op::IncCounter,
+            Op::CmpCounterLessJmp { op_index: 1, value: Some(2), name: "ARGS".into() },
+        ];
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.inject_scope(&BTreeMap::from([("_ctx_".into(), "a ".into())])).unwrap();
+        // run the first iteration, which ends with DestroyScope
+        for _ in 0..9 {
+            vm.step(&mut out, &ir).unwrap();
+        }
+        vm.inject_scope(&BTreeMap::from([("_ctx_".into(), "b ".into())])).unwrap();
+        vm.run(&mut out, &ir).unwrap();
+
+        assert_eq!(out, "a first elementb second element".as_bytes());
+    }
+
+    #[test]
+    fn inject_scope_invalid_name() {
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+        let extra = BTreeMap::from([
+            ("_valid_".into(), "value".into()),
+            ("invalid".into(), "value".into()),
+        ]);
+
+        assert_eq!(vm.inject_scope(&extra), Err(VmError::InvalidScopeVarName { name: "invalid".into() }));
+        assert!(vm.scope.is_empty());
+    }
+
+    #[test]
+    fn profile_per_op_kind() {
+        let code = "{{ first }}{{ ARGS[:] | \"$(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.run(&mut Vec::new(), &ir).unwrap();
+        assert!(vm.profile().is_empty());
+
+        vm.clear_state();
+        vm.enable_profiling();
+        vm.run(&mut Vec::new(), &ir).unwrap();
+
+        let profile = vm.profile();
+        let mut kinds: Vec<_> = ir.iter().map(|op| op.kind()).collect();
+        kinds.sort();
+        kinds.dedup();
+
+        assert_eq!(profile.keys().copied().collect::<Vec<_>>(), kinds);
+    }
+
+    fn run_with_bools(code: &str, bools: &[(&str, bool)]) -> String {
+        let bools: BoolVars = bools.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        let nodes = ast(code).unwrap();
+        let [no_opt, full_opt] = [NO_OPT, FULL_OPT].map(|opt| {
+            let ir = gen_ir(code, &nodes, opt).unwrap();
+            let mut out = Vec::new();
+
+            Vm::new(&VARS, &ARRAYS).with_bools(&bools).run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        });
+
+        assert_eq!(no_opt, full_opt, "{:?}", code);
+        no_opt
+    }
+
+    #[test]
+    fn if_unless() {
+        let code = "a{{ if on }}b{{ first }}{{ end }}c{{ unless on }}d{{ end }}e";
+
+        assert_eq!(run_with_bools(code, &[("on", true)]), "abfirst argce");
+        assert_eq!(run_with_bools(code, &[("on", false)]), "acde");
+        // undefined bools are false
+        assert_eq!(run_with_bools(code, &[]), "acde");
+    }
+
+    #[test]
+    fn nested_conditions() {
+        let code = "{{ if a }}1{{ unless b }}2{{ ARGS[0:1] | \"$(_item_)\" }}{{ end }}{{ end }}{{ if b }}3{{ end }}";
+
+        assert_eq!(run_with_bools(code, &[("a", true), ("b", false)]), "12first element");
+        assert_eq!(run_with_bools(code, &[("a", true), ("b", true)]), "13");
+        assert_eq!(run_with_bools(code, &[("a", false), ("b", false)]), "");
+        assert_eq!(run_with_bools("{{ if a }}{{ end }}", &[("a", true)]), "");
+    }
+
+    #[test]
+    fn bool_fallback_and_rendering() {
+        let vars = [("lang", "pipa"), ("on", "true"), ("off", "false"), ("other", "yes")];
+        let code = "{{ if on }}on{{ end }}{{ if off }}off{{ end }}{{ if other }}other{{ end }}";
+
+        assert_renders_to(code, &vars, &[], "on");
+        // string vars shadow bools when rendered
+        assert_eq!(run_with_bools("{{ flag }} {{ first }}", &[("flag", false), ("first", true)]), "false first arg");
+    }
+
+    #[test]
+    fn passing_assertion() {
+        assert_renders_to("before {{ assert lang \"pipa\" }}after", &[("lang", "pipa")], &[], "before after");
+    }
+
+    #[test]
+    fn failing_assertion() {
+        assert_vm_error("{{ assert lang \"pipa\" }}", &[("lang", "rust")], &[], VmError::AssertionFailed {
+            name: "lang".into(),
+            expected: "pipa".into(),
+            got: "rust".into(),
+        });
+    }
+
+    #[test]
+    fn assertion_on_scope_var() {
+        let ir = [
+            Op::PutStr { value: "value".into() },
+            Op::PutScopeVar { name: "_".into() },
+            Op::Assert { name: "_".into(), expected: "value".into() },
+            Op::Assert { name: "_".into(), expected: "other".into() },
+        ];
+
+        assert_eq!(run_ir(&ir), Err(VmError::AssertionFailed {
+            name: "_".into(),
+            expected: "other".into(),
+            got: "value".into(),
+        }));
+    }
+
+    #[test]
+    fn vars_named_like_keywords() {
+        let vars = [("version", "1"), ("end", "2"), ("stop", "x"), ("chars", "c"), ("indent", "i"), ("endindent", "e"), ("if", "true"), ("on", "true")];
+
+        assert_renders_to("{{ version }}|{{ end }}", &vars, &[], "1|2");
+        assert_renders_to("{{ chars }} {{ indent }} {{ endindent }} {{ version | \"v$(_)\" }}", &vars, &[], "c i e v1");
+        assert_renders_to("{{ if on }}[{{ end }}]{{ end }}", &vars, &[], "[]2");
+        assert_renders_to("{{ if if }}{{ chars chars | \"$(_item_)\" }}{{ end }}{{ \"$(stop)\" }}", &vars, &[], "cx");
+        // the directive takes no arguments, so the var can't be told apart from it
+        assert_vm_error("a{{ stop }}", &vars, &[], VmError::ShadowedVar { name: "stop".into() });
+    }
+
+    #[test]
+    fn chars_loop() {
+        let vars = [("word", "abc"), ("flag", "🇺🇦é"), ("empty", "")];
+
+        assert_renders_to("{{ word[0:1] }}{{ chars word[1:] | \" $(_item_)\" }}", &vars, &[], "a b c");
+        assert_renders_to("{{ chars flag | \"$(_index_):$(_item_) \" }}", &vars, &[], "0:🇺🇦 1:é ");
+        assert_renders_to("{{ chars empty | \"never\" }}{{ chars missing | \"never\" }}", &vars, &[], "");
+    }
+
+    #[test]
+    fn zip_loop() {
+        let arrays: [(&str, &[&str]); 2] = [("NAMES", &["host", "port", "user"]), ("VALUES", &["localhost", "5432"])];
+        let code = "{{ zip(NAMES, VALUES) | \"$(_index_) $(_a_)=$(_b_)\\n\" }}";
+
+        // stops at the shorter array
+        assert_renders_to(code, &[], &arrays, "0 host=localhost\n1 port=5432\n");
+        assert_renders_to("{{ zip(VALUES,NAMES) | \"$(_b_) \" }}", &[], &arrays, "host port ");
+        assert_renders_to("{{ zip(NAMES, MISSING) | \"never\" }}", &[], &arrays, "");
+    }
+
+    #[test]
+    fn json_escape() {
+        let vars = BTreeMap::from([("msg".to_string(), "say \"hi\"\nto C:\\".to_string())]);
+        let arrays = BTreeMap::from([("ITEMS".to_string(), vec!["a\"b".to_string(), "\t\u{1}".to_string()])]);
+        let code = r#"\{"msg": "{{ msg }}", "short": "{{ msg | "$(_)" | truncate(5) }}", "items": [{{ ITEMS[:] | "\"$(_item_)\", " }}]\}"#;
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let mut out = Vec::new();
+
+        Vm::new(&vars, &arrays).with_escape(Escape::Json).run(&mut out, &ir).unwrap();
+
+        // literals are kept, values are escaped once even if they are piped
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"msg": "say \"hi\"\nto C:\\", "short": "say \"", "items": ["a\"b", "\t\u0001", ]}"#
+        );
+        assert_eq!(Escape::None.apply("\"\n"), "\"\n");
+    }
+
+    #[test]
+    fn json_escape_after_filters() {
+        let vars = BTreeMap::from([("msg".to_string(), "say \"hi\"".to_string())]);
+        let arrays = BTreeMap::new();
+        let render = |code: &str| {
+            let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+            let mut out = Vec::new();
+
+            Vm::new(&vars, &arrays).with_escape(Escape::Json).run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // filters see the value as is, so they can't cut an escape sequence in half
+        assert_eq!(render("{{ msg | truncate(5) }}"), r#"say \""#);
+        assert_eq!(render("{{ msg | \"$(_)\" | replace(\"\\\"\", \"'\") }}"), "say 'hi'");
+        assert_eq!(render("{{ msg | \"<$(_)>\" | \"[$(_)]\" }}"), r#"[<say \"hi\">]"#);
+        // the result of a filter over literals and vars is escaped as a whole
+        assert_eq!(render("{{ msg | \"\\\"$(_)\" | truncate(3) }}"), r#"\"sa"#);
+    }
+
+    #[test]
+    fn chunks() {
+        let chunks = |code: &str| {
+            let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+            let mut vm = Vm::new(&VARS, &ARRAYS);
+
+            vm.run_chunks(&ir).collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(chunks("<ul>{{ ARGS[:2] | \"<li>$(_item_)</li>\" }}</ul>").unwrap(), [
+            "<ul>", "<li>first element</li>", "<li>second element</li>", "</ul>",
+        ]);
+        assert_eq!(chunks("{{ first }} {{ second }}").unwrap(), ["first arg second arg"]);
+        // nothing is written, so there are no chunks
+        assert_eq!(chunks("{{ ARGS[:0] | \"-\" }}").unwrap(), Vec::<String>::new());
+        assert_eq!(chunks("a{{ assert lang \"rust\" }}"), Err(VmError::AssertionFailed { name: "lang".into(), expected: "rust".into(), got: "".into() }));
+    }
+
+    #[test]
+    fn tail() {
+        let items: Vec<String> = (0..1000).map(|i| format!("line {}", i)).collect();
+        let arrays = BTreeMap::from([("LINES".to_string(), items)]);
+        let vars = BTreeMap::from([("flag".to_string(), "🇺🇦🇺🇦".to_string())]);
+        let tail = |code: &str, max_bytes| {
+            let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+
+            Vm::new(&vars, &arrays).run_tail(&ir, max_bytes).unwrap()
+        };
+        let code = "{{ LINES[:] | \"$(_item_)\\n\" }}";
+
+        assert_eq!(tail(code, 20), "7\nline 998\nline 999\n");
+        assert_eq!(tail(code, 0), "");
+        assert_eq!(tail(code, usize::MAX).lines().count(), 1000);
+        // a flag is 8 bytes, the one cut by the limit is dropped
+        assert_eq!(tail("{{ flag }}!", 13), "🇺🇦!");
+    }
+
+    #[test]
+    fn empty_program() {
+        let ir = gen_ir("{{ first }}", &ast("{{ first }}").unwrap(), NO_OPT).unwrap();
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        assert_eq!(vm.run(&mut out, &ir[..0]), Ok(0));
+        assert!(out.is_empty());
+        // the count of written bytes is kept until clear_state
+        vm.run(&mut out, &ir).unwrap();
+        assert_eq!(vm.run(&mut out, &[]), Ok(9));
+    }
+
+    #[test]
+    fn iteration_callback() {
+        let items: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+        let arrays = BTreeMap::from([("ITEMS".to_string(), items)]);
+        let vars = BTreeMap::new();
+        let iterations = |code: &str| {
+            let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+            let mut indexes = Vec::new();
+            let mut vm = Vm::new(&vars, &arrays);
+
+            vm.on_iteration(|i| indexes.push(i));
+            vm.run(&mut io::sink(), &ir).unwrap();
+            drop(vm);
+
+            indexes
+        };
+
+        assert_eq!(iterations("{{ ITEMS[:] | \"$(_item_)\" }}"), [0, 1, 2, 3, 4]);
+        assert_eq!(iterations("{{ ITEMS[3:] | \"-\" }}{{ ITEMS[:1] | \"-\" }}"), [3, 4, 0]);
+        assert_eq!(iterations("text {{ ITEMS[:0] | \"-\" }}{{ MISSING[:] | \"-\" }}{{ \"no loop\" }}"), []);
+    }
+
+    #[test]
+    fn scope_destroy_callback() {
+        let code = "{{ ARGS[:2] | \"$(_index_) $(_item_)\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let mut scopes = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        vm.on_scope_destroy(|scope| scopes.push(scope.clone()));
+        vm.run(&mut io::sink(), &ir).unwrap();
+        drop(vm);
+
+        assert_eq!(scopes, [
+            BTreeMap::from([("_index_".to_string(), "0".to_string()), ("_item_".to_string(), "first element".to_string())]),
+            BTreeMap::from([("_index_".to_string(), "1".to_string()), ("_item_".to_string(), "second element".to_string())]),
+        ]);
+    }
+
+    #[test]
+    fn mutate_vars() {
+        let code = "{{ name }}: {{ ITEMS[:] | \"$(_item_) \" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let vars = BTreeMap::from([("name".to_string(), "Alice".to_string())]);
+        let arrays = BTreeMap::new();
+        let mut vm = Vm::new(&vars, &arrays);
+        let render = |vm: &mut Vm| {
+            let mut out = Vec::new();
+
+            vm.clear_state();
+            vm.run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(render(&mut vm), "Alice: ");
+
+        vm.vars_mut().insert("name".into(), "Bob".into());
+        vm.arrays_mut().insert("ITEMS".into(), vec!["a".into(), "b".into()]);
+        assert_eq!(render(&mut vm), "Bob: a b ");
+        // the borrowed maps are copied, not changed
+        assert_eq!(vars["name"], "Alice");
+        assert!(arrays.is_empty());
+
+        vm.scope_mut().insert("_".into(), "value".into());
+        assert_eq!(vm.get_string_var("_"), Ok("value"));
+    }
+
+    #[test]
+    fn var_names() {
+        let vars = BTreeMap::from([("first".to_string(), String::new()), ("_item_".to_string(), String::new())]);
+        let arrays = BTreeMap::from([
+            ("ARGS".to_string(), Vec::new()),
+            ("args".to_string(), Vec::new()),
+            ("A".to_string(), Vec::new()),
+        ]);
+        let names = |errors: Vec<VarNameError>| errors.into_iter().map(|e| e.name).collect::<Vec<_>>();
+
+        assert_eq!(names(validate_var_names(&vars)), ["_item_"]);
+        assert_eq!(names(validate_array_names(&arrays)), ["A", "args"]);
+        assert!(validate_var_names(&VARS).is_empty());
+        assert!(validate_array_names(&ARRAYS).is_empty());
+    }
+
+    #[test]
+    fn shared_base() {
+        let base = Arc::new(BTreeMap::from([
+            ("lang".to_string(), "pipa".to_string()),
+            ("greeting".to_string(), "hello".to_string()),
+        ]));
+        let code = "{{ greeting[:4] }} {{ lang }}{{ missing }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let arrays = BTreeMap::new();
+        let render = |overrides| {
+            let mut out = Vec::new();
+
+            Vm::with_shared_base(Arc::clone(&base), overrides, &arrays).run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(render(BTreeMap::new()), "hell pipa");
+        assert_eq!(render(BTreeMap::from([("greeting".to_string(), "hi".to_string())])), "hi pipa");
+        assert_eq!(base["greeting"], "hello");
+    }
+
+    #[test]
+    fn padded_counter() {
+        let items: Vec<String> = (0..11).map(|i| i.to_string()).collect();
+        let items: Vec<&str> = items.iter().map(|s| s.as_str()).collect();
+        let arrays = [("ITEMS", items.as_slice())];
+
+        assert_renders_to("{{ ITEMS#pad2[:3] | \"$(_index_) \" }}", &[], &arrays, "00 01 02 ");
+        assert_renders_to("{{ ITEMS#pad2[9:] | \"$(_index_) \" }}", &[], &arrays, "09 10 ");
+        assert_renders_to("{{ ITEMS#pad1[9:] | \"$(_index_) \" }}", &[], &arrays, "9 10 ");
+    }
+
+    #[test]
+    fn slice_by_graphemes() {
+        // 3 graphemes, but 19 bytes
+        let vars = [("emoji", "\u{1f44d}\u{1f3fd}\u{1f1ef}\u{1f1f5}e\u{301}")];
+
+        assert_renders_to("{{ emoji[1:] }}", &vars, &[], "\u{1f1ef}\u{1f1f5}e\u{301}");
+        assert_renders_to("{{ emoji[2:3] }}", &vars, &[], "e\u{301}");
+        // ends past the last grapheme are clamped to it
+        assert_renders_to("{{ emoji[1:20] }}", &vars, &[], "\u{1f1ef}\u{1f1f5}e\u{301}");
+        assert_renders_to("{{ emoji[:6] | \"[$(_)]\" }}", &vars, &[], "[\u{1f44d}\u{1f3fd}\u{1f1ef}\u{1f1f5}e\u{301}]");
+        assert_renders_to("{{ emoji[5:20] }}", &vars, &[], "");
+    }
+
+    #[test]
+    fn resume() {
+        let code = "<ul>{{ ARGS[:] | \"<li>$(_index_) $(_item_)</li>\" }}</ul>{{ first }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), FULL_OPT).unwrap();
+        let expected = run(code).unwrap();
+
+        for steps in 0..=ir.len() {
+            let mut out = Vec::new();
+            let mut vm = Vm::new(&VARS, &ARRAYS);
+
+            for _ in 0..steps {
+                vm.step(&mut out, &ir).unwrap();
+            }
+
+            assert_eq!(vm.resume(&mut out, &ir), Ok(expected.len() as u64));
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn halt() {
+        let ir = [
+            Op::PutStr { value: "one ".into() },
+            Op::PutName { name: "first".into(), start: None, end: None },
+            Op::Halt,
+            Op::PutStr { value: "two".into() },
+            Op::Flush,
+        ];
+        let mut out = Vec::new();
+        let mut vm = Vm::new(&VARS, &ARRAYS);
+
+        // the stack is flushed, the rest isn't run even on resume
+        assert_eq!(vm.run(&mut out, &ir), Ok(13));
+        assert_eq!(vm.resume(&mut out, &ir), Ok(13));
+        assert_eq!(out, b"one first arg");
+
+        let chunks: Result<Vec<String>, VmError> = Vm::new(&VARS, &ARRAYS).run_chunks(&ir).collect();
+        assert_eq!(chunks, Ok(vec!["one first arg".into()]));
+    }
+
+    #[test]
+    fn grapheme_cache_stats() {
+        let arrays = BTreeMap::from([("ITEMS".to_string(), vec!["item".to_string(); 10])]);
+        let code = "{{ ARGS[:] | \"$(_item_)\" | truncate(1) }}{{ ITEMS[:] | \"$(_item_):$(first)\" }}{{ first }}{{ second }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let mut vm = Vm::new(&VARS, &arrays);
+
+        assert_eq!(vm.grapheme_cache_stats(), CacheStats::default());
+        vm.run(&mut Vec::new(), &ir).unwrap();
+        // vars are segmented by new, scope vars aren't cached
+        assert_eq!(vm.grapheme_cache_stats(), CacheStats { hits: 12, misses: 0 });
+
+        // set_var segments only the changed var
+        vm.set_var("first", "змінений");
+        vm.clear_state();
+        vm.run(&mut Vec::new(), &ir).unwrap();
+        assert_eq!(vm.grapheme_cache_stats(), CacheStats { hits: 24, misses: 0 });
+
+        // vars changed through vars_mut are segmented again on their first use
+        vm.vars_mut().insert("first".into(), "changed".into());
+        vm.clear_state();
+        vm.run(&mut Vec::new(), &ir).unwrap();
+        assert_eq!(vm.grapheme_cache_stats(), CacheStats { hits: 34, misses: 2 });
+    }
+
+    #[test]
+    fn slice_after_set_var() {
+        let code = "{{ word[1:3] }} {{ word[2:] }} {{ word[:2] }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let render = |vm: &mut Vm| {
+            let mut out = Vec::new();
+            vm.clear_state();
+            vm.run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let vars = BTreeMap::from([("word".to_string(), "слово".to_string())]);
+        let arrays = BTreeMap::new();
+        let mut vm = Vm::new(&vars, &arrays);
+
+        assert_eq!(render(&mut vm), "ло ово сл");
+        // 3 graphemes of 19 bytes
+        vm.set_var("word", "\u{1f44d}\u{1f3fd}\u{1f1ef}\u{1f1f5}e\u{301}");
+        assert_eq!(render(&mut vm), "\u{1f1ef}\u{1f1f5}e\u{301} e\u{301} \u{1f44d}\u{1f3fd}\u{1f1ef}\u{1f1f5}");
+        vm.set_var("word", "ab");
+        assert_eq!(render(&mut vm), "b  ab");
+    }
+
+    #[test]
+    fn max_iterations() {
+        let arrays = BTreeMap::from([("ROWS".to_string(), (0..1000).map(|i| i.to_string()).collect())]);
+        let code = "{{ ROWS[:] | \"<tr>$(_item_)</tr>\" }}{{ ROWS[990:] | \"<tr>$(_item_)</tr>\" }}";
+        let ir = gen_ir(code, &ast(code).unwrap(), NO_OPT).unwrap();
+        let render = |n| {
+            let mut out = Vec::new();
+
+            Vm::new(&VARS, &arrays).with_max_iterations(n).run(&mut out, &ir).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let rows = |range: std::ops::Range<usize>| range.map(|i| format!("<tr>{}</tr>", i)).collect::<String>();
+
+        // every loop is capped on its own, counting from the start of its slice
+        assert_eq!(render(100), rows(0..100) + &rows(990..1000));
+        assert_eq!(render(3), rows(0..3) + &rows(990..993));
+        assert_eq!(render(0), "");
+    }
 }
@@ -0,0 +1,124 @@
+use std::process::{Command, Output};
+use std::env::temp_dir;
+use std::fs::write;
+
+
+fn pipa(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pipa"))
+        .args(args)
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_valid_files() {
+    let output = pipa(&["check", "examples/vars.pipa", "examples/all_features.pipa"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+}
+
+#[test]
+fn check_invalid_file() {
+    let output = pipa(&["check", "examples/vars.pipa", "negative_examples/invalid_name.pipa", "examples/all_features.pipa"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.starts_with("negative_examples/invalid_name.pipa:"), "{}", stdout);
+    assert!(stdout.contains("Only alphabetic ascii-chars can be used for names"), "{}", stdout);
+}
+
+#[test]
+fn check_missing_file() {
+    let output = pipa(&["check", "examples/missing.pipa"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.starts_with("examples/missing.pipa: "), "{}", stdout);
+}
+
+#[test]
+fn env_file() {
+    let path = temp_dir().join("pipa_cli_env_file.env");
+    write(&path, "# constants\nlang=pipa\n\nFILES=one.txt,two.txt\nmsg=a=b\n").unwrap();
+
+    let output = pipa(&["--env-file", path.to_str().unwrap(), "{{ lang }} {{ msg }}:{{ FILES[:] | \" $(_item_)\" }}"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "pipa a=b: one.txt two.txt");
+}
+
+#[test]
+fn env_file_malformed_line() {
+    let path = temp_dir().join("pipa_cli_env_file_malformed.env");
+    write(&path, "lang=pipa\n# comment\nmalformed\n").unwrap();
+
+    let output = pipa(&["--env-file", path.to_str().unwrap(), "{{ lang }}"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.ends_with(": line 3: expected KEY=value\n"), "{}", stdout);
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn vars_file_toml() {
+    let path = temp_dir().join("pipa_cli_vars_file.toml");
+    write(&path, "lang = \"pipa\"\nrelease = 1\nFILES = [\"one.txt\", \"two.txt\"]\n\n[db]\nport = 5432\n").unwrap();
+
+    let output = pipa(&["--vars-file", path.to_str().unwrap(), "{{ lang }}{{ release }} {{ db.port }}:{{ FILES[:] | \" $(_item_)\" }}"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "pipa1 5432: one.txt two.txt");
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn vars_file_toml_list_for_string_name() {
+    let path = temp_dir().join("pipa_cli_vars_file_list.toml");
+    write(&path, "[db]\nhosts = [\"a\", \"b\"]\n").unwrap();
+
+    let output = pipa(&["--vars-file", path.to_str().unwrap(), "{{ db.hosts }}"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.ends_with(": db.hosts: lists can only be assigned to array names\n"), "{}", stdout);
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn vars_file_yaml() {
+    let path = temp_dir().join("pipa_cli_vars_file.yaml");
+    write(&path, "lang: pipa\nenabled: true\nFILES:\n  - one.txt\n  - two.txt\ndb:\n  host: localhost\n").unwrap();
+
+    let output = pipa(&["--vars-file", path.to_str().unwrap(), "{{ lang }} {{ enabled }} {{ db.host }}:{{ FILES[:] | \" $(_item_)\" }}"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "pipa true localhost: one.txt two.txt");
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn vars_file_yaml_scalar_for_array_name() {
+    let path = temp_dir().join("pipa_cli_vars_file_scalar.yml");
+    write(&path, "FILES: one.txt\n").unwrap();
+
+    let output = pipa(&["--vars-file", path.to_str().unwrap(), "{{ FILES[:] | \"$(_item_)\" }}"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.ends_with(": FILES: values of array names must be lists\n"), "{}", stdout);
+}
+
+#[test]
+fn vars_file_unsupported_format() {
+    let path = temp_dir().join("pipa_cli_vars_file.ini");
+    write(&path, "lang=pipa\n").unwrap();
+
+    let output = pipa(&["--vars-file", path.to_str().unwrap(), "{{ lang }}"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains("unsupported format of"), "{}", stdout);
+}
@@ -0,0 +1,144 @@
+use pipa::syntax::{ast, ast_with_options, TokenType, CompileOptions, DEFAULT_OPTIONS};
+use pipa::ir::{gen_ir, Type};
+use pipa::error::{CompileError, ErrorReason};
+use pipa::analysis::NO_OPT;
+
+fn message(code: &str, reason: ErrorReason) -> String {
+    let err = ast(code)
+        .and_then(|nodes| gen_ir(code, &nodes, NO_OPT))
+        .expect_err("the template must not compile");
+
+    assert_eq!(err.reason, reason, "{:?}", code);
+    write_message(&err, code)
+}
+
+fn write_message(err: &CompileError, code: &str) -> String {
+    let mut output = Vec::new();
+
+    err.write_message(&mut output, "test.pipa", code).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_all_error_messages() {
+    insta::assert_snapshot!("syntax_error", message(
+        "text {{ \"unclosed }}",
+        ErrorReason::SyntaxError { expected: vec![TokenType::Quote] },
+    ));
+    insta::assert_snapshot!("unexpected_token", message(
+        "{{ first \\| }}",
+        ErrorReason::SyntaxError { expected: vec![] },
+    ));
+    insta::assert_snapshot!("name_error", message(
+        "{{ имя }}",
+        ErrorReason::NameError,
+    ));
+    insta::assert_snapshot!("macro_redefinition", message(
+        "{{\n  @print \"a\"\n  @print \"b\"\n}}",
+        ErrorReason::MacroRedefinition { name: "print".into(), first_defined: 5 },
+    ));
+    insta::assert_snapshot!("undefined_macro", message(
+        "{{ first | ?print }}",
+        ErrorReason::UndefinedMacro { name: "print".into() },
+    ));
+    insta::assert_snapshot!("undefined_var", message(
+        "{{ \"$(_item_)\" }}",
+        ErrorReason::UndefinedVar { name: "_item_".into() },
+    ));
+    insta::assert_snapshot!("nested_macro", message(
+        "{{ @print \"a\" | @print_me \"b\" }}",
+        ErrorReason::NestedMacro,
+    ));
+    insta::assert_snapshot!("empty_macro", message(
+        "{{ @print_me }}",
+        ErrorReason::EmptyMacro,
+    ));
+    insta::assert_snapshot!("pipe_no_parent", message(
+        "{{ | \"a\" }}",
+        ErrorReason::PipeNoParent { after_literal: false },
+    ));
+    insta::assert_snapshot!("pipe_no_parent_after_literal", message(
+        "Hello {{ | \"a\" }}",
+        ErrorReason::PipeNoParent { after_literal: true },
+    ));
+    insta::assert_snapshot!("pipe_no_children", message(
+        "{{ first | }}",
+        ErrorReason::PipeNoChildren,
+    ));
+    insta::assert_snapshot!("array_not_piped", message(
+        "{{ ARGS[:] }}",
+        ErrorReason::ArrayNotPiped,
+    ));
+    insta::assert_snapshot!("type_error", message(
+        "{{ assert first \"$(second)\" }}",
+        ErrorReason::TypeError { expected: Type::Literal, got: Type::Name },
+    ));
+    insta::assert_snapshot!("int_overflow", message(
+        "{{ 99999999999999999999999 }}",
+        ErrorReason::IntOverflow,
+    ));
+    insta::assert_snapshot!("unclosed_block", message(
+        "{{ if on }}text",
+        ErrorReason::UnclosedBlock { expected: "end" },
+    ));
+    insta::assert_snapshot!("unclosed_indent", message(
+        "{{ indent 2 }}text",
+        ErrorReason::UnclosedBlock { expected: "endindent" },
+    ));
+    insta::assert_snapshot!("width_too_large", message(
+        "{{ indent 4000000000 }}text{{ endindent }}",
+        ErrorReason::WidthTooLarge { max: 1024 },
+    ));
+    insta::assert_snapshot!("unmatched_end", message(
+        "{{ indent 2 }}text{{ end }}",
+        ErrorReason::UnmatchedEnd { keyword: "end" },
+    ));
+    insta::assert_snapshot!("unmatched_endindent", message(
+        "{{ if on }}text{{ endindent }}",
+        ErrorReason::UnmatchedEnd { keyword: "endindent" },
+    ));
+    insta::assert_snapshot!("unknown_filter", message(
+        "{{ first | upper() }}",
+        ErrorReason::UnknownFilter { name: "upper".into() },
+    ));
+    insta::assert_snapshot!("filter_args", message(
+        "{{ first | truncate(\"…\") }}",
+        ErrorReason::FilterArgs { name: "truncate".into(), expected: "(Int) or (Int, String)" },
+    ));
+    insta::assert_snapshot!("invalid_version", message(
+        "{{ version \"1.x\" }}",
+        ErrorReason::InvalidVersion { version: "1.x".into() },
+    ));
+    insta::assert_snapshot!("unknown_annotation", message(
+        "{{ ARGS#upper[:] | \"$(_index_)\" }}",
+        ErrorReason::UnknownAnnotation { name: "upper".into() },
+    ));
+    insta::assert_snapshot!("error_in_macro", message(
+        "{{\n  @item \"<li>$(_item_)</li>\"\n}}\n{{ first | ?item }}",
+        ErrorReason::UndefinedVar { name: "_item_".into() },
+    ));
+    insta::assert_snapshot!("unquoted_pipe_target", message(
+        "{{ name | other }}",
+        ErrorReason::UnquotedPipeTarget { suggestion: "\"$(other)\"".into() },
+    ));
+    insta::assert_snapshot!("import_error", message(
+        "{{ @import \"lib.pipa\" }}",
+        ErrorReason::ImportError { path: "lib.pipa".into() },
+    ));
+
+    let code = "{{ ARGS[:] | \"$(_item_)\" }}";
+    let options = CompileOptions { allow_loops: false, ..DEFAULT_OPTIONS };
+    let err = ast_with_options(code, &options).unwrap_err();
+    assert_eq!(err.reason, ErrorReason::FeatureDisabled { feature: "loops".into() });
+    insta::assert_snapshot!("feature_disabled", write_message(&err, code));
+
+    let options = CompileOptions { open: '%', close: '%', ..DEFAULT_OPTIONS };
+    let err = ast_with_options(code, &options).unwrap_err();
+    assert_eq!(err.reason, ErrorReason::InvalidDelimiters { open: '%', close: '%' });
+    insta::assert_snapshot!("invalid_delimiters", write_message(&err, code));
+
+    // the parser doesn't produce it at the moment
+    let code = "{{ ARGS[:] }}";
+    let err = CompileError { first_char: 3, expanded_at: None, reason: ErrorReason::ArrayNoNewLine };
+    insta::assert_snapshot!("array_no_new_line", write_message(&err, code));
+}
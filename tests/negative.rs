@@ -1,15 +1,15 @@
 use std::fs::{read_to_string};
-use pipa::ir::gen_ir;
-use pipa::syntax::{ast, TokenType};
+use pipa::ir::{gen_ir, Type};
+use pipa::syntax::{ast, TokenType, MAX_WIDTH};
 use pipa::error::{CompileError, ErrorReason};
 use pipa::analysis::{NO_OPT, FULL_OPT};
-use pipa::utils::err_reason;
+use pipa::utils::{err_reason, assert_compile_error};
 
 fn test_str(code: &str) -> Result<(), CompileError> {
     let nodes = ast(code)?;
 
-    let c1 = gen_ir(code, nodes.clone(), NO_OPT);
-    let c2 = gen_ir(code, nodes, FULL_OPT);
+    let c1 = gen_ir(code, &nodes, NO_OPT);
+    let c2 = gen_ir(code, &nodes, FULL_OPT);
 
     assert_eq!(c1.is_err(), c2.is_err());
 
@@ -75,9 +75,35 @@ fn undefined_scope_with_range() {
     assert_eq!(err_reason(test_file("negative_examples/undefined_scope_with_range.pipa")), ErrorReason::UndefinedVar { name: "_name".into() });
 }
 
+#[test]
+fn undefined_scope_var() {
+    assert_eq!(err_reason(test_file("negative_examples/undefined_scope_var.pipa")), ErrorReason::UndefinedVar { name: "_request_id_".into() });
+}
+
+#[test]
+fn undefined_scope_var_assert() {
+    assert_eq!(err_reason(test_file("negative_examples/undefined_scope_var_assert.pipa")), ErrorReason::UndefinedVar { name: "_request_id_".into() });
+}
+
+#[test]
+fn premature_scope_ref() {
+    assert_eq!(err_reason(test_file("negative_examples/premature_scope_ref.pipa")), ErrorReason::UndefinedVar { name: "_".into() });
+}
+
+#[test]
+fn premature_scope_ref_in_loop() {
+    assert_eq!(err_reason(test_file("negative_examples/premature_scope_ref_in_loop.pipa")), ErrorReason::UndefinedVar { name: "_".into() });
+}
+
 #[test]
 fn orphan_pipe() {
-    assert_eq!(err_reason(test_file("negative_examples/orphan_pipe.pipa")), ErrorReason::PipeNoParent);
+    assert_eq!(err_reason(test_file("negative_examples/orphan_pipe.pipa")), ErrorReason::PipeNoParent { after_literal: true });
+}
+
+#[test]
+fn orphan_pipe_without_text() {
+    assert_compile_error("{{ | \"a\" }}", ErrorReason::PipeNoParent { after_literal: false });
+    assert_compile_error("{{ if on }}{{ | \"a\" }}{{ end }}", ErrorReason::PipeNoParent { after_literal: false });
 }
 
 #[test]
@@ -89,7 +115,7 @@ fn array_not_piped() {
 
 #[test]
 fn missing_space_name_string() {
-    assert_eq!(err_reason(test_file("negative_examples/missing_space_name_string.pipa")), ErrorReason::SyntaxError { expected: vec![TokenType::Space, TokenType::NewLine, TokenType::RangeBegin] });
+    assert_eq!(err_reason(test_file("negative_examples/missing_space_name_string.pipa")), ErrorReason::SyntaxError { expected: vec![TokenType::Space, TokenType::NewLine, TokenType::RangeBegin, TokenType::ExprBegin] });
 }
 
 #[test]
@@ -109,7 +135,7 @@ fn array_no_braces() {
 
 #[test]
 fn array_mismatched_brace() {
-    assert_eq!(err_reason(test_file("negative_examples/array_mismatched_brace.pipa")), ErrorReason::SyntaxError { expected: vec![TokenType::Space, TokenType::NewLine, TokenType::RangeBegin] }); 
+    assert_eq!(err_reason(test_file("negative_examples/array_mismatched_brace.pipa")), ErrorReason::SyntaxError { expected: vec![TokenType::Space, TokenType::NewLine, TokenType::RangeBegin, TokenType::ExprBegin] }); 
 }
 
 #[test]
@@ -143,5 +169,284 @@ fn undefined_macro() {
 
 #[test]
 fn macro_redifinition() {
-    assert_eq!(err_reason(test_file("negative_examples/macro_redifinition.pipa")), ErrorReason::MacroRedefinition { name: "print".into() }); 
+    assert_eq!(err_reason(test_file("negative_examples/macro_redifinition.pipa")), ErrorReason::MacroRedefinition { name: "print".into(), first_defined: 118 }); 
+}
+
+#[test]
+fn macro_redifinition_message() {
+    let filename = "negative_examples/macro_redifinition.pipa";
+    let code = read_to_string(filename).unwrap();
+    let mut output = Vec::new();
+
+    test_str(&code).unwrap_err().write_message(&mut output, filename, &code).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.starts_with("negative_examples/macro_redifinition.pipa:10:"), "{}", output);
+    assert!(output.contains("first defined at line 9"), "{}", output);
+}
+
+
+// assert
+
+#[test]
+fn assert_interpolated_expected() {
+    assert_compile_error("{{ assert lang \"$(other)\" }}", ErrorReason::TypeError { expected: Type::Literal, got: Type::Name });
+}
+
+
+// ints
+
+#[test]
+fn int_overflow() {
+    assert_compile_error("{{ 99999999999999999999999 }}", ErrorReason::IntOverflow);
+    assert_compile_error("{{ 18446744073709551616 }}", ErrorReason::IntOverflow);
+    assert_compile_error("{{ first | truncate(18446744073709551616) }}", ErrorReason::IntOverflow);
+}
+
+#[test]
+fn range_overflow() {
+    assert_compile_error("{{ FILES[99999999999999999999999:] | \"$(_item_)\" }}", ErrorReason::IntOverflow);
+    assert_compile_error("{{ FILES[:99999999999999999999999] | \"$(_item_)\" }}", ErrorReason::IntOverflow);
+}
+
+#[test]
+fn range_non_ascii_start() {
+    assert_compile_error("{{ FILES[\u{e9}1] | \"$(_item_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Int, TokenType::RangeSep] });
+}
+
+#[test]
+fn range_non_int_end() {
+    assert_compile_error("{{ first[1:b] }}", ErrorReason::SyntaxError { expected: vec![TokenType::Int, TokenType::RangeEnd] });
+    assert_compile_error("{{ FILES[:b] | \"$(_item_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Int, TokenType::RangeEnd] });
+}
+
+#[test]
+fn name_with_range_delimiter() {
+    assert_compile_error("{{ first:second }}", ErrorReason::NameError);
+    assert_compile_error("{{ first:[1:] }}", ErrorReason::NameError);
+    assert_compile_error("{{ \"$(fi:rst)\" }}", ErrorReason::NameError);
+}
+
+
+// conditions
+
+#[test]
+fn unclosed_block() {
+    assert_compile_error("{{ if on }}text{{ if other }}{{ end }}", ErrorReason::UnclosedBlock { expected: "end" });
+}
+
+#[test]
+fn unmatched_end() {
+    assert_compile_error("{{ indent 2 }}{{ if on }}text{{ end }}{{ end }}", ErrorReason::UnmatchedEnd { keyword: "end" });
+}
+
+#[test]
+fn mismatched_indent_end() {
+    assert_compile_error("{{ indent 2 }}text{{ end }}", ErrorReason::UnmatchedEnd { keyword: "end" });
+    assert_compile_error("{{ if on }}text{{ endindent }}", ErrorReason::UnmatchedEnd { keyword: "endindent" });
+    assert_compile_error("{{ indent 2 }}text", ErrorReason::UnclosedBlock { expected: "endindent" });
+    assert_compile_error("{{ indent on }}text{{ endindent }}", ErrorReason::SyntaxError { expected: vec![TokenType::Int] });
+}
+
+#[test]
+fn indent_too_wide() {
+    assert_compile_error("{{ indent 4000000000 }}text{{ endindent }}", ErrorReason::WidthTooLarge { max: MAX_WIDTH });
+    assert_compile_error("{{ indent 1025 }}text{{ endindent }}", ErrorReason::WidthTooLarge { max: MAX_WIDTH });
+}
+
+#[test]
+fn condition_on_array() {
+    assert_compile_error("{{ unless ARGS }}{{ end }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+}
+
+#[test]
+fn condition_on_scope_var() {
+    assert_compile_error("{{ if _item_ }}{{ end }}", ErrorReason::UndefinedVar { name: "_item_".into() });
+}
+
+
+// filters
+
+#[test]
+fn unknown_filter() {
+    assert_compile_error("{{ first | upper() }}", ErrorReason::UnknownFilter { name: "upper".into() });
+}
+
+#[test]
+fn filter_args() {
+    let expected = ErrorReason::FilterArgs { name: "truncate".into(), expected: "(Int) or (Int, String)" };
+
+    assert_compile_error("{{ first | truncate() }}", expected.clone());
+    assert_compile_error("{{ first | truncate(\"…\", 5) }}", expected);
+    assert_compile_error("{{ first | truncate(5, \"$(second)\") }}", ErrorReason::TypeError { expected: Type::Literal, got: Type::Name });
+    assert_compile_error("{{ first | truncate(5,) }}", ErrorReason::SyntaxError { expected: vec![TokenType::Int, TokenType::String] });
+    assert_compile_error("{{ first | truncate(5 6) }}", ErrorReason::SyntaxError { expected: vec![TokenType::ExprEnd] });
+    assert_compile_error("{{ first | fixed(\"2\") }}", ErrorReason::FilterArgs { name: "fixed".into(), expected: "(Int up to 255)" });
+    assert_compile_error("{{ first | fixed(70000) }}", ErrorReason::FilterArgs { name: "fixed".into(), expected: "(Int up to 255)" });
+    assert_compile_error("{{ first | fixed(18446744073709551615) }}", ErrorReason::FilterArgs { name: "fixed".into(), expected: "(Int up to 255)" });
+
+    let expected = ErrorReason::FilterArgs { name: "replace".into(), expected: "(non-empty String, String)" };
+    assert_compile_error("{{ first | replace(\"a\") }}", expected.clone());
+    assert_compile_error("{{ first | replace(\"a\", 1) }}", expected.clone());
+    // an empty pattern would match between every char
+    assert_compile_error("{{ first | replace(\"\", \"-\") }}", expected);
+
+    assert_compile_error("{{ first | default(1) }}", ErrorReason::FilterArgs { name: "default".into(), expected: "(String)" });
+
+    let expected = ErrorReason::FilterArgs { name: "attr".into(), expected: "(non-empty String)" };
+    assert_compile_error("{{ first | attr() }}", expected.clone());
+    assert_compile_error("{{ first | attr(\"\") }}", expected);
+
+    assert_compile_error("{{ first | strip_ansi(\"m\") }}", ErrorReason::FilterArgs { name: "strip_ansi".into(), expected: "()" });
+}
+
+#[test]
+fn filter_without_value() {
+    assert_compile_error("{{ truncate(5) }}", ErrorReason::PipeNoParent { after_literal: false });
+    assert_compile_error("{{ ARGS[:] | truncate(5) }}", ErrorReason::UndefinedVar { name: "_".into() });
+}
+
+
+// version
+
+#[test]
+fn version_too_high() {
+    assert_compile_error("{{ version \"999.0\" }}", ErrorReason::VersionTooHigh { required: "999.0".into(), current: pipa::syntax::VERSION.into() });
+}
+
+#[test]
+fn invalid_version() {
+    for version in ["", "1.x", "1..0", "1.0.0.0", "v1.0", "1.0-beta"] {
+        let code = format!("{{{{ version \"{}\" }}}}", version);
+
+        assert_compile_error(&code, ErrorReason::InvalidVersion { version: version.into() });
+    }
+}
+
+
+// chars
+
+#[test]
+fn chars_not_piped() {
+    assert_compile_error("{{ chars word }}", ErrorReason::ArrayNotPiped);
+}
+
+#[test]
+fn chars_of_array() {
+    assert_compile_error("{{ chars ARGS | \"$(_item_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+}
+
+
+// fan-out
+
+#[test]
+fn fan_out_without_branch() {
+    assert_compile_error("{{ first || }}", ErrorReason::PipeNoChildren);
+    assert_compile_error("{{ first ||| \"x\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::String] });
+}
+
+#[test]
+fn fan_out_without_value() {
+    assert_compile_error("{{ @both || \"a\" || \"b\" }}{{ ?both }}", ErrorReason::PipeNoParent { after_literal: false });
+    assert_compile_error("{{ ARGS[:] || \"$(_item_)\" }}", ErrorReason::UndefinedVar { name: "_".into() });
+}
+
+
+// loop annotations
+
+#[test]
+fn unknown_annotation() {
+    assert_compile_error("{{ ARGS#upper[:] | \"$(_index_)\" }}", ErrorReason::UnknownAnnotation { name: "upper".into() });
+    assert_compile_error("{{ ARGS#pad[:] | \"$(_index_)\" }}", ErrorReason::UnknownAnnotation { name: "pad".into() });
+    assert_compile_error("{{ ARGS#pad4000000000[:] | \"$(_index_)\" }}", ErrorReason::WidthTooLarge { max: MAX_WIDTH });
+    assert_compile_error("{{ ARGS#pad99999999999999999999999[:] | \"$(_index_)\" }}", ErrorReason::IntOverflow);
+}
+
+
+// array concatenation
+
+#[test]
+fn array_concat_of_names() {
+    assert_compile_error("{{ ARGS + first[:] | \"$(_item_)\" }}", ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+    assert_compile_error("{{ ARGS + first + ARGS[:] | \"$(_item_)\" }}", ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+}
+
+#[test]
+fn array_concat_without_range() {
+    assert_compile_error("{{ ARGS + PHONES | \"$(_item_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::RangeBegin] });
+    assert_compile_error("{{ ARGS + }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+    assert_compile_error("{{ ARGS + PHONES[:] }}", ErrorReason::ArrayNotPiped);
+}
+
+
+// zip
+
+#[test]
+fn zip_args() {
+    assert_compile_error("{{ zip(ARGS) | \"$(_a_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+    assert_compile_error("{{ zip(ARGS, PHONES, UTF) | \"$(_a_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+    assert_compile_error("{{ zip(ARGS, ) | \"$(_a_)\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::Name] });
+    assert_compile_error("{{ zip(ARGS, first) | \"$(_a_)\" }}", ErrorReason::TypeError { expected: Type::Array, got: Type::Name });
+}
+
+#[test]
+fn zip_scope() {
+    assert_compile_error("{{ zip(ARGS, PHONES) }}", ErrorReason::ArrayNotPiped);
+    assert_compile_error("{{ zip(ARGS, PHONES) | \"$(_item_)\" }}", ErrorReason::UndefinedVar { name: "_item_".into() });
+}
+
+#[test]
+fn loop_macro_in_pipe() {
+    assert_compile_error("{{ @list ARGS[:] | \"$(_item_)\" }}{{ first | ?list }}", ErrorReason::TypeError { expected: Type::String, got: Type::Array });
+    assert_compile_error("{{ @list ARGS[:] }}", ErrorReason::ArrayNotPiped);
+}
+
+#[test]
+fn unresolved_import() {
+    assert_compile_error("{{ @import \"lib.pipa\" }}{{ ?p }}", ErrorReason::ImportError { path: "lib.pipa".into() });
+    assert_compile_error("{{ @import }}", ErrorReason::SyntaxError { expected: vec![TokenType::String] });
+}
+
+#[test]
+fn error_in_expanded_macro() {
+    let code = "{{ @item \"<li>$(_item_)</li>\" @short truncate(3) }}\n{{ first | ?item }}{{ ?short }}";
+    let err = test_str(code).unwrap_err();
+
+    // the error is in the definition, the expansion is reported as well
+    assert_eq!(err.reason, ErrorReason::UndefinedVar { name: "_item_".into() });
+    assert_eq!(&code[err.first_char..err.first_char + 6], "_item_");
+    assert_eq!(err.expanded_at.map(|(start, end)| &code[start..end]), Some("?item"));
+
+    let err = test_str("{{ @short truncate(3) }}{{ ?short }}").unwrap_err();
+    assert_eq!(err.reason, ErrorReason::PipeNoParent { after_literal: false });
+    assert_eq!(err.expanded_at, Some((27, 33)));
+
+    // errors outside of macros aren't marked
+    assert_eq!(test_str("{{ first | \"$(_item_)\" }}").unwrap_err().expanded_at, None);
+}
+
+#[test]
+fn unquoted_pipe_target() {
+    assert_compile_error("{{ name | other }}", ErrorReason::UnquotedPipeTarget { suggestion: "\"$(other)\"".into() });
+    assert_compile_error("{{ name | 5 }}", ErrorReason::UnquotedPipeTarget { suggestion: "\"5\"".into() });
+    assert_compile_error("{{ ARGS[:] | _item_ }}", ErrorReason::UnquotedPipeTarget { suggestion: "\"$(_item_)\"".into() });
+    assert_compile_error("{{ name || other }}", ErrorReason::UnquotedPipeTarget { suggestion: "\"$(other)\"".into() });
+}
+
+#[test]
+fn format_symbol_without_name() {
+    assert_compile_error("{{ \"$ \" }}", ErrorReason::SyntaxError { expected: vec![TokenType::ExprBegin] });
+    assert_compile_error("{{ \"$\" }}", ErrorReason::SyntaxError { expected: vec![TokenType::ExprBegin] });
+    assert_compile_error("{{ \"$ARGS\" }}", ErrorReason::NameError);
+    assert_compile_error("{{ first | \"$_item_\" }}", ErrorReason::UndefinedVar { name: "_item_".into() });
+}
+
+#[test]
+fn loop_vars_after_loop() {
+    // the loop may be skipped, so its vars are undefined after it even if it ran
+    assert_compile_error("{{ ARGS[:0] | \"x\" }}{{ _item_ }}", ErrorReason::UndefinedVar { name: "_item_".into() });
+    assert_compile_error("{{ ARGS[:] | \"x\" }}{{ \"$(_index_)\" }}", ErrorReason::UndefinedVar { name: "_index_".into() });
+    assert_compile_error("{{ ARGS[:] | \"x\" }}{{ first | \"$(_item_)\" }}", ErrorReason::UndefinedVar { name: "_item_".into() });
+    assert_compile_error("{{ zip(ARGS, PHONES) | \"$(_a_)\" }}{{ _b_ }}", ErrorReason::UndefinedVar { name: "_b_".into() });
 }
@@ -1,7 +1,7 @@
 use std::io::{stdout, Write};
 use std::fs::{read_to_string, read_dir};
 use pipa::ir::gen_ir;
-use pipa::syntax::ast;
+use pipa::syntax::{ast, strip_comments};
 use pipa::vm::Vm;
 use pipa::analysis::{NO_OPT, FULL_OPT};
 use pipa::utils::{VARS, ARRAYS};
@@ -18,7 +18,7 @@ fn test_str(f: &mut impl Write, filename: &str, code: &str, output: &str) {
         }
     };
 
-    let ir = match gen_ir(&code, nodes.clone(), NO_OPT) {
+    let ir = match gen_ir(code, &nodes, NO_OPT) {
         Ok(ir) => ir,
         Err(e) => {
             e.write_message(f, filename, code).unwrap();
@@ -44,7 +44,7 @@ fn test_str(f: &mut impl Write, filename: &str, code: &str, output: &str) {
     let out_s = String::from_utf8(out).expect(&format!("Output of '{}' is not utf8", filename));
     assert_eq!(out_s, output, "{}\n{:#?}\n{:#?}\n{:#?}", filename, &ir, nodes, NO_OPT);
     // check diff with full optimizations
-    let ir_opt = match gen_ir(&code, nodes.clone(), FULL_OPT) {
+    let ir_opt = match gen_ir(code, &nodes, FULL_OPT) {
         Ok(ir) => ir,
         Err(e) => {
             e.write_message(f, filename, code).unwrap();
@@ -89,6 +89,23 @@ fn positive_snippets() {
     
 }
 
+#[test]
+fn positive_snippets_without_comments() {
+    let mut stdout = stdout().lock();
+
+    for path in read_dir("examples").unwrap() {
+        let pathb = path.unwrap().path();
+        let path: &str = pathb.to_str().unwrap();
+
+        if !path.contains(".snapshot") {
+            let code = strip_comments(&read_to_string(path).unwrap());
+            let out = read_to_string(path.replace(".pipa", ".snapshot")).unwrap();
+
+            test_str(&mut stdout, path, &code, &out);
+        }
+    }
+}
+
 // property testing
 
 #[test]
@@ -152,3 +169,222 @@ fn multi_empty_lines_produce_nothing() {
 
     test_str(&mut stdout, "*.pipa", code, "");
 }
+
+
+#[test]
+fn loop_scope_vars_resolve_in_piped_string() {
+    let mut stdout = stdout().lock();
+    let code = "{{ ARGS[:] | \"item $(_index_): $(_item_)\\n\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "item 0: first element\nitem 1: second element\nitem 2: third element\n");
+}
+
+
+#[test]
+fn loop_scope_vars_resolve_in_nested_pipe() {
+    let mut stdout = stdout().lock();
+    let code = "{{ ARGS[1:] | \"$(_item_)\" | \"item $(_index_): $(_)\\n\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "item 1: second element\nitem 2: third element\n");
+}
+
+
+#[test]
+fn scope_var_after_pipe_is_defined() {
+    let mut stdout = stdout().lock();
+    let code = "{{ first | \"value\" | \"$(_) of $(_)\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "value of value");
+}
+
+
+#[test]
+fn empty_string_produce_nothing() {
+    let mut stdout = stdout().lock();
+    let code = "{{ \"\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "");
+}
+
+
+#[test]
+fn empty_string_after_literal_keeps_literal() {
+    let mut stdout = stdout().lock();
+    let code = "literal{{ \"\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "literal");
+}
+
+
+#[test]
+fn pipe_into_empty_string_pipes_empty_value() {
+    let mut stdout = stdout().lock();
+    let code = "{{ first | \"\" }}{{ 1 | \"\" | \"[$(_)]\" }}{{ \"\" | \"x\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "[]x");
+}
+
+
+#[test]
+fn literal_ends_with_multibyte_char() {
+    let mut stdout = stdout().lock();
+    let code = "{{ first }} caf\u{e9} \u{65e5}";
+
+    test_str(&mut stdout, "*.pipa", code, "first arg caf\u{e9} \u{65e5}");
+}
+
+
+#[test]
+fn truncate_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ first | truncate(5, \"…\") }}", "first…");
+    test_str(&mut stdout, "*.pipa", "{{ first | truncate(9, \"…\") }}", "first arg");
+    test_str(&mut stdout, "*.pipa", "{{ 12345 | truncate(2) | \"[$(_)]\" }}", "[12]");
+}
+
+
+#[test]
+fn truncate_filter_in_loops_and_macros() {
+    let mut stdout = stdout().lock();
+    let code = "{{ @short truncate(3, \"..\") }}{{ ARGS[:] | \"$(_item_)\" | ?short | \"$(_index_): $(_)\\n\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "0: fir..\n1: sec..\n2: thi..\n");
+}
+
+
+#[test]
+fn fixed_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ 500 | fixed(2) }}", "5.00");
+    test_str(&mut stdout, "*.pipa", "{{ ARGS[:] | \"1$(_index_)5\" | fixed(2) | \"$(_) \" }}", "1.05 1.15 1.25 ");
+    test_str(&mut stdout, "*.pipa", "{{ first | fixed(2) }}", "first arg");
+}
+
+#[test]
+fn default_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "Hello, {{ first | default(\"guest\") }}", "Hello, first arg");
+    test_str(&mut stdout, "*.pipa", "Hello, {{ lang | default(\"guest\") }}", "Hello, guest");
+    // composes with other filters
+    test_str(&mut stdout, "*.pipa", "{{ lang | default(\"none\") | attr(\"lang\") }}{{ first | truncate(0) | default(\"-\") }}", " lang=\"none\"-");
+}
+
+#[test]
+fn strip_ansi_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ \"\u{1b}[1;32mok\u{1b}[0m: done\" | strip_ansi() }}", "ok: done");
+    test_str(&mut stdout, "*.pipa", "{{ first | strip_ansi() }}", "first arg");
+}
+
+#[test]
+fn attr_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "<div{{ first | attr(\"class\") }}>", "<div class=\"first arg\">");
+    // empty values omit the whole attribute
+    test_str(&mut stdout, "*.pipa", "<div{{ lang | attr(\"lang\") }}>", "<div>");
+    test_str(&mut stdout, "*.pipa", "<div{{ \"\" | attr(\"class\") }}{{ ARGS[:1] | \"$(_item_)\" | attr(\"title\") }}>", "<div title=\"first element\">");
+}
+
+#[test]
+fn replace_filter() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ first | replace(\" arg\", \"!\") }}", "first!");
+    test_str(&mut stdout, "*.pipa", "{{ PHONES[:2] | \"$(_item_)\" | replace(\"-\", \".\") | \"$(_) \" }}", "555.123.4567 555.987.6543 ");
+    // no match
+    test_str(&mut stdout, "*.pipa", "{{ first | replace(\"x\", \"y\") }}", "first arg");
+}
+
+
+#[test]
+fn version_directive_produce_nothing() {
+    let mut stdout = stdout().lock();
+    let code = format!("{{{{ version \"{}\" }}}}text{{{{ version \"0.1\" }}}}", pipa::syntax::VERSION);
+
+    test_str(&mut stdout, "*.pipa", &code, "text");
+}
+
+
+#[test]
+fn fan_out_branches_see_the_same_value() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ first || \"upper: $(_)\" || \" raw: $(_)\" }}", "upper: first arg raw: first arg");
+    // a pipe after the last branch gets the concatenation
+    test_str(&mut stdout, "*.pipa", "{{ first || \"[$(_)]\" || truncate(3) | \"<$(_)>\" }}", "<[first arg]fir>");
+    test_str(&mut stdout, "*.pipa", "{{ first | \"x\" || \"$(_)\" || \"$(_)$(_)\" }}", "xxx");
+}
+
+
+#[test]
+fn fan_out_in_loops_and_macros() {
+    let mut stdout = stdout().lock();
+    let code = "{{ @wrap \"$(_)!\" | \"($(_))\" @pair || ?wrap || \"=$(_)\" }}{{ ARGS[:2] | \"$(_item_)\" | ?pair | \"$(_)\\n\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "(first element!)=first element\n(second element!)=second element\n");
+}
+
+
+#[test]
+fn ints_are_64_bit() {
+    let mut stdout = stdout().lock();
+    let code = "{{ 18446744073709551615 }} {{ first[4294967296:] }}{{ ARGS[4294967296:18446744073709551615] | \"$(_item_)\" }}";
+
+    test_str(&mut stdout, "*.pipa", code, "18446744073709551615 ");
+}
+
+#[test]
+fn array_concat() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ ARGS + PHONES[:] | \"$(_index_):$(_item_) \" }}", "0:first element 1:second element 2:third element 3:555-123-4567 4:555-987-6543 5:555-555-0000 ");
+    // the range applies to the joined sequence
+    test_str(&mut stdout, "*.pipa", "{{ ARGS + PHONES + ARGS[2:4] | \"$(_item_),\" }}", "third element,555-123-4567,");
+    // undefined arrays are empty
+    test_str(&mut stdout, "*.pipa", "{{ MISSING + PHONES + MISSING[:] | \"$(_item_),\" }}", "555-123-4567,555-987-6543,555-555-0000,");
+    test_str(&mut stdout, "*.pipa", "{{ MISSING + MISSING[:] | \"never\" }}", "");
+}
+
+#[test]
+fn loops_in_macros() {
+    let mut stdout = stdout().lock();
+    let code = "{{ @list ARGS[1:] | \"* $(_item_)\\n\"\n@pairs zip(UTF, PHONES) | \"$(_a_)=$(_b_);\" }}{{ ?list }}{{ unless on\n?pairs\nend }}";
+
+    test_str(&mut stdout, "*.pipa", code, "* second element\n* third element\nпервый=555-123-4567;segunda=555-987-6543;三番目=555-555-0000;");
+}
+
+#[test]
+fn format_symbol_without_parentheses() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "{{ ARGS[:] | \"$_index_:$_item_, \" }}", "0:first element, 1:second element, 2:third element, ");
+    test_str(&mut stdout, "*.pipa", "{{ ARGS[:] | \"$(_index_):$(_item_), \" }}", "0:first element, 1:second element, 2:third element, ");
+    // the name ends at the first grapheme that can't be a part of it
+    test_str(&mut stdout, "*.pipa", "{{ first | \"<$_>-$second.\\$first\" }}", "<first arg>-second arg.$first");
+}
+
+#[test]
+fn stop_ends_rendering() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "a{{ first }}b{{ unless on }}{{ stop }}{{ end }}c{{ second }}", "afirst argb");
+    test_str(&mut stdout, "*.pipa", "a{{ if on }}{{ stop }}{{ end }}b", "ab");
+    test_str(&mut stdout, "*.pipa", "{{ ARGS[:] | \"$(_item_) \" }}truncated{{ stop }}{{ PHONES[:] | \"$(_item_)\" }}", "first element second element third element truncated");
+}
+
+#[test]
+fn indent_block() {
+    let mut stdout = stdout().lock();
+
+    test_str(&mut stdout, "*.pipa", "list:\n{{ indent 2 }}- {{ first }}\n- {{ second }}\n{{ endindent }}end", "list:\n  - first arg\n  - second arg\nend");
+    // the first line is indented only if the block starts a line, blank lines stay empty
+    test_str(&mut stdout, "*.pipa", "a: {{ indent 2 }}b\n\nc{{ endindent }}", "a: b\n\n  c");
+    // nested blocks accumulate
+    test_str(&mut stdout, "*.pipa", "{{ indent 2 }}a\n{{ indent 2 }}b\n{{ endindent }}c\n{{ endindent }}d", "  a\n    b\n  c\nd");
+    test_str(&mut stdout, "*.pipa", "rows:{{ indent 4 }}{{ ARGS[:2] | \"\\n$(_item_)\" }}{{ endindent }}", "rows:\n    first element\n    second element");
+}
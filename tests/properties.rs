@@ -0,0 +1,56 @@
+use proptest::prelude::*;
+use proptest::string::string_regex;
+use pipa::syntax::ast;
+use pipa::ir::gen_ir;
+use pipa::vm::Vm;
+use pipa::analysis::{NO_OPT, FULL_OPT, OptOptions};
+use pipa::utils::{VARS, ARRAYS};
+
+// literal, one code block with a var, an int or a string with an optional pipe, literal
+const TEMPLATE: &str = concat!(
+    r#"[a-zA-Z0-9 .,\n]{0,12}"#,
+    r#"\{\{ (first|fifth|value_1|undefined|[0-9]{1,4}|"[a-z ]{0,6}"|first\[[0-9]?:[0-9]?\]|ARGS\[[0-9]?:[0-9]?\] \| "[a-z]{0,3}\$\(_item_\)")"#,
+    r#"( \| "[a-z ]{0,4}(\$\(_\))?")? \}\}"#,
+    r#"[a-zA-Z0-9 .,\n]{0,12}"#,
+);
+
+fn template() -> impl Strategy<Value = String> {
+    string_regex(TEMPLATE).unwrap()
+}
+
+fn render(code: &str, opt: OptOptions) -> Vec<u8> {
+    let nodes = ast(code).unwrap();
+    let ir = gen_ir(code, &nodes, opt).unwrap();
+    let mut out = Vec::new();
+
+    Vm::new(&VARS, &ARRAYS).run(&mut out, &ir).unwrap();
+
+    out
+}
+
+proptest! {
+    #[test]
+    fn any_valid_template_compiles(code in template()) {
+        let nodes = ast(&code);
+        prop_assert!(nodes.is_ok(), "{:?}: {:?}", code, nodes);
+
+        let nodes = nodes.unwrap();
+
+        for opt in [NO_OPT, FULL_OPT] {
+            let ir = gen_ir(&code, &nodes, opt);
+            prop_assert!(ir.is_ok(), "{:?}: {:?}", code, ir);
+        }
+    }
+
+    #[test]
+    fn output_is_utf8(code in template()) {
+        let out = render(&code, FULL_OPT);
+
+        prop_assert!(String::from_utf8(out).is_ok(), "{:?}", code);
+    }
+
+    #[test]
+    fn optimized_renders_identically(code in template()) {
+        prop_assert_eq!(render(&code, NO_OPT), render(&code, FULL_OPT), "{:?}", code);
+    }
+}